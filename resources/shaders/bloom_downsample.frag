@@ -0,0 +1,18 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(sampler2D(t_source, s_source), 0));
+    // Four bilinear taps average a 4x4 texel block of the larger level.
+    vec3 color = texture(sampler2D(t_source, s_source), v_tex_coords + texel * vec2(-1.0, -1.0)).rgb
+        + texture(sampler2D(t_source, s_source), v_tex_coords + texel * vec2(1.0, -1.0)).rgb
+        + texture(sampler2D(t_source, s_source), v_tex_coords + texel * vec2(-1.0, 1.0)).rgb
+        + texture(sampler2D(t_source, s_source), v_tex_coords + texel * vec2(1.0, 1.0)).rgb;
+    f_color = vec4(color * 0.25, 1.0);
+}
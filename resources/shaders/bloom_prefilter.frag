@@ -0,0 +1,26 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+layout(set=0, binding=2) uniform Bloom {
+    // threshold and knee
+    vec4 u_params;
+};
+
+void main() {
+    vec3 color = texture(sampler2D(t_source, s_source), v_tex_coords).rgb;
+    float brightness = max(color.r, max(color.g, color.b));
+
+    // Soft threshold, fading in over the knee below the threshold instead of cutting off.
+    float threshold = u_params.x;
+    float knee = max(u_params.y, 0.0001);
+    float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee);
+    float contribution = max(soft, brightness - threshold) / max(brightness, 0.0001);
+
+    f_color = vec4(color * contribution, 1.0);
+}
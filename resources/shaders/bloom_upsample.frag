@@ -0,0 +1,22 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+
+vec3 tap(vec2 offset) {
+    return texture(sampler2D(t_source, s_source), v_tex_coords + offset).rgb;
+}
+
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(sampler2D(t_source, s_source), 0));
+    // 3x3 tent filter, added onto the larger level by the blend state.
+    vec3 color = tap(vec2(0.0)) * 4.0
+        + (tap(vec2(-texel.x, 0.0)) + tap(vec2(texel.x, 0.0))
+            + tap(vec2(0.0, -texel.y)) + tap(vec2(0.0, texel.y))) * 2.0
+        + tap(-texel) + tap(texel) + tap(vec2(-texel.x, texel.y)) + tap(vec2(texel.x, -texel.y));
+    f_color = vec4(color / 16.0, 1.0);
+}
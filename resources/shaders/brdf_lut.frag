@@ -0,0 +1,61 @@
+#version 450
+
+// Split sum lookup table of the specular BRDF, by the cosine of the view angle along x and
+// roughness along y. Red holds the scale and green the bias applied to the Fresnel term.
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+const float PI = 3.14159265359;
+const uint SAMPLE_COUNT = 512u;
+
+vec2 hammersley(uint i) {
+    uint bits = (i << 16u) | (i >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return vec2(float(i) / float(SAMPLE_COUNT), float(bits) * 2.3283064365386963e-10);
+}
+
+// Half vector around +z, distributed like the GGX microfacets.
+vec3 importance_sample_ggx(vec2 xi, float roughness) {
+    float a = roughness * roughness;
+    float phi = 2.0 * PI * xi.x;
+    float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+float geometry_schlick_ggx(float n_dot_v, float roughness) {
+    // Image based lighting remaps roughness differently from direct lights.
+    float k = roughness * roughness / 2.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+void main() {
+    float n_dot_v = max(v_tex_coords.x, 0.001);
+    float roughness = v_tex_coords.y;
+    vec3 view = vec3(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+
+    float scale = 0.0;
+    float bias = 0.0;
+    for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+        vec3 half_dir = importance_sample_ggx(hammersley(i), roughness);
+        vec3 light_dir = normalize(2.0 * dot(view, half_dir) * half_dir - view);
+        float n_dot_l = max(light_dir.z, 0.0);
+        float n_dot_h = max(half_dir.z, 0.0);
+        float v_dot_h = max(dot(view, half_dir), 0.0);
+        if (n_dot_l > 0.0) {
+            float geometry = geometry_schlick_ggx(n_dot_v, roughness)
+                * geometry_schlick_ggx(n_dot_l, roughness);
+            float visibility = geometry * v_dot_h / (n_dot_h * n_dot_v);
+            float fresnel = pow(1.0 - v_dot_h, 5.0);
+            scale += (1.0 - fresnel) * visibility;
+            bias += fresnel * visibility;
+        }
+    }
+
+    f_color = vec4(vec2(scale, bias) / float(SAMPLE_COUNT), 0.0, 1.0);
+}
@@ -0,0 +1,11 @@
+#version 450
+
+// Copies the first sample of the multisampled depth buffer into a single sampled one, which
+// full screen effects can read.
+
+layout(set = 0, binding = 0) uniform texture2DMS t_depth;
+layout(set = 0, binding = 1) uniform sampler s_depth;
+
+void main() {
+    gl_FragDepth = texelFetch(sampler2DMS(t_depth, s_depth), ivec2(gl_FragCoord.xy), 0).r;
+}
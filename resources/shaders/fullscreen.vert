@@ -0,0 +1,11 @@
+#version 450
+
+layout(location=0) out vec2 v_tex_coords;
+
+void main() {
+    // A single triangle covering the screen, with texture coordinates running down from the
+    // top left corner.
+    vec2 tex_coords = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    v_tex_coords = tex_coords;
+    gl_Position = vec4(tex_coords.x * 2.0 - 1.0, 1.0 - tex_coords.y * 2.0, 0.0, 1.0);
+}
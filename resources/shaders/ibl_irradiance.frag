@@ -0,0 +1,41 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform textureCube t_environment;
+layout(set=0, binding=1) uniform sampler s_environment;
+layout(set=0, binding=2) uniform Face {
+    vec4 u_right;
+    vec4 u_up;
+    vec4 u_forward;
+    // x holds the roughness of the prefiltered level
+    vec4 u_params;
+};
+
+const float PI = 3.14159265359;
+const float SAMPLE_DELTA = 0.1;
+
+void main() {
+    vec2 position = vec2(v_tex_coords.x * 2.0 - 1.0, 1.0 - v_tex_coords.y * 2.0);
+    vec3 normal = normalize(u_forward.xyz + position.x * u_right.xyz + position.y * u_up.xyz);
+    vec3 up = abs(normal.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 right = normalize(cross(up, normal));
+    up = cross(normal, right);
+
+    // Cosine weighted sum of the light arriving over the hemisphere around the normal.
+    vec3 irradiance = vec3(0.0);
+    float sample_count = 0.0;
+    for (float phi = 0.0; phi < 2.0 * PI; phi += SAMPLE_DELTA) {
+        for (float theta = 0.0; theta < 0.5 * PI; theta += SAMPLE_DELTA) {
+            vec3 direction = normal * cos(theta)
+                + (right * cos(phi) + up * sin(phi)) * sin(theta);
+            irradiance += texture(samplerCube(t_environment, s_environment), direction).rgb
+                * cos(theta) * sin(theta);
+            sample_count += 1.0;
+        }
+    }
+
+    f_color = vec4(PI * irradiance / sample_count, 1.0);
+}
@@ -0,0 +1,66 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform textureCube t_environment;
+layout(set=0, binding=1) uniform sampler s_environment;
+layout(set=0, binding=2) uniform Face {
+    vec4 u_right;
+    vec4 u_up;
+    vec4 u_forward;
+    // x holds the roughness of the prefiltered level
+    vec4 u_params;
+};
+
+const float PI = 3.14159265359;
+const uint SAMPLE_COUNT = 128u;
+
+// Low discrepancy sequence spreading the samples evenly.
+vec2 hammersley(uint i) {
+    uint bits = (i << 16u) | (i >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return vec2(float(i) / float(SAMPLE_COUNT), float(bits) * 2.3283064365386963e-10);
+}
+
+// Half vector around the normal, distributed like the GGX microfacets.
+vec3 importance_sample_ggx(vec2 xi, vec3 normal, float roughness) {
+    float a = roughness * roughness;
+    float phi = 2.0 * PI * xi.x;
+    float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+
+    vec3 up = abs(normal.z) < 0.999 ? vec3(0.0, 0.0, 1.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(up, normal));
+    vec3 bitangent = cross(normal, tangent);
+    return normalize(
+        (tangent * cos(phi) + bitangent * sin(phi)) * sin_theta + normal * cos_theta
+    );
+}
+
+void main() {
+    vec2 position = vec2(v_tex_coords.x * 2.0 - 1.0, 1.0 - v_tex_coords.y * 2.0);
+    vec3 normal = normalize(u_forward.xyz + position.x * u_right.xyz + position.y * u_up.xyz);
+    // The view direction is assumed to match the reflection, which loses the stretching of
+    // reflections at grazing angles.
+    vec3 view = normal;
+    float roughness = u_params.x;
+
+    vec3 color = vec3(0.0);
+    float weight = 0.0;
+    for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+        vec3 half_dir = importance_sample_ggx(hammersley(i), normal, roughness);
+        vec3 light_dir = normalize(2.0 * dot(view, half_dir) * half_dir - view);
+        float n_dot_l = dot(normal, light_dir);
+        if (n_dot_l > 0.0) {
+            color += texture(samplerCube(t_environment, s_environment), light_dir).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+
+    f_color = vec4(color / max(weight, 0.001), 1.0);
+}
@@ -0,0 +1,23 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+layout(set=0, binding=2) uniform PostEffect {
+    // texel size, near and far plane
+    vec4 u_frame;
+    // strength
+    vec4 u_params[2];
+};
+
+void main() {
+    // Red and blue are pulled apart towards the edges of the screen.
+    vec2 offset = (v_tex_coords - 0.5) * u_params[0].x;
+    float r = texture(sampler2D(t_source, s_source), v_tex_coords + offset).r;
+    float g = texture(sampler2D(t_source, s_source), v_tex_coords).g;
+    float b = texture(sampler2D(t_source, s_source), v_tex_coords - offset).b;
+    f_color = vec4(r, g, b, 1.0);
+}
@@ -0,0 +1,34 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+layout(set=0, binding=2) uniform PostEffect {
+    // texel size, near and far plane
+    vec4 u_frame;
+    // strength
+    vec4 u_params[2];
+};
+// 16x16x16 colour cube unwrapped into 16 slices side by side, blue selecting the slice.
+layout(set=0, binding=3) uniform texture2D t_lut;
+
+const float LUT_SIZE = 16.0;
+
+vec3 lookup(vec3 color) {
+    vec3 cell = clamp(color, 0.0, 1.0) * (LUT_SIZE - 1.0);
+    float slice = floor(cell.b);
+    float next_slice = min(slice + 1.0, LUT_SIZE - 1.0);
+    vec2 texel = vec2(cell.r + 0.5, cell.g + 0.5) / vec2(LUT_SIZE * LUT_SIZE, LUT_SIZE);
+    vec2 slice_width = vec2(1.0 / LUT_SIZE, 0.0);
+    vec3 a = texture(sampler2D(t_lut, s_source), texel + slice_width * slice).rgb;
+    vec3 b = texture(sampler2D(t_lut, s_source), texel + slice_width * next_slice).rgb;
+    return mix(a, b, cell.b - slice);
+}
+
+void main() {
+    vec3 color = texture(sampler2D(t_source, s_source), v_tex_coords).rgb;
+    f_color = vec4(mix(color, lookup(color), u_params[0].x), 1.0);
+}
@@ -0,0 +1,53 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+layout(set=0, binding=2) uniform PostEffect {
+    // texel size, near and far plane
+    vec4 u_frame;
+    // span max, reduce multiplier and reduce minimum
+    vec4 u_params[2];
+};
+
+vec3 tap(vec2 offset) {
+    return texture(sampler2D(t_source, s_source), v_tex_coords + offset).rgb;
+}
+
+float luma(vec3 color) {
+    // Edges are found on perceptual brightness, so the linear colour is roughly gamma encoded.
+    return dot(sqrt(color), vec3(0.299, 0.587, 0.114));
+}
+
+void main() {
+    vec2 texel = u_frame.xy;
+    vec3 rgb_m = tap(vec2(0.0));
+    float luma_nw = luma(tap(vec2(-1.0, -1.0) * texel));
+    float luma_ne = luma(tap(vec2(1.0, -1.0) * texel));
+    float luma_sw = luma(tap(vec2(-1.0, 1.0) * texel));
+    float luma_se = luma(tap(vec2(1.0, 1.0) * texel));
+    float luma_m = luma(rgb_m);
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    vec2 direction = vec2(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        (luma_nw + luma_sw) - (luma_ne + luma_se)
+    );
+    float reduce = max(
+        (luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * u_params[0].y,
+        u_params[0].z
+    );
+    float scale = 1.0 / (min(abs(direction.x), abs(direction.y)) + reduce);
+    direction = clamp(direction * scale, vec2(-u_params[0].x), vec2(u_params[0].x)) * texel;
+
+    vec3 rgb_a = 0.5 * (tap(direction * (1.0 / 3.0 - 0.5)) + tap(direction * (2.0 / 3.0 - 0.5)));
+    vec3 rgb_b = rgb_a * 0.5 + 0.25 * (tap(direction * -0.5) + tap(direction * 0.5));
+    float luma_b = luma(rgb_b);
+    vec3 color = (luma_b < luma_min || luma_b > luma_max) ? rgb_a : rgb_b;
+
+    f_color = vec4(color, 1.0);
+}
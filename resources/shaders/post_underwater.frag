@@ -0,0 +1,35 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+layout(set=0, binding=2) uniform PostEffect {
+    // texel size, near and far plane
+    vec4 u_frame;
+    // tint colour and visibility distance, then strength
+    vec4 u_params[2];
+};
+layout(set=0, binding=4) uniform texture2D t_depth;
+layout(set=0, binding=5) uniform sampler s_depth;
+
+float linear_depth(float depth) {
+    float near = u_frame.z;
+    float far = u_frame.w;
+    return near * far / (far - depth * (far - near));
+}
+
+void main() {
+    vec3 color = texture(sampler2D(t_source, s_source), v_tex_coords).rgb;
+    float depth = linear_depth(texture(sampler2D(t_depth, s_depth), v_tex_coords).r);
+
+    vec3 tint = u_params[0].rgb;
+    float strength = u_params[1].x;
+    float murk = 1.0 - exp(-depth / max(u_params[0].w, 0.001));
+    color *= mix(vec3(1.0), tint, strength);
+    color = mix(color, tint * 0.2, murk * strength);
+
+    f_color = vec4(color, 1.0);
+}
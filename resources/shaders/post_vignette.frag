@@ -0,0 +1,22 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+layout(set=0, binding=2) uniform PostEffect {
+    // texel size, near and far plane
+    vec4 u_frame;
+    // intensity, radius and softness
+    vec4 u_params[2];
+};
+
+void main() {
+    vec3 color = texture(sampler2D(t_source, s_source), v_tex_coords).rgb;
+    float distance = length(v_tex_coords - 0.5) * 1.41421356;
+    float radius = u_params[0].y;
+    float vignette = smoothstep(radius, radius - u_params[0].z, distance);
+    f_color = vec4(color * mix(1.0, vignette, u_params[0].x), 1.0);
+}
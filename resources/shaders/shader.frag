@@ -3,50 +3,344 @@
 layout(location=0) in vec2 v_tex_coords;
 layout(location=1) in vec3 v_normal;
 layout(location=2) in vec3 v_position;
+layout(location=3) in vec4 v_tangent;
+layout(location=4) flat in uint v_layer;
 
 layout(location=0) out vec4 f_color;
 
-layout(set = 0, binding = 0) uniform texture2D t_diffuse;
+// One layer for most materials, and one per block texture for the terrain.
+layout(set = 0, binding = 0) uniform texture2DArray t_diffuse;
 layout(set = 0, binding = 1) uniform sampler s_diffuse;
 
-//layout(set = 0, binding = 2) uniform texture2D t_normal;
-//layout(set = 0, binding = 3) uniform sampler s_normal;
+layout(set = 0, binding = 2) uniform texture2D t_normal;
+layout(set = 0, binding = 3) uniform sampler s_normal;
+layout(set = 0, binding = 4) uniform texture2D t_specular;
+layout(set = 0, binding = 5) uniform sampler s_specular;
+layout(set = 0, binding = 6) uniform texture2D t_emissive;
+layout(set = 0, binding = 7) uniform sampler s_emissive;
+layout(set = 0, binding = 8) uniform texture2D t_ao;
+layout(set = 0, binding = 9) uniform sampler s_ao;
+layout(set = 0, binding = 10) uniform MaterialFactors {
+    vec4 u_base_color;
+    // w holds the material model
+    vec4 u_emissive;
+    // metallic, roughness and occlusion strength
+    vec4 u_material_params;
+};
 
 
 layout(set=1, binding=0)
 uniform Uniforms {
     vec3 u_view_position;
     mat4 u_view_proj;
+    // w holds the fog mode
+    vec4 u_fog_color;
+    // start and end distance, base height and height falloff
+    vec4 u_fog_params;
+    // direction towards the sun, w holds the scattering strength
+    vec4 u_fog_sun_direction;
+    vec4 u_fog_sun_color;
+    // inverse frame size
+    vec4 u_screen;
+    // highest prefiltered mip level and intensity of the image based lighting
+    vec4 u_environment;
 };
+// Ambient occlusion of the frame, white while SSAO is off.
+layout(set=1, binding=1) uniform texture2D t_occlusion;
+layout(set=1, binding=2) uniform sampler s_occlusion;
+layout(set=1, binding=3) uniform textureCube t_irradiance;
+layout(set=1, binding=4) uniform textureCube t_prefiltered;
+layout(set=1, binding=5) uniform texture2D t_brdf_lut;
+layout(set=1, binding=6) uniform sampler s_environment;
+
+const uint MODEL_PHONG = 0u;
+const uint MODEL_PBR = 1u;
+
+const float PI = 3.14159265359;
+
+const uint FOG_OFF = 0u;
+const uint FOG_LINEAR = 1u;
+const uint FOG_EXPONENTIAL = 2u;
+const uint FOG_HEIGHT = 3u;
+
+const uint LIGHT_POINT = 0u;
+const uint LIGHT_SPOT = 1u;
+const uint LIGHT_DIRECTIONAL = 2u;
 
-layout(set=2, binding=0) uniform Light {
-    vec3 light_position;
-    vec3 light_color;
+struct Light {
+    // w holds the light kind
+    vec4 position;
+    // w holds the cosine of the outer spot angle
+    vec4 direction;
+    // w holds the intensity
+    vec4 color;
+    // constant, linear and quadratic terms, w holds the cosine of the inner spot angle
+    vec4 attenuation;
 };
 
+layout(set=2, binding=0) readonly buffer Lights {
+    uvec4 light_count;
+    vec4 ambient;
+    Light lights[];
+};
+
+const int MAX_CASCADES = 4;
+
+layout(set=3, binding=0) uniform texture2DArray t_shadow;
+layout(set=3, binding=1) uniform samplerShadow s_shadow;
+layout(set=3, binding=2) uniform Shadows {
+    mat4 cascades[MAX_CASCADES];
+    // far view distance of every cascade
+    vec4 cascade_splits;
+    vec4 view_forward;
+    // cascade count, depth bias, normal bias and texel size
+    vec4 shadow_params;
+};
+
+// Fraction of the sun's light reaching this fragment, filtered over 3x3 shadow map texels.
+float sun_visibility(vec3 normal) {
+    int cascade_count = int(shadow_params.x);
+    float view_depth = dot(v_position - u_view_position, view_forward.xyz);
+    int cascade = 0;
+    while (cascade < cascade_count && view_depth > cascade_splits[cascade]) {
+        cascade++;
+    }
+    if (cascade >= cascade_count) {
+        return 1.0;
+    }
+
+    vec4 light_space = cascades[cascade] * vec4(v_position + normal * shadow_params.z, 1.0);
+    vec3 coords = light_space.xyz / light_space.w;
+    vec2 uv = vec2(coords.x * 0.5 + 0.5, 0.5 - coords.y * 0.5);
+    if (coords.z > 1.0 || any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0)))) {
+        return 1.0;
+    }
+
+    float depth = coords.z - shadow_params.y;
+    float visibility = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec2 offset = vec2(x, y) * shadow_params.w;
+            visibility += texture(
+                sampler2DArrayShadow(t_shadow, s_shadow),
+                vec4(uv + offset, float(cascade), depth)
+            );
+        }
+    }
+    return visibility / 9.0;
+}
+
+// How much of the fragment is hidden by fog, from 0 to 1.
+float fog_amount(float distance, vec3 ray) {
+    uint mode = uint(u_fog_color.w);
+    float start = u_fog_params.x;
+    float end = u_fog_params.y;
+    // Dense enough to hide 98% by the end distance.
+    float density = 4.0 / end;
+
+    float amount = 0.0;
+    if (mode == FOG_LINEAR) {
+        amount = clamp((distance - start) / max(end - start, 0.001), 0.0, 1.0);
+    } else if (mode == FOG_EXPONENTIAL) {
+        amount = 1.0 - exp(-distance * density);
+    } else if (mode == FOG_HEIGHT) {
+        // Density falls off exponentially with height, integrated along the view ray.
+        float falloff = u_fog_params.w;
+        float origin = (u_view_position.y - u_fog_params.z) * falloff;
+        float rise = ray.y * distance * falloff;
+        float along_ray = abs(rise) > 0.001 ? (1.0 - exp(-rise)) / rise : 1.0;
+        amount = 1.0 - exp(-density * distance * exp(-origin) * along_ray);
+    }
+    // Terrain past the load distance is always hidden, whatever the fog mode.
+    return max(amount, smoothstep(end * 0.9, end, distance));
+}
+
+vec3 apply_fog(vec3 color) {
+    if (uint(u_fog_color.w) == FOG_OFF) {
+        return color;
+    }
+    vec3 to_fragment = v_position - u_view_position;
+    float distance = length(to_fragment);
+    vec3 ray = to_fragment / max(distance, 0.001);
+
+    // Sunlight scattered towards the camera brightens the fog when looking towards the sun.
+    float sun_amount = max(dot(ray, u_fog_sun_direction.xyz), 0.0);
+    vec3 fog_color = u_fog_color.rgb
+        + u_fog_sun_color.rgb * pow(sun_amount, 8.0) * u_fog_sun_direction.w;
+    return mix(color, fog_color, fog_amount(distance, ray));
+}
+
+float distribution_ggx(float n_dot_h, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float denominator = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denominator * denominator);
+}
+
+float geometry_schlick_ggx(float n_dot_v, float roughness) {
+    float r = roughness + 1.0;
+    float k = r * r / 8.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+vec3 fresnel_schlick_roughness(float cos_theta, vec3 f0, float roughness) {
+    return f0 + (max(vec3(1.0 - roughness), f0) - f0)
+        * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Cook-Torrance BRDF with a GGX distribution, Smith geometry and Schlick Fresnel, already
+// multiplied by the cosine of the light angle.
+vec3 cook_torrance(
+    vec3 normal,
+    vec3 view_dir,
+    vec3 light_dir,
+    vec3 albedo,
+    vec3 f0,
+    float metallic,
+    float roughness
+) {
+    vec3 half_dir = normalize(view_dir + light_dir);
+    float n_dot_l = max(dot(normal, light_dir), 0.0);
+    float n_dot_v = max(dot(normal, view_dir), 0.0);
+    float distribution = distribution_ggx(max(dot(normal, half_dir), 0.0), roughness);
+    float geometry = geometry_schlick_ggx(n_dot_v, roughness)
+        * geometry_schlick_ggx(n_dot_l, roughness);
+    vec3 fresnel = fresnel_schlick(max(dot(half_dir, view_dir), 0.0), f0);
+
+    vec3 specular = distribution * geometry * fresnel / max(4.0 * n_dot_v * n_dot_l, 0.001);
+    vec3 diffuse = (1.0 - fresnel) * (1.0 - metallic) * albedo / PI;
+    return (diffuse + specular) * n_dot_l;
+}
+
+// Ambient light of PBR materials from the irradiance and prefiltered environment maps.
+vec3 environment_light(
+    vec3 normal,
+    vec3 view_dir,
+    vec3 albedo,
+    vec3 f0,
+    float metallic,
+    float roughness
+) {
+    float n_dot_v = max(dot(normal, view_dir), 0.0);
+    vec3 fresnel = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    vec3 diffuse_weight = (1.0 - fresnel) * (1.0 - metallic);
+
+    vec3 irradiance = texture(samplerCube(t_irradiance, s_environment), normal).rgb;
+    vec3 reflection = reflect(-view_dir, normal);
+    vec3 prefiltered = textureLod(
+        samplerCube(t_prefiltered, s_environment),
+        reflection,
+        roughness * u_environment.x
+    ).rgb;
+    vec2 brdf = texture(sampler2D(t_brdf_lut, s_environment), vec2(n_dot_v, roughness)).rg;
+
+    vec3 diffuse = diffuse_weight * irradiance * albedo;
+    vec3 specular = prefiltered * (fresnel * brdf.x + brdf.y);
+    // The flat ambient light keeps unlit areas from going black when the environment is off.
+    return (diffuse + specular) * u_environment.y
+        + diffuse_weight * albedo * ambient.rgb * ambient.a;
+}
+
 void main() {
 
-    vec4 object_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
-    // Not supporting normal mapping for now
-    // vec4 object_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords);
-
-//    float ambient_strength = 0.1;
-//    vec3 ambient_color = light_color * ambient_strength;
-//    vec3 normal = normalize(v_normal);
-//    // vec3 normal = normalize(object_normal.rgb * 2.0 - 1.0);
-//    vec3 light_dir = normalize(light_position - v_position);
-//
-//    vec3 view_dir = normalize(u_view_position - v_position);
-//    vec3 half_dir = normalize(view_dir + light_dir);
-//    vec3 reflect_dir = reflect(-light_dir, normal);
-//
-//    float specular_strength = pow(max(dot(normal, half_dir), 0.0), 32);
-//    vec3 specular_color = specular_strength * light_color;
-//
-//    float diffuse_strength = max(dot(normal, light_dir), 0.0);
-//    vec3 diffuse_color = light_color * diffuse_strength;
-//
-//    vec3 result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
-
-    f_color = object_color; // vec4(result, object_color.a);
-}
\ No newline at end of file
+    vec4 object_color = texture(
+        sampler2DArray(t_diffuse, s_diffuse),
+        vec3(v_tex_coords, float(v_layer))
+    ) * u_base_color;
+    vec3 object_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords).xyz * 2.0 - 1.0;
+    vec4 specular_map = texture(sampler2D(t_specular, s_specular), v_tex_coords);
+    vec3 emissive = texture(sampler2D(t_emissive, s_emissive), v_tex_coords).rgb * u_emissive.rgb;
+    float material_occlusion = mix(
+        1.0,
+        texture(sampler2D(t_ao, s_ao), v_tex_coords).r,
+        u_material_params.z
+    );
+    bool pbr = uint(u_emissive.w) == MODEL_PBR;
+    float metallic = clamp(specular_map.b * u_material_params.x, 0.0, 1.0);
+    float roughness = clamp(specular_map.g * u_material_params.y, 0.04, 1.0);
+    vec3 f0 = mix(vec3(0.04), object_color.rgb, metallic);
+
+    float occlusion = texture(
+        sampler2D(t_occlusion, s_occlusion),
+        gl_FragCoord.xy * u_screen.xy
+    ).r * material_occlusion;
+    vec3 vertex_normal = normalize(v_normal);
+    vec3 tangent = normalize(v_tangent.xyz - vertex_normal * dot(v_tangent.xyz, vertex_normal));
+    vec3 bitangent = cross(vertex_normal, tangent) * v_tangent.w;
+    vec3 normal = normalize(mat3(tangent, bitangent, vertex_normal) * object_normal);
+    vec3 view_dir = normalize(u_view_position - v_position);
+
+    vec3 diffuse_color = vec3(0.0);
+    vec3 specular_color = vec3(0.0);
+    vec3 pbr_color = vec3(0.0);
+    for (uint i = 0u; i < light_count.x; i++) {
+        Light light = lights[i];
+        uint kind = uint(light.position.w);
+
+        vec3 light_dir;
+        float attenuation = 1.0;
+        if (kind == LIGHT_DIRECTIONAL) {
+            light_dir = -light.direction.xyz;
+        } else {
+            vec3 to_light = light.position.xyz - v_position;
+            float distance = length(to_light);
+            light_dir = to_light / distance;
+            attenuation = 1.0 / (light.attenuation.x
+                + light.attenuation.y * distance
+                + light.attenuation.z * distance * distance);
+            if (kind == LIGHT_SPOT) {
+                float theta = dot(-light_dir, light.direction.xyz);
+                attenuation *= smoothstep(light.direction.w, light.attenuation.w, theta);
+            }
+        }
+        // The brightest directional light comes first and is the one casting shadows.
+        if (i == 0u && kind == LIGHT_DIRECTIONAL) {
+            attenuation *= sun_visibility(vertex_normal);
+        }
+        vec3 radiance = light.color.rgb * light.color.a * attenuation;
+
+        if (pbr) {
+            pbr_color += radiance * cook_torrance(
+                normal,
+                view_dir,
+                light_dir,
+                object_color.rgb,
+                f0,
+                metallic,
+                roughness
+            );
+            continue;
+        }
+
+        float diffuse_strength = max(dot(normal, light_dir), 0.0);
+        diffuse_color += radiance * diffuse_strength;
+
+        vec3 half_dir = normalize(view_dir + light_dir);
+        float specular_strength = diffuse_strength > 0.0
+            ? pow(max(dot(normal, half_dir), 0.0), 32)
+            : 0.0;
+        specular_color += radiance * specular_strength * specular_map.r;
+    }
+
+    vec3 result;
+    if (pbr) {
+        vec3 ambient_light = environment_light(
+            normal,
+            view_dir,
+            object_color.rgb,
+            f0,
+            metallic,
+            roughness
+        );
+        result = ambient_light * occlusion + pbr_color + emissive;
+    } else {
+        vec3 ambient_color = ambient.rgb * ambient.a * occlusion;
+        result = (ambient_color + diffuse_color + specular_color) * object_color.xyz + emissive;
+    }
+
+    f_color = vec4(apply_fog(result), object_color.a);
+}
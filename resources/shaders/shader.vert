@@ -3,6 +3,8 @@
 layout(location=0) in vec3 a_position;
 layout(location=1) in vec2 a_tex_coords;
 layout(location=2) in vec3 a_normal;
+layout(location=3) in vec4 a_tangent;
+layout(location=4) in uint a_layer;
 
 layout(location=5) in vec4 model_matrix0;
 layout(location=6) in vec4 model_matrix1;
@@ -12,6 +14,8 @@ layout(location=8) in vec4 model_matrix3;
 layout(location=0) out vec2 v_tex_coords;
 layout(location=1) out vec3 v_normal;
 layout(location=2) out vec3 v_position;
+layout(location=3) out vec4 v_tangent;
+layout(location=4) flat out uint v_layer;
 
 layout(set=1, binding=0) uniform Uniforms {
     vec3 u_view_position;
@@ -24,7 +28,9 @@ void main() {
     vec4 model_space = model_matrix * vec4(a_position, 1.0);
     v_tex_coords = a_tex_coords;
     v_normal = normal_matrix * a_normal;
+    v_tangent = vec4(mat3(model_matrix) * a_tangent.xyz, a_tangent.w);
     v_position = model_space.xyz;
+    v_layer = a_layer;
 
     gl_Position = u_view_proj * model_space;
 }
\ No newline at end of file
@@ -0,0 +1,17 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+
+layout(location=5) in vec4 model_matrix0;
+layout(location=6) in vec4 model_matrix1;
+layout(location=7) in vec4 model_matrix2;
+layout(location=8) in vec4 model_matrix3;
+
+layout(set=0, binding=0) uniform Cascade {
+    mat4 u_light_view_proj;
+};
+
+void main() {
+    mat4 model_matrix = mat4(model_matrix0, model_matrix1, model_matrix2, model_matrix3);
+    gl_Position = u_light_view_proj * model_matrix * vec4(a_position, 1.0);
+}
@@ -0,0 +1,53 @@
+#version 450
+
+layout(location=0) in vec3 v_ray;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform Sky {
+    mat4 u_inverse_view_proj;
+    vec4 u_view_position;
+    // w holds the cosine of the disc radius
+    vec4 u_sun_direction;
+    vec4 u_moon_direction;
+    vec4 u_zenith_color;
+    vec4 u_horizon_color;
+    vec4 u_sun_color;
+    // x holds the star visibility
+    vec4 u_params;
+};
+
+const vec3 MOON_COLOR = vec3(0.8, 0.85, 0.95);
+
+float hash(vec3 p) {
+    p = fract(p * 0.3183099 + 0.1);
+    p *= 17.0;
+    return fract(p.x * p.y * p.z * (p.x + p.y + p.z));
+}
+
+// Soft edged disc around `direction`, whose w holds the cosine of its radius.
+float disc(vec3 ray, vec4 direction) {
+    return smoothstep(direction.w, mix(direction.w, 1.0, 0.1), dot(ray, direction.xyz));
+}
+
+void main() {
+    vec3 ray = normalize(v_ray);
+    float height = ray.y;
+
+    // Below the horizon the sky keeps the horizon colour, which the fog shares, so fogged
+    // terrain fades into it.
+    vec3 color = mix(u_horizon_color.rgb, u_zenith_color.rgb, sqrt(clamp(height, 0.0, 1.0)));
+
+    float above_horizon = smoothstep(-0.02, 0.02, height);
+    float sun = max(dot(ray, u_sun_direction.xyz), 0.0);
+    color += u_sun_color.rgb * pow(sun, 64.0) * 0.4 * above_horizon;
+    color += u_sun_color.rgb * disc(ray, u_sun_direction) * above_horizon;
+    color += MOON_COLOR * disc(ray, u_moon_direction) * above_horizon;
+
+    if (u_params.x > 0.0) {
+        float star = step(0.9985, hash(floor(ray * 400.0)));
+        color += vec3(star * u_params.x * clamp(height * 4.0, 0.0, 1.0));
+    }
+
+    f_color = vec4(color, 1.0);
+}
@@ -0,0 +1,23 @@
+#version 450
+
+layout(location=0) out vec3 v_ray;
+
+layout(set=0, binding=0) uniform Sky {
+    mat4 u_inverse_view_proj;
+    vec4 u_view_position;
+    vec4 u_sun_direction;
+    vec4 u_moon_direction;
+    vec4 u_zenith_color;
+    vec4 u_horizon_color;
+    vec4 u_sun_color;
+    vec4 u_params;
+};
+
+void main() {
+    // A single triangle covering the screen, placed on the far plane.
+    vec2 position = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2) * 2.0 - 1.0;
+    vec4 world = u_inverse_view_proj * vec4(position, 1.0, 1.0);
+    v_ray = world.xyz / world.w - u_view_position.xyz;
+
+    gl_Position = vec4(position, 1.0, 1.0);
+}
@@ -0,0 +1,68 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out float f_occlusion;
+
+const int MAX_SAMPLES = 64;
+
+layout(set=0, binding=0) uniform texture2D t_depth;
+layout(set=0, binding=1) uniform texture2D t_normal;
+layout(set=0, binding=2) uniform texture2D t_noise;
+layout(set=0, binding=3) uniform sampler s_clamp;
+layout(set=0, binding=4) uniform sampler s_repeat;
+layout(set=0, binding=5) uniform Ssao {
+    mat4 u_projection;
+    mat4 u_inverse_projection;
+    // sample count, radius, bias and intensity
+    vec4 u_params;
+    // noise tiling, then texel size
+    vec4 u_scale;
+    // hemisphere samples, denser towards the centre
+    vec4 u_kernel[MAX_SAMPLES];
+};
+
+vec3 view_position(vec2 uv) {
+    float depth = texture(sampler2D(t_depth, s_clamp), uv).r;
+    vec4 ndc = vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    vec4 position = u_inverse_projection * ndc;
+    return position.xyz / position.w;
+}
+
+void main() {
+    float depth = texture(sampler2D(t_depth, s_clamp), v_tex_coords).r;
+    // Nothing was drawn here, so there is nothing to occlude.
+    if (depth >= 1.0) {
+        f_occlusion = 1.0;
+        return;
+    }
+
+    int sample_count = int(u_params.x);
+    float radius = u_params.y;
+    float bias = u_params.z;
+
+    vec3 position = view_position(v_tex_coords);
+    vec3 normal = normalize(texture(sampler2D(t_normal, s_clamp), v_tex_coords).xyz);
+    vec3 random = texture(sampler2D(t_noise, s_repeat), v_tex_coords * u_scale.xy).xyz;
+
+    // Rotates the kernel around the normal by the noise vector, which the blur smooths out.
+    vec3 tangent = normalize(random - normal * dot(random, normal));
+    vec3 bitangent = cross(normal, tangent);
+    mat3 tbn = mat3(tangent, bitangent, normal);
+
+    float occlusion = 0.0;
+    for (int i = 0; i < sample_count; i++) {
+        vec3 sample_position = position + tbn * u_kernel[i].xyz * radius;
+
+        vec4 offset = u_projection * vec4(sample_position, 1.0);
+        offset.xy /= offset.w;
+        vec2 sample_uv = vec2(offset.x * 0.5 + 0.5, 0.5 - offset.y * 0.5);
+
+        float sample_depth = view_position(sample_uv).z;
+        float range = smoothstep(0.0, 1.0, radius / abs(position.z - sample_depth));
+        occlusion += (sample_depth >= sample_position.z + bias ? 1.0 : 0.0) * range;
+    }
+
+    float visibility = 1.0 - occlusion / float(max(sample_count, 1));
+    f_occlusion = pow(visibility, u_params.w);
+}
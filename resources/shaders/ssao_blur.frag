@@ -0,0 +1,30 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out float f_occlusion;
+
+const int MAX_SAMPLES = 64;
+
+layout(set=0, binding=0) uniform texture2D t_occlusion;
+layout(set=0, binding=1) uniform sampler s_clamp;
+layout(set=0, binding=2) uniform Ssao {
+    mat4 u_projection;
+    mat4 u_inverse_projection;
+    vec4 u_params;
+    // noise tiling, then texel size
+    vec4 u_scale;
+    vec4 u_kernel[MAX_SAMPLES];
+};
+
+void main() {
+    // Averages over the 4x4 tile of the noise texture, which removes its pattern.
+    float occlusion = 0.0;
+    for (int x = -2; x < 2; x++) {
+        for (int y = -2; y < 2; y++) {
+            vec2 offset = vec2(float(x), float(y)) * u_scale.zw;
+            occlusion += texture(sampler2D(t_occlusion, s_clamp), v_tex_coords + offset).r;
+        }
+    }
+    f_occlusion = occlusion / 16.0;
+}
@@ -0,0 +1,24 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=2) in vec3 a_normal;
+
+layout(location=5) in vec4 model_matrix0;
+layout(location=6) in vec4 model_matrix1;
+layout(location=7) in vec4 model_matrix2;
+layout(location=8) in vec4 model_matrix3;
+
+layout(location=0) out vec3 v_normal;
+
+layout(set=0, binding=0) uniform Prepass {
+    mat4 u_view;
+    mat4 u_view_proj;
+};
+
+void main() {
+    mat4 model_matrix = mat4(model_matrix0, model_matrix1, model_matrix2, model_matrix3);
+    mat3 normal_matrix = mat3(transpose(inverse(model_matrix)));
+    // Normals are stored in view space, where the occlusion pass works.
+    v_normal = mat3(u_view) * (normal_matrix * a_normal);
+    gl_Position = u_view_proj * model_matrix * vec4(a_position, 1.0);
+}
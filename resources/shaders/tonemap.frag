@@ -0,0 +1,37 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_hdr;
+layout(set=0, binding=1) uniform sampler s_hdr;
+layout(set=0, binding=2) uniform ToneMap {
+    // exposure, bloom intensity and tone mapping operator
+    vec4 u_params;
+};
+layout(set=0, binding=3) uniform texture2D t_bloom;
+
+const uint TONE_MAPPING_OFF = 0u;
+const uint TONE_MAPPING_REINHARD = 1u;
+const uint TONE_MAPPING_ACES = 2u;
+
+// Narkowicz's fit of the ACES filmic curve.
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+void main() {
+    vec3 color = texture(sampler2D(t_hdr, s_hdr), v_tex_coords).rgb;
+    color += texture(sampler2D(t_bloom, s_hdr), v_tex_coords).rgb * u_params.y;
+    color *= u_params.x;
+
+    uint mode = uint(u_params.z);
+    if (mode == TONE_MAPPING_REINHARD) {
+        color = color / (1.0 + color);
+    } else if (mode == TONE_MAPPING_ACES) {
+        color = aces(color);
+    }
+
+    f_color = vec4(clamp(color, 0.0, 1.0), 1.0);
+}
@@ -1,9 +1,229 @@
-use crate::ecs::component::MeshId;
+use crate::{material::MaterialModel, mesh::GeneratedNormals, texture::SamplerConfig};
+use anyhow::*;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ModelAsset {
-    Cube,
-    Room,
-    Dynamic(u32),
-    DynamicMesh(MeshId),
+    Manifest(AssetHandle),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AssetHandle(u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum AssetKind {
+    Obj,
+    /// glTF 2.0, as `.gltf` or `.glb`.
+    Gltf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MaterialOverride {
+    pub material: String,
+    #[serde(default)]
+    pub diffuse_texture: Option<String>,
+    /// Shades the material with another model than the one its file asks for.
+    #[serde(default)]
+    pub model: Option<MaterialModel>,
+    #[serde(default)]
+    pub metallic: Option<f32>,
+    #[serde(default)]
+    pub roughness: Option<f32>,
+    /// Filtering and anisotropy of the textures of the material.
+    #[serde(default)]
+    pub sampler: Option<SamplerConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AssetDescriptor {
+    pub id: String,
+    pub path: String,
+    pub kind: AssetKind,
+    #[serde(default)]
+    pub materials: Vec<MaterialOverride>,
+    /// Normals generated for OBJ meshes that have none.
+    #[serde(default)]
+    pub normals: GeneratedNormals,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PropDescriptor {
+    pub asset: String,
+    pub position: (f32, f32, f32),
+    #[serde(default)]
+    pub rotation: (f32, f32, f32),
+}
+
+/// An image voxel faces can show, listed under the name the terrain refers to it by.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockTextureDescriptor {
+    pub name: String,
+    pub path: String,
+}
+
+/// The faces of the environment cubemap that image based lighting is convolved from.
+#[derive(Clone, Debug, Deserialize)]
+pub struct EnvironmentDescriptor {
+    pub positive_x: String,
+    pub negative_x: String,
+    pub positive_y: String,
+    pub negative_y: String,
+    pub positive_z: String,
+    pub negative_z: String,
+}
+
+impl EnvironmentDescriptor {
+    /// The faces in the layer order of cube textures.
+    fn faces(&self) -> [&str; 6] {
+        [
+            &self.positive_x,
+            &self.negative_x,
+            &self.positive_y,
+            &self.negative_y,
+            &self.positive_z,
+            &self.negative_z,
+        ]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AssetManifest {
+    pub assets: Vec<AssetDescriptor>,
+    #[serde(default)]
+    pub props: Vec<PropDescriptor>,
+    #[serde(default)]
+    pub blocks: Vec<BlockTextureDescriptor>,
+    #[serde(default)]
+    pub environment: Option<EnvironmentDescriptor>,
+}
+
+pub struct AssetRegistry {
+    root: PathBuf,
+    handles: HashMap<String, AssetHandle>,
+    descriptors: Vec<AssetDescriptor>,
+    props: Vec<PropDescriptor>,
+    block_textures: HashMap<String, String>,
+    environment: Option<EnvironmentDescriptor>,
+}
+
+impl AssetRegistry {
+    pub fn load<P: AsRef<Path>>(manifest_path: P) -> Result<Self> {
+        let manifest_path = manifest_path.as_ref();
+        let source = std::fs::read_to_string(manifest_path)
+            .with_context(|| format!("Unable to read manifest {:?}", manifest_path))?;
+        let manifest: AssetManifest = ron::de::from_str(&source)
+            .with_context(|| format!("Unable to parse manifest {:?}", manifest_path))?;
+        let root = manifest_path
+            .parent()
+            .context("Manifest has no parent directory")?;
+        Self::from_manifest(root, manifest)
+    }
+
+    pub fn from_manifest<P: AsRef<Path>>(root: P, manifest: AssetManifest) -> Result<Self> {
+        let mut handles = HashMap::new();
+        for (idx, descriptor) in manifest.assets.iter().enumerate() {
+            let handle = AssetHandle(idx as u32);
+            if handles.insert(descriptor.id.clone(), handle).is_some() {
+                bail!("Duplicate asset id {:?} in manifest", descriptor.id);
+            }
+        }
+        for prop in &manifest.props {
+            if !handles.contains_key(&prop.asset) {
+                bail!("Prop refers to unknown asset {:?}", prop.asset);
+            }
+        }
+        let mut block_textures = HashMap::new();
+        for block in manifest.blocks {
+            if block_textures
+                .insert(block.name.clone(), block.path)
+                .is_some()
+            {
+                bail!("Duplicate block texture {:?} in manifest", block.name);
+            }
+        }
+
+        Ok(Self {
+            root: root.as_ref().to_path_buf(),
+            handles,
+            descriptors: manifest.assets,
+            props: manifest.props,
+            block_textures,
+            environment: manifest.environment,
+        })
+    }
+
+    pub fn handle(&self, id: &str) -> Option<AssetHandle> {
+        self.handles.get(id).cloned()
+    }
+
+    pub fn descriptor(&self, handle: AssetHandle) -> Option<&AssetDescriptor> {
+        self.descriptors.get(handle.0 as usize)
+    }
+
+    pub fn path(&self, descriptor: &AssetDescriptor) -> PathBuf {
+        self.root.join(&descriptor.path)
+    }
+
+    pub fn props(&self) -> &[PropDescriptor] {
+        &self.props
+    }
+
+    pub fn block_texture_path(&self, name: &str) -> Option<PathBuf> {
+        self.block_textures
+            .get(name)
+            .map(|path| self.root.join(path))
+    }
+
+    /// Paths of the environment cubemap faces, in the layer order of cube textures.
+    pub fn environment_faces(&self) -> Option<Vec<PathBuf>> {
+        self.environment.as_ref().map(|environment| {
+            environment
+                .faces()
+                .iter()
+                .map(|path| self.root.join(path))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_faces_follow_the_cube_layer_order() {
+        let manifest: AssetManifest = ron::de::from_str(
+            r#"(
+                assets: [],
+                environment: Some((
+                    positive_x: "px.png",
+                    negative_x: "nx.png",
+                    positive_y: "py.png",
+                    negative_y: "ny.png",
+                    positive_z: "pz.png",
+                    negative_z: "nz.png",
+                )),
+            )"#,
+        )
+        .unwrap();
+        let registry = AssetRegistry::from_manifest("sky", manifest).unwrap();
+
+        let faces = registry.environment_faces().unwrap();
+        let names = ["px", "nx", "py", "ny", "pz", "nz"];
+        assert_eq!(faces.len(), names.len());
+        for (face, name) in faces.iter().zip(names.iter()) {
+            assert_eq!(*face, Path::new("sky").join(format!("{}.png", name)));
+        }
+    }
+
+    #[test]
+    fn environment_is_optional() {
+        let manifest: AssetManifest = ron::de::from_str("(assets: [])").unwrap();
+        let registry = AssetRegistry::from_manifest("", manifest).unwrap();
+        assert!(registry.environment_faces().is_none());
+    }
 }
@@ -6,4 +6,5 @@ pub trait BindGroup {
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Hash)]
 pub enum BindGroupType {
     Material,
+    Shadow,
 }
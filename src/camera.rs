@@ -40,7 +40,20 @@ impl Projection {
     }
 
     pub fn calc_matrix(&self) -> cgmath::Matrix4<f32> {
-        OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, self.znear, self.zfar)
+        self.calc_matrix_for_range(self.znear, self.zfar)
+    }
+
+    /// The same projection with its depth range replaced, used to split the view into slices.
+    pub fn calc_matrix_for_range(&self, znear: f32, zfar: f32) -> cgmath::Matrix4<f32> {
+        OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, znear, zfar)
+    }
+
+    pub fn znear(&self) -> f32 {
+        self.znear
+    }
+
+    pub fn zfar(&self) -> f32 {
+        self.zfar
     }
 }
 
@@ -69,12 +82,11 @@ impl Camera {
     }
 
     pub fn calc_matrix(&self) -> cgmath::Matrix4<f32> {
-        cgmath::Matrix4::look_to_rh(
-            self.position,
-            cgmath::Vector3::new(self.yaw.0.cos(), self.pitch.0.sin(), self.yaw.0.sin())
-                .normalize(),
-            cgmath::Vector3::unit_y(),
-        )
+        cgmath::Matrix4::look_to_rh(self.position, self.forward(), cgmath::Vector3::unit_y())
+    }
+
+    pub fn forward(&self) -> cgmath::Vector3<f32> {
+        cgmath::Vector3::new(self.yaw.0.cos(), self.pitch.0.sin(), self.yaw.0.sin()).normalize()
     }
 
     pub fn projection(&self, projection: &Projection) -> cgmath::Matrix4<f32> {
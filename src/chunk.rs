@@ -1,11 +1,11 @@
-use crate::mesh::MeshVertex;
+use crate::mesh::{compute_tangents, MeshVertex};
 use crate::worker::pool::Pool;
 use crate::{ecs::component::*, worker::worker::Worker};
 use legion::{Entity, World};
 use noise::Fbm;
 use noise::{
     utils::{NoiseMapBuilder, PlaneMapBuilder},
-    NoiseFn,
+    NoiseFn, Seedable,
 };
 use std::{collections::HashSet, thread};
 use std::{
@@ -223,9 +223,27 @@ impl ChunkExecutor {
         data.sender.send(mesh).unwrap();
     }
 }
-const CHUNK_SIZE: usize = 32;
+pub const CHUNK_SIZE: usize = 32;
 const CHUNK_RADIUS: i32 = 10;
 
+/// Distance from the camera within which terrain is always loaded. The load radius is counted
+/// from the chunk the camera is in, so the last ring of chunks is not included.
+pub fn load_distance() -> f32 {
+    ((CHUNK_RADIUS - 1) * CHUNK_SIZE as i32) as f32
+}
+
+/// Number of chunks within the load radius, which is the most that are ever loaded at once.
+pub fn max_loaded_chunks() -> u32 {
+    let radius = CHUNK_RADIUS * CHUNK_RADIUS;
+    (-CHUNK_RADIUS..=CHUNK_RADIUS)
+        .flat_map(|x| (-CHUNK_RADIUS..=CHUNK_RADIUS).map(move |z| x * x + z * z))
+        .filter(|distance| *distance <= radius)
+        .count() as u32
+}
+/// Every chunk is generated from the same noise seed, so a chunk location always produces the
+/// same mesh.
+const TERRAIN_SEED: u32 = 0;
+
 bitflags! {
     pub struct Sides: u32 {
         const NONE      = 0b00000000;
@@ -249,14 +267,76 @@ const CUBE_COORDINATES: [[f32; 3]; 8] = [
     [-0.5, 0.5, -0.5],
 ];
 
-const UVS: [[f32; 2]; 4] = [
-    [0.125, 1.0 - 0.9375],
-    [0.1875, 1.0 - 0.9375],
-    [0.125, 1.0 - 1.0],
-    [0.1875, 1.0 - 1.0],
-];
+/// Blocks up to this many below the grass are dirt, deeper ones are stone.
+const DIRT_DEPTH: usize = 3;
+
+/// The images voxel faces can show, in the order of the layers of the block texture array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockTexture {
+    GrassTop,
+    GrassSide,
+    Dirt,
+    Stone,
+}
+
+impl BlockTexture {
+    pub const ALL: [BlockTexture; 4] = [
+        BlockTexture::GrassTop,
+        BlockTexture::GrassSide,
+        BlockTexture::Dirt,
+        BlockTexture::Stone,
+    ];
+
+    /// The name the asset manifest lists the image under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockTexture::GrassTop => "grass_top",
+            BlockTexture::GrassSide => "grass_side",
+            BlockTexture::Dirt => "dirt",
+            BlockTexture::Stone => "stone",
+        }
+    }
+
+    pub fn layer(&self) -> u32 {
+        *self as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Block {
+    Grass,
+    Dirt,
+    Stone,
+}
+
+impl Block {
+    /// The block at `depth` blocks below the surface of its column.
+    fn at_depth(depth: usize) -> Self {
+        match depth {
+            0 => Block::Grass,
+            depth if depth <= DIRT_DEPTH => Block::Dirt,
+            _ => Block::Stone,
+        }
+    }
+
+    /// The texture shown on `side` of the block.
+    fn texture(&self, side: Sides) -> BlockTexture {
+        match (self, side) {
+            (Block::Grass, Sides::TOP) => BlockTexture::GrassTop,
+            (Block::Grass, Sides::BOTTOM) | (Block::Dirt, _) => BlockTexture::Dirt,
+            (Block::Grass, _) => BlockTexture::GrassSide,
+            (Block::Stone, _) => BlockTexture::Stone,
+        }
+    }
+}
+
+/// Every face covers a whole layer, with `[0.0, 0.0]` at its top left. Coordinates past 1
+/// repeat the texture, so faces spanning several voxels can tile it.
+const UVS: [[f32; 2]; 4] = [[0.0, 1.0], [1.0, 1.0], [0.0, 0.0], [1.0, 0.0]];
 
 const QUAD_UV_ORDER: [u32; 4] = [3, 2, 0, 1];
+/// The two triangles of a face, indexing its four vertices.
+const QUAD_INDICES: [u32; 6] = [3, 1, 0, 3, 2, 1];
 
 pub struct ChunkBuilder {
     idx: u32,
@@ -276,12 +356,13 @@ impl ChunkBuilder {
 
 pub fn make_mesh(idx: u32, chunk_location: cgmath::Vector2<i32>) -> MeshReference {
     let mut builder = VoxelMeshBuilder::new();
-    let mut fbm = Fbm::new();
+    let mut fbm = Fbm::new().set_seed(TERRAIN_SEED);
     fbm.octaves = 4;
     fbm.persistence = 0.5;
 
     PlaneMapBuilder::new(&fbm).set_size(1000, 100);
     let mut height_map = vec![vec![vec![false; CHUNK_SIZE + 2]; CHUNK_SIZE + 2]; CHUNK_SIZE + 2];
+    let mut surface = vec![vec![0; CHUNK_SIZE + 2]; CHUNK_SIZE + 2];
     for x in 0..CHUNK_SIZE + 2 {
         for z in 0..CHUNK_SIZE + 2 {
             let stone_height = fbm.get([
@@ -293,6 +374,7 @@ pub fn make_mesh(idx: u32, chunk_location: cgmath::Vector2<i32>) -> MeshReferenc
             for y in 0..CHUNK_SIZE {
                 height_map[x][y][z] = y < stone_height as usize;
             }
+            surface[x][z] = stone_height as usize;
         }
     }
 
@@ -303,6 +385,7 @@ pub fn make_mesh(idx: u32, chunk_location: cgmath::Vector2<i32>) -> MeshReferenc
                 if height_map[x][y][z] {
                     builder
                         .set_position(&pos)
+                        .set_block(Block::at_depth(surface[x][z] - 1 - y))
                         .generate_voxel(get_sides(&height_map, &pos));
                 }
             }
@@ -344,24 +427,26 @@ fn get_sides(height_map: &Vec<Vec<Vec<bool>>>, pos: &cgmath::Vector3<usize>) ->
 
 pub struct VoxelMeshBuilder {
     current_cube_pos: cgmath::Vector3<u32>,
+    current_block: Block,
     indices: Vec<u32>,
     vertices: Vec<MeshVertex>,
     index_offset: u32,
 }
 
-const SIDE_VERTICES: [(Sides, [u32; 4]); 6] = [
-    (Sides::TOP, [7, 6, 5, 4]),
-    (Sides::BOTTOM, [0, 1, 2, 3]),
-    (Sides::LEFT, [7, 4, 0, 3]),
-    (Sides::RIGHT, [5, 6, 2, 1]),
-    (Sides::FORWARD, [4, 5, 1, 0]),
-    (Sides::BACKWARD, [6, 7, 3, 2]),
+const SIDE_VERTICES: [(Sides, [u32; 4], [f32; 3]); 6] = [
+    (Sides::TOP, [7, 6, 5, 4], [0.0, 1.0, 0.0]),
+    (Sides::BOTTOM, [0, 1, 2, 3], [0.0, -1.0, 0.0]),
+    (Sides::LEFT, [7, 4, 0, 3], [-1.0, 0.0, 0.0]),
+    (Sides::RIGHT, [5, 6, 2, 1], [1.0, 0.0, 0.0]),
+    (Sides::FORWARD, [4, 5, 1, 0], [0.0, 0.0, 1.0]),
+    (Sides::BACKWARD, [6, 7, 3, 2], [0.0, 0.0, -1.0]),
 ];
 
 impl VoxelMeshBuilder {
     pub fn new() -> Self {
         Self {
             current_cube_pos: cgmath::Vector3::new(0, 0, 0),
+            current_block: Block::Stone,
             indices: Vec::new(),
             vertices: Vec::new(),
             index_offset: 0,
@@ -375,15 +460,22 @@ impl VoxelMeshBuilder {
         self
     }
 
+    /// Sets the block the voxels generated next are textured as.
+    pub fn set_block(&mut self, block: Block) -> &mut VoxelMeshBuilder {
+        self.current_block = block;
+        self
+    }
+
     pub fn move_position(&mut self, delta: cgmath::Vector3<u32>) -> &mut VoxelMeshBuilder {
         self.current_cube_pos += delta;
         self
     }
 
     pub fn generate_voxel(&mut self, sides: Sides) -> &mut VoxelMeshBuilder {
-        for (side, indices) in SIDE_VERTICES.iter() {
+        for (side, indices, normal) in SIDE_VERTICES.iter() {
             if sides.contains(*side) {
-                self.build_quad(&indices);
+                let layer = self.current_block.texture(*side).layer();
+                self.build_quad(&indices, *normal, layer);
             }
         }
         self
@@ -397,7 +489,7 @@ impl VoxelMeshBuilder {
         }
     }
 
-    fn build_quad(&mut self, vertex_idx: &[u32; 4]) {
+    fn build_quad(&mut self, vertex_idx: &[u32; 4], normal: [f32; 3], layer: u32) {
         for (vertex, uv) in vertex_idx.iter().zip(QUAD_UV_ORDER.iter()) {
             let mut v = CUBE_COORDINATES[*vertex as usize].clone();
             v[0] += self.current_cube_pos.x as f32;
@@ -405,21 +497,57 @@ impl VoxelMeshBuilder {
             v[2] += self.current_cube_pos.z as f32;
             self.vertices.push(MeshVertex {
                 position: v,
-                tex_coords: UVS[*uv as usize].clone(),
-                normal: [0.0, 1.0, 0.0],
+                tex_coords: UVS[*uv as usize],
+                normal,
+                tangent: [0.0; 4],
+                layer,
             });
         }
-        self.indices.extend(
-            [
-                3 + self.index_offset,
-                1 + self.index_offset,
-                0 + self.index_offset,
-                3 + self.index_offset,
-                2 + self.index_offset,
-                1 + self.index_offset,
-            ]
-            .iter(),
-        );
+        let quad = self.vertices.len() - 4;
+        compute_tangents(&mut self.vertices[quad..], &QUAD_INDICES);
+        let index_offset = self.index_offset;
+        self.indices
+            .extend(QUAD_INDICES.iter().map(|index| index + index_offset));
         self.index_offset += 4;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_layered_by_depth() {
+        assert_eq!(Block::at_depth(0), Block::Grass);
+        assert_eq!(Block::at_depth(1), Block::Dirt);
+        assert_eq!(Block::at_depth(DIRT_DEPTH), Block::Dirt);
+        assert_eq!(Block::at_depth(DIRT_DEPTH + 1), Block::Stone);
+    }
+
+    #[test]
+    fn grass_shows_dirt_underneath() {
+        assert_eq!(Block::Grass.texture(Sides::TOP), BlockTexture::GrassTop);
+        assert_eq!(Block::Grass.texture(Sides::LEFT), BlockTexture::GrassSide);
+        assert_eq!(Block::Grass.texture(Sides::BOTTOM), BlockTexture::Dirt);
+        assert_eq!(Block::Stone.texture(Sides::TOP), BlockTexture::Stone);
+    }
+
+    #[test]
+    fn texture_layers_follow_their_order() {
+        for (layer, texture) in BlockTexture::ALL.iter().enumerate() {
+            assert_eq!(texture.layer(), layer as u32);
+        }
+    }
+
+    #[test]
+    fn terrain_faces_use_the_layer_of_their_block() {
+        let mesh = make_mesh(0, cgmath::Vector2::new(0, 0));
+        let layers = mesh
+            .vertex_data
+            .iter()
+            .map(|vertex| vertex.layer)
+            .collect::<HashSet<_>>();
+        assert!(layers.contains(&BlockTexture::GrassTop.layer()));
+        assert!(layers.contains(&BlockTexture::GrassSide.layer()));
+    }
+}
@@ -1,11 +1,15 @@
+use anyhow::*;
 use std::sync::Arc;
 
+/// Owns the device and, when created for a window, the surface and swap chain. Headless displays
+/// still carry a swap chain descriptor, which describes the size and format of their offscreen
+/// render targets.
 pub struct Display {
-    pub surface: wgpu::Surface,
+    pub surface: Option<wgpu::Surface>,
     pub device: Arc<wgpu::Device>,
     pub queue: Arc<wgpu::Queue>,
     pub swap_chain_descriptor: wgpu::SwapChainDescriptor,
-    pub swap_chain: wgpu::SwapChain,
+    pub swap_chain: Option<wgpu::SwapChain>,
     pub size: winit::dpi::PhysicalSize<u32>,
 }
 
@@ -24,37 +28,96 @@ impl Display {
             .await
             .unwrap();
 
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    features: wgpu::Features::empty(),
-                    limits: Default::default(),
-                },
-                None,
-            )
-            .await
-            .unwrap();
+        let (device, queue) = Self::request_device(&adapter).await.unwrap();
 
         let swap_chain_format = adapter.get_swap_chain_preferred_format(&surface);
 
         let swap_chain_descriptor = wgpu::SwapChainDescriptor {
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
             format: swap_chain_format.into(),
-            width: size.width,
-            height: size.height,
+            width: size.width.max(1),
+            height: size.height.max(1),
             present_mode: wgpu::PresentMode::Immediate,
         };
 
         let swap_chain = device.create_swap_chain(&surface, &swap_chain_descriptor);
 
         Self {
-            surface,
+            surface: Some(surface),
             device: Arc::new(device),
             queue: Arc::new(queue),
             swap_chain_descriptor,
-            swap_chain,
+            swap_chain: Some(swap_chain),
             size,
         }
     }
+
+    /// Creates a display without a window, for rendering into offscreen targets. Any adapter is
+    /// accepted, so this also works on software rasterizers such as lavapipe or llvmpipe.
+    #[cfg(test)]
+    pub async fn headless(width: u32, height: u32) -> Result<Self> {
+        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+            })
+            .await
+            .context("No graphics adapter available")?;
+        log::info!("Rendering headless on {:?}", adapter.get_info());
+
+        let (device, queue) = Self::request_device(&adapter).await?;
+        let swap_chain_descriptor = wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+            format: crate::offscreen::OffscreenTarget::HEADLESS_FORMAT,
+            width: width.max(1),
+            height: height.max(1),
+            present_mode: wgpu::PresentMode::Immediate,
+        };
+
+        Ok(Self {
+            surface: None,
+            device: Arc::new(device),
+            queue: Arc::new(queue),
+            swap_chain_descriptor,
+            swap_chain: None,
+            size: winit::dpi::PhysicalSize::new(width, height),
+        })
+    }
+
+    async fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue)> {
+        let device = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    // Terrain chunks are drawn with a single indirect call where this is supported.
+                    features: adapter.features() & wgpu::Features::MULTI_DRAW_INDIRECT,
+                    limits: Default::default(),
+                },
+                None,
+            )
+            .await?;
+        Ok(device)
+    }
+
+    /// Recreates the swap chain for a new window size. Minimized windows report a size of zero,
+    /// which a swap chain cannot have, so the old one is kept until the window is restored.
+    pub fn resize(&mut self, size: winit::dpi::PhysicalSize<u32>) {
+        self.size = size;
+        if self.is_minimized() {
+            return;
+        }
+        self.swap_chain_descriptor.width = size.width;
+        self.swap_chain_descriptor.height = size.height;
+        if let Some(surface) = &self.surface {
+            self.swap_chain = Some(
+                self.device
+                    .create_swap_chain(surface, &self.swap_chain_descriptor),
+            );
+        }
+    }
+
+    pub fn is_minimized(&self) -> bool {
+        self.size.width == 0 || self.size.height == 0
+    }
 }
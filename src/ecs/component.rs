@@ -33,3 +33,69 @@ impl Ord for MeshId {
         (self.0 * self.0 + self.1 * self.1).cmp(&(other.0 * other.0 + other.1 * other.1))
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    Point,
+    /// A cone with full intensity inside `inner` and falling off to nothing at `outer`.
+    Spot {
+        inner: Rad<f32>,
+        outer: Rad<f32>,
+    },
+    Directional,
+}
+
+/// Intensity is divided by `constant + linear * d + quadratic * d * d` at distance `d`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32,
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Self {
+            constant: 1.0,
+            linear: 0.09,
+            quadratic: 0.032,
+        }
+    }
+}
+
+/// A light placed by the entity's `Transform`. Spot and directional lights shine along the
+/// transform's negative Z axis, and directional lights ignore attenuation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub attenuation: Attenuation,
+}
+
+/// Marks the directional light that follows the sun or the moon through the day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CelestialBody {
+    Sun,
+    Moon,
+}
+
+/// The sky at the current time of day, kept on a single entity for the renderer to draw.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sky {
+    /// Direction towards the sun.
+    pub sun_direction: cgmath::Vector3<f32>,
+    pub moon_direction: cgmath::Vector3<f32>,
+    pub zenith_color: [f32; 3],
+    pub horizon_color: [f32; 3],
+    pub sun_color: [f32; 3],
+    /// Tints the ambient light.
+    pub ambient: [f32; 3],
+    pub star_visibility: f32,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        crate::ecs::resource::TimeOfDay::default().sky()
+    }
+}
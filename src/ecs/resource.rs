@@ -0,0 +1,121 @@
+use std::{f32::consts::PI, time::Duration};
+
+use cgmath::InnerSpace;
+
+use super::component::Sky;
+
+/// Tilt of the sun's path away from the zenith, so it never stands straight overhead.
+const SUN_PATH_TILT: f32 = 0.5;
+
+const DAY_ZENITH: [f32; 3] = [0.25, 0.45, 0.85];
+const DAY_HORIZON: [f32; 3] = [0.7, 0.8, 0.95];
+const DUSK_ZENITH: [f32; 3] = [0.3, 0.3, 0.55];
+const DUSK_HORIZON: [f32; 3] = [0.95, 0.55, 0.3];
+const NIGHT_ZENITH: [f32; 3] = [0.01, 0.01, 0.04];
+const NIGHT_HORIZON: [f32; 3] = [0.04, 0.05, 0.1];
+
+const NOON_SUN: [f32; 3] = [1.0, 0.95, 0.85];
+const LOW_SUN: [f32; 3] = [1.0, 0.5, 0.25];
+const MOON: [f32; 3] = [0.6, 0.7, 1.0];
+const DAY_AMBIENT: [f32; 3] = [1.0, 1.0, 1.0];
+const NIGHT_AMBIENT: [f32; 3] = [0.15, 0.17, 0.3];
+
+pub const SUN_INTENSITY: f32 = 0.9;
+pub const MOON_INTENSITY: f32 = 0.15;
+
+/// The game clock's time of day, which moves the sun and moon and colours the sky.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeOfDay {
+    /// Hours since midnight, from 0 up to 24.
+    pub hours: f32,
+    /// Real seconds a full day takes.
+    pub day_length: f32,
+    pub paused: bool,
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        Self {
+            hours: 10.0,
+            day_length: 600.0,
+            paused: false,
+        }
+    }
+}
+
+impl TimeOfDay {
+    pub fn advance(&mut self, dt: Duration) {
+        if self.paused || self.day_length <= 0.0 {
+            return;
+        }
+        self.hours = (self.hours + dt.as_secs_f32() * 24.0 / self.day_length).rem_euclid(24.0);
+    }
+
+    /// Direction towards the sun. It rises in the east (+X) at 6:00 and sets at 18:00.
+    pub fn sun_direction(&self) -> cgmath::Vector3<f32> {
+        let angle = (self.hours / 24.0) * 2.0 * PI - PI / 2.0;
+        cgmath::Vector3::new(
+            angle.cos(),
+            angle.sin() * SUN_PATH_TILT.cos(),
+            angle.sin() * SUN_PATH_TILT.sin(),
+        )
+        .normalize()
+    }
+
+    /// Direction towards the moon, which always stands opposite the sun.
+    pub fn moon_direction(&self) -> cgmath::Vector3<f32> {
+        -self.sun_direction()
+    }
+
+    /// Colour and intensity of the sunlight, reddening towards the horizon and fading out
+    /// below it.
+    pub fn sun_light(&self) -> ([f32; 3], f32) {
+        let height = self.sun_direction().y;
+        let color = lerp(LOW_SUN, NOON_SUN, smoothstep(0.0, 0.4, height));
+        (color, SUN_INTENSITY * smoothstep(-0.05, 0.1, height))
+    }
+
+    pub fn moon_light(&self) -> ([f32; 3], f32) {
+        let height = self.moon_direction().y;
+        (MOON, MOON_INTENSITY * smoothstep(-0.05, 0.1, height))
+    }
+
+    pub fn sky(&self) -> Sky {
+        let sun_direction = self.sun_direction();
+        let height = sun_direction.y;
+        let daylight = smoothstep(-0.1, 0.3, height);
+        let dusk = 1.0 - smoothstep(0.0, 0.3, height.abs());
+        let zenith = lerp(
+            lerp(NIGHT_ZENITH, DAY_ZENITH, daylight),
+            DUSK_ZENITH,
+            dusk * 0.5,
+        );
+        let horizon = lerp(
+            lerp(NIGHT_HORIZON, DAY_HORIZON, daylight),
+            DUSK_HORIZON,
+            dusk,
+        );
+        Sky {
+            sun_direction,
+            moon_direction: self.moon_direction(),
+            zenith_color: zenith,
+            horizon_color: horizon,
+            sun_color: self.sun_light().0,
+            ambient: lerp(NIGHT_AMBIENT, DAY_AMBIENT, daylight),
+            star_visibility: 1.0 - smoothstep(-0.2, 0.05, height),
+        }
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [
+        a[0] + (b[0] - a[0]) * t,
+        a[1] + (b[1] - a[1]) * t,
+        a[2] + (b[2] - a[2]) * t,
+    ]
+}
@@ -1,5 +1,8 @@
-use super::component::{Momentum, Transform};
-use cgmath::InnerSpace;
+use super::{
+    component::{CelestialBody, Light, Momentum, Sky, Transform},
+    resource::TimeOfDay,
+};
+use cgmath::{InnerSpace, Rotation};
 use legion::*;
 
 #[system(for_each)]
@@ -8,3 +11,27 @@ pub fn update_positions(transform: &mut Transform, momentum: &mut Momentum) {
     transform.rotation.y += momentum.rotation.y;
     transform.rotation.z += momentum.rotation.z;
 }
+
+#[system(for_each)]
+pub fn update_celestial_bodies(
+    body: &CelestialBody,
+    transform: &mut Transform,
+    light: &mut Light,
+    #[resource] time: &TimeOfDay,
+) {
+    let (towards, (color, intensity)) = match body {
+        CelestialBody::Sun => (time.sun_direction(), time.sun_light()),
+        CelestialBody::Moon => (time.moon_direction(), time.moon_light()),
+    };
+    // Lights shine along their negative Z axis, so it has to point away from the body.
+    let rotation =
+        cgmath::Quaternion::between_vectors(-cgmath::Vector3::unit_z(), -towards.normalize());
+    transform.rotation = rotation.into();
+    light.color = color;
+    light.intensity = intensity;
+}
+
+#[system(for_each)]
+pub fn update_sky(sky: &mut Sky, #[resource] time: &TimeOfDay) {
+    *sky = time.sky();
+}
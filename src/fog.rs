@@ -0,0 +1,98 @@
+use crate::ecs::component::Sky;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FogMode {
+    Off,
+    /// Fades in between the start and end distances.
+    Linear,
+    /// Thickens exponentially with distance and is complete by the end distance.
+    Exponential,
+    /// Exponential fog that thins out with height above `base_height`.
+    Height,
+}
+
+impl FogMode {
+    pub const ALL: [FogMode; 4] = [
+        FogMode::Off,
+        FogMode::Linear,
+        FogMode::Exponential,
+        FogMode::Height,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FogMode::Off => "Off",
+            FogMode::Linear => "Linear",
+            FogMode::Exponential => "Exponential",
+            FogMode::Height => "Height",
+        }
+    }
+}
+
+/// Distance fog hiding the edge of the loaded terrain. The fog takes the sky's horizon colour,
+/// so distant terrain fades into the sky behind it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FogConfig {
+    pub mode: FogMode,
+    /// Where linear fog starts, as a fraction of the terrain load distance.
+    pub start: f32,
+    /// Where the fog is complete, as a fraction of the terrain load distance.
+    pub end: f32,
+    /// Height above which height fog thins out.
+    pub base_height: f32,
+    /// How quickly height fog thins out above `base_height`.
+    pub height_falloff: f32,
+    /// Strength of the sunlight scattered towards the camera when looking at the sun.
+    pub scattering: f32,
+}
+
+impl Default for FogConfig {
+    fn default() -> Self {
+        Self {
+            mode: FogMode::Linear,
+            start: 0.6,
+            end: 1.0,
+            base_height: 32.0,
+            height_falloff: 0.05,
+            scattering: 0.5,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FogRaw {
+    /// `w` holds the fog mode.
+    color: [f32; 4],
+    /// Start and end distance, base height and height falloff.
+    params: [f32; 4],
+    /// Direction towards the sun, `w` holds the scattering strength.
+    sun_direction: [f32; 4],
+    sun_color: [f32; 4],
+}
+
+impl FogRaw {
+    /// Fog for the current sky, with distances scaled to `load_distance`.
+    pub fn new(config: &FogConfig, sky: &Sky, load_distance: f32) -> Self {
+        let [r, g, b] = sky.horizon_color;
+        let [sun_r, sun_g, sun_b] = sky.sun_color;
+        let start = config.start.min(config.end) * load_distance;
+        let end = config.end * load_distance;
+        Self {
+            color: [r, g, b, config.mode as u32 as f32],
+            params: [start, end, config.base_height, config.height_falloff],
+            sun_direction: sky.sun_direction.extend(config.scattering).into(),
+            sun_color: [sun_r, sun_g, sun_b, 0.0],
+        }
+    }
+}
+
+impl Default for FogRaw {
+    fn default() -> Self {
+        Self::new(
+            &FogConfig::default(),
+            &Sky::default(),
+            crate::chunk::load_distance(),
+        )
+    }
+}
@@ -3,9 +3,10 @@ use std::{sync::Arc, time::Duration};
 use legion::*;
 
 use crate::{
+    asset::{AssetRegistry, ModelAsset},
     camera::{Camera, CameraController},
     chunk::ChunkManager,
-    ecs::system::*,
+    ecs::{component::*, resource::*, system::*},
     event::Event,
 };
 
@@ -16,10 +17,11 @@ pub struct Game {
     camera_controller: CameraController,
     chunk_manager: ChunkManager,
     player: legion::Entity,
+    pub time_of_day: TimeOfDay,
 }
 
 impl Game {
-    pub fn new(device: Arc<wgpu::Device>) -> Self {
+    pub fn new(device: Arc<wgpu::Device>, registry: &AssetRegistry) -> Self {
         let mut world = World::default();
         let mut chunk_manager = ChunkManager::new(device);
         chunk_manager.load_region(cgmath::Vector2::new(0, 0));
@@ -28,9 +30,81 @@ impl Game {
             cgmath::Deg(-180.0),
             cgmath::Deg(-20.0),
         ),));
+        // The sun and moon are placed by the day cycle on the first update.
+        for body in [CelestialBody::Sun, CelestialBody::Moon].iter() {
+            world.push((
+                *body,
+                Transform {
+                    position: cgmath::Vector3::new(0.0, 0.0, 0.0),
+                    rotation: cgmath::Euler::new(
+                        cgmath::Rad(0.0),
+                        cgmath::Rad(0.0),
+                        cgmath::Rad(0.0),
+                    ),
+                },
+                Light {
+                    kind: LightKind::Directional,
+                    color: [1.0, 1.0, 1.0],
+                    intensity: 0.0,
+                    attenuation: Attenuation::default(),
+                },
+            ));
+        }
+        world.push((Sky::default(),));
+        world.push((
+            Transform {
+                position: cgmath::Vector3::new(8.0, 38.0, 8.0),
+                rotation: cgmath::Euler::new(cgmath::Rad(0.0), cgmath::Rad(0.0), cgmath::Rad(0.0)),
+            },
+            Light {
+                kind: LightKind::Point,
+                color: [1.0, 0.6, 0.3],
+                intensity: 2.0,
+                attenuation: Attenuation::default(),
+            },
+        ));
+        // Pointing straight down onto the terrain.
+        world.push((
+            Transform {
+                position: cgmath::Vector3::new(-8.0, 44.0, -8.0),
+                rotation: cgmath::Euler::new(
+                    cgmath::Deg(-90.0).into(),
+                    cgmath::Rad(0.0),
+                    cgmath::Rad(0.0),
+                ),
+            },
+            Light {
+                kind: LightKind::Spot {
+                    inner: cgmath::Deg(20.0).into(),
+                    outer: cgmath::Deg(30.0).into(),
+                },
+                color: [0.6, 0.8, 1.0],
+                intensity: 3.0,
+                attenuation: Attenuation::default(),
+            },
+        ));
+        for prop in registry.props() {
+            let handle = registry.handle(&prop.asset).unwrap();
+            let (x, y, z) = prop.rotation;
+            world.push((
+                Transform {
+                    position: prop.position.into(),
+                    rotation: cgmath::Euler::new(
+                        cgmath::Deg(x).into(),
+                        cgmath::Deg(y).into(),
+                        cgmath::Deg(z).into(),
+                    ),
+                },
+                ModelReference {
+                    asset_reference: ModelAsset::Manifest(handle),
+                },
+            ));
+        }
 
         let schedule = Schedule::builder()
             .add_system(update_positions_system())
+            .add_system(update_celestial_bodies_system())
+            .add_system(update_sky_system())
             .build();
         let resources = Resources::default();
         Self {
@@ -40,6 +114,7 @@ impl Game {
             camera_controller: CameraController::new(20.0, 0.4),
             resources,
             chunk_manager,
+            time_of_day: TimeOfDay::default(),
         }
     }
 
@@ -58,6 +133,8 @@ impl Game {
         self.camera_controller.update(&mut camera, dt);
         let position = cgmath::Vector2::new(camera.position.x, camera.position.z);
         self.chunk_manager.update(&mut self.world, position);
+        self.time_of_day.advance(dt);
+        self.resources.insert(self.time_of_day);
         self.schedule.execute(&mut self.world, &mut self.resources);
     }
 }
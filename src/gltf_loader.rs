@@ -0,0 +1,514 @@
+use crate::{
+    material::{
+        Material, MaterialCache, MaterialDesc, MaterialFactors, MaterialImages, MaterialModel,
+    },
+    mesh::{compute_tangents, flat_normals, Mesh, MeshVertex, Model},
+    pipeline::PipelineBindGroupInfo,
+    texture::{SamplerConfig, TextureFilter},
+};
+use anyhow::*;
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, SquareMatrix, Vector3, Vector4};
+use std::{fmt::Debug, path::Path, sync::Arc};
+
+impl Model {
+    /// Loads a glTF 2.0 file, either `.gltf` with its buffers and images next to it or inside
+    /// data URIs, or binary `.glb`. Node transforms are baked into the vertices, so every mesh
+    /// primitive of the default scene becomes one mesh of the model. Materials use the PBR
+    /// model.
+    pub fn load_gltf<F: AsRef<Path> + Debug>(
+        name: String,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bind_group_info: Option<Arc<PipelineBindGroupInfo>>,
+        file_path: F,
+        material_cache: &MaterialCache,
+    ) -> Result<Self> {
+        let path = file_path.as_ref();
+        let (document, buffers, images) =
+            gltf::import(path).with_context(|| format!("Unable to load glTF file {:?}", path))?;
+
+        let primitives = load_primitives(path, &document, &buffers)?;
+
+        let mut materials = Vec::new();
+        for material in document.materials() {
+            let material_name = material
+                .name()
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("material {}", materials.len()));
+            let material = load_material(
+                device,
+                queue,
+                bind_group_info.clone(),
+                path,
+                &material,
+                &images,
+                material_cache,
+            )
+            .with_context(|| {
+                format!("Failed to load material {:?} of {:?}", material_name, path)
+            })?;
+            materials.push(material);
+        }
+        // Primitives without a material use the default one of the specification, which is
+        // added after the materials of the file.
+        let default_material = materials.len();
+        if let Some(primitive) = primitives
+            .iter()
+            .find(|primitive| primitive.material.index().is_none())
+        {
+            let material = load_material(
+                device,
+                queue,
+                bind_group_info,
+                path,
+                &primitive.material,
+                &images,
+                material_cache,
+            )
+            .with_context(|| format!("Failed to load the default material of {:?}", path))?;
+            materials.push(material);
+        }
+
+        let meshes = primitives
+            .into_iter()
+            .map(|primitive| {
+                Mesh::new(
+                    device,
+                    primitive.name,
+                    &primitive.vertices,
+                    &primitive.indices,
+                    primitive.material.index().unwrap_or(default_material),
+                )
+            })
+            .collect();
+        Ok(Self::new(name, meshes, materials))
+    }
+}
+
+/// A triangle primitive of the default scene, with the transform of its node baked in.
+struct Primitive<'a> {
+    name: String,
+    vertices: Vec<MeshVertex>,
+    indices: Vec<u32>,
+    /// The default material of the specification when the primitive has none.
+    material: gltf::Material<'a>,
+}
+
+/// Reads every triangle primitive of the default scene, or the first scene when the file has
+/// no default. Primitives of other modes are skipped.
+fn load_primitives<'a>(
+    path: &Path,
+    document: &'a gltf::Document,
+    buffers: &[gltf::buffer::Data],
+) -> Result<Vec<Primitive<'a>>> {
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .with_context(|| format!("{:?} has no scene", path))?;
+    let mut nodes = Vec::new();
+    for node in scene.nodes() {
+        collect_nodes(node, Matrix4::identity(), &mut nodes);
+    }
+
+    let mut primitives = Vec::new();
+    for (node, transform) in nodes {
+        let mesh = match node.mesh() {
+            Some(mesh) => mesh,
+            None => continue,
+        };
+        let node_name = node
+            .name()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("node {}", node.index()));
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                log::warn!(
+                    "Skipping {:?} primitive of node {:?} in {:?}",
+                    primitive.mode(),
+                    node_name,
+                    path
+                );
+                continue;
+            }
+            let (vertices, indices) =
+                load_primitive(&primitive, buffers, transform).with_context(|| {
+                    format!(
+                        "Failed to load primitive {} of node {:?} in {:?}",
+                        primitive.index(),
+                        node_name,
+                        path
+                    )
+                })?;
+            primitives.push(Primitive {
+                name: format!("{}/{}", node_name, primitive.index()),
+                vertices,
+                indices,
+                material: primitive.material(),
+            });
+        }
+    }
+    Ok(primitives)
+}
+
+/// Flattens the node hierarchy into every node along with its world transform.
+fn collect_nodes<'a>(
+    node: gltf::Node<'a>,
+    parent: Matrix4<f32>,
+    nodes: &mut Vec<(gltf::Node<'a>, Matrix4<f32>)>,
+) {
+    let transform = parent * Matrix4::from(node.transform().matrix());
+    for child in node.children() {
+        collect_nodes(child, transform, nodes);
+    }
+    nodes.push((node, transform));
+}
+
+fn load_material(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    bind_group_info: Option<Arc<PipelineBindGroupInfo>>,
+    path: &Path,
+    material: &gltf::Material,
+    images: &[gltf::image::Data],
+    material_cache: &MaterialCache,
+) -> Result<Arc<Material>> {
+    let desc = material_desc(path, material);
+    let pbr = material.pbr_metallic_roughness();
+    let image = |texture: Option<gltf::texture::Texture>| {
+        texture
+            .map(|texture| decode_image(&images[texture.source().index()]))
+            .transpose()
+    };
+    material_cache.get_or_load(&desc, || {
+        let images = MaterialImages {
+            diffuse: image(pbr.base_color_texture().map(|info| info.texture()))?
+                .into_iter()
+                .collect(),
+            normal: image(material.normal_texture().map(|normal| normal.texture()))?,
+            specular: image(pbr.metallic_roughness_texture().map(|info| info.texture()))?,
+            emissive: image(material.emissive_texture().map(|info| info.texture()))?,
+            occlusion: image(
+                material
+                    .occlusion_texture()
+                    .map(|occlusion| occlusion.texture()),
+            )?,
+        };
+        Material::from_images(
+            device,
+            queue,
+            material.name().unwrap_or("glTF material").to_string(),
+            bind_group_info,
+            &desc,
+            &images,
+        )
+    })
+}
+
+fn material_desc(path: &Path, material: &gltf::Material) -> MaterialDesc {
+    let pbr = material.pbr_metallic_roughness();
+    let factors = MaterialFactors {
+        base_color: pbr.base_color_factor(),
+        emissive: material.emissive_factor(),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        occlusion_strength: material
+            .occlusion_texture()
+            .map_or(1.0, |occlusion| occlusion.strength()),
+    };
+    MaterialDesc {
+        model: MaterialModel::Pbr,
+        factors,
+        embedded: Some((path.to_path_buf(), material.index())),
+        sampler: pbr
+            .base_color_texture()
+            .map_or_else(SamplerConfig::default, |info| {
+                sampler_config(&info.texture().sampler())
+            }),
+        ..Default::default()
+    }
+}
+
+/// Every map of a material shares one sampler, which follows the base colour texture.
+/// Filters the file leaves out keep their defaults.
+fn sampler_config(sampler: &gltf::texture::Sampler) -> SamplerConfig {
+    use gltf::texture::{MagFilter, MinFilter};
+    let mut config = SamplerConfig::default();
+    if let Some(MagFilter::Nearest) = sampler.mag_filter() {
+        config.mag_filter = TextureFilter::Nearest;
+    }
+    if let Some(min_filter) = sampler.min_filter() {
+        let (min, mipmap) = match min_filter {
+            MinFilter::Nearest | MinFilter::NearestMipmapNearest => {
+                (TextureFilter::Nearest, TextureFilter::Nearest)
+            }
+            MinFilter::Linear | MinFilter::LinearMipmapNearest => {
+                (TextureFilter::Linear, TextureFilter::Nearest)
+            }
+            MinFilter::NearestMipmapLinear => (TextureFilter::Nearest, TextureFilter::Linear),
+            MinFilter::LinearMipmapLinear => (TextureFilter::Linear, TextureFilter::Linear),
+        };
+        config.min_filter = min;
+        config.mipmap_filter = mipmap;
+    }
+    config
+}
+
+/// Expands the 8 bit images gltf decodes to RGBA. Single channel images are spread over red,
+/// green and blue, and two channel ones keep their channels in red and green.
+fn decode_image(data: &gltf::image::Data) -> Result<image::DynamicImage> {
+    use gltf::image::Format;
+    let channels = match data.format {
+        Format::R8 => 1,
+        Format::R8G8 => 2,
+        Format::R8G8B8 => 3,
+        Format::R8G8B8A8 => 4,
+        format => bail!("Unsupported image format {:?}", format),
+    };
+    let mut rgba = Vec::with_capacity(data.pixels.len() / channels * 4);
+    for texel in data.pixels.chunks(channels) {
+        rgba.extend_from_slice(&match *texel {
+            [r] => [r, r, r, 255],
+            [r, g] => [r, g, 0, 255],
+            [r, g, b] => [r, g, b, 255],
+            [r, g, b, a] => [r, g, b, a],
+            _ => unreachable!(),
+        });
+    }
+    let image = image::RgbaImage::from_raw(data.width, data.height, rgba)
+        .context("Image data is smaller than its size")?;
+    Ok(image::DynamicImage::ImageRgba8(image))
+}
+
+fn load_primitive(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+    transform: Matrix4<f32>,
+) -> Result<(Vec<MeshVertex>, Vec<u32>)> {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+    let positions = reader
+        .read_positions()
+        .context("Primitive has no positions")?
+        .collect::<Vec<_>>();
+    let normals = reader
+        .read_normals()
+        .map(|normals| normals.collect::<Vec<_>>());
+    let tex_coords = reader
+        .read_tex_coords(0)
+        .map(|tex_coords| tex_coords.into_f32().collect::<Vec<_>>());
+    let tangents = reader
+        .read_tangents()
+        .map(|tangents| tangents.collect::<Vec<_>>());
+    let mut indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+        None => (0..positions.len() as u32).collect(),
+    };
+    let check_count = |attribute: &str, count: Option<usize>| match count {
+        Some(count) if count != positions.len() => bail!(
+            "Primitive has {} {} for {} positions",
+            count,
+            attribute,
+            positions.len()
+        ),
+        _ => Ok(()),
+    };
+    check_count("normals", normals.as_ref().map(Vec::len))?;
+    check_count("texture coordinates", tex_coords.as_ref().map(Vec::len))?;
+    check_count("tangents", tangents.as_ref().map(Vec::len))?;
+    if let Some(&index) = indices
+        .iter()
+        .find(|&&index| index as usize >= positions.len())
+    {
+        bail!(
+            "Index {} is out of range of {} vertices",
+            index,
+            positions.len()
+        );
+    }
+
+    let linear = Matrix3::from_cols(
+        transform.x.truncate(),
+        transform.y.truncate(),
+        transform.z.truncate(),
+    );
+    let normal_matrix = linear.invert().unwrap_or(linear).transpose();
+    // Mirroring transforms turn the triangles inside out and flip the bitangents.
+    let mirrored = linear.determinant() < 0.0;
+    if mirrored {
+        for triangle in indices.chunks_mut(3) {
+            triangle.reverse();
+        }
+    }
+
+    let mut vertices = positions
+        .iter()
+        .enumerate()
+        .map(|(i, &position)| {
+            let position = transform * Vector4::new(position[0], position[1], position[2], 1.0);
+            let normal = normals.as_ref().map_or([0.0; 3], |normals| {
+                (normal_matrix * Vector3::from(normals[i]))
+                    .normalize()
+                    .into()
+            });
+            let tangent = tangents.as_ref().map_or([0.0; 4], |tangents| {
+                let [x, y, z, w] = tangents[i];
+                let tangent = (linear * Vector3::new(x, y, z)).normalize();
+                [
+                    tangent.x,
+                    tangent.y,
+                    tangent.z,
+                    if mirrored { -w } else { w },
+                ]
+            });
+            MeshVertex {
+                position: position.truncate().into(),
+                tex_coords: tex_coords
+                    .as_ref()
+                    .map_or([0.0; 2], |tex_coords| tex_coords[i]),
+                normal,
+                tangent,
+                layer: 0,
+            }
+        })
+        .collect::<Vec<_>>();
+    // The specification asks for flat normals when a primitive has none, and tangents cannot
+    // be kept without normals either.
+    if normals.is_none() {
+        let (flat, flat_indices) = flat_normals(&vertices, &indices);
+        vertices = flat;
+        indices = flat_indices;
+    }
+    if normals.is_none() || tangents.is_none() {
+        compute_tangents(&mut vertices, &indices);
+    }
+    Ok((vertices, indices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// One triangle in the XY plane, drawn by a child node that is scaled and moved by its
+    /// parent, and by a mirrored node with a material. The buffer holds three positions and
+    /// three `u16` indices.
+    const TRIANGLES: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0, 2] }],
+        "nodes": [
+            { "name": "parent", "translation": [10, 0, 0], "children": [1] },
+            { "name": "child", "mesh": 0, "scale": [2, 2, 2] },
+            { "name": "mirrored", "mesh": 1, "scale": [-1, 1, 1] }
+        ],
+        "meshes": [
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "indices": 1 }] },
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "indices": 1, "material": 0 }] }
+        ],
+        "materials": [
+            { "name": "red", "pbrMetallicRoughness": { "baseColorFactor": [1, 0, 0, 1] } }
+        ],
+        "accessors": [
+            {
+                "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                "min": [0, 0, 0], "max": [1, 1, 0]
+            },
+            { "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }
+        ],
+        "bufferViews": [
+            { "buffer": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 6 }
+        ],
+        "buffers": [{
+            "byteLength": 44,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAABAAIAAAA="
+        }]
+    }"#;
+
+    /// `gltf::import_slice` cannot read data URIs, so the fixture goes through a file named
+    /// after the test.
+    fn import(test: &str) -> (gltf::Document, Vec<gltf::buffer::Data>) {
+        let path =
+            std::env::temp_dir().join(format!("render-lock-{}-{}.gltf", test, std::process::id()));
+        std::fs::write(&path, TRIANGLES).unwrap();
+        let (document, buffers, _) = gltf::import(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (document, buffers)
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(&expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    /// Positions of the triangles in index order.
+    fn corners(primitive: &Primitive) -> Vec<[f32; 3]> {
+        primitive
+            .indices
+            .iter()
+            .map(|&index| primitive.vertices[index as usize].position)
+            .collect()
+    }
+
+    #[test]
+    fn node_transforms_are_baked_into_vertices() {
+        let (document, buffers) = import("node_transforms");
+        let primitives = load_primitives(Path::new("triangles.gltf"), &document, &buffers).unwrap();
+
+        assert_eq!(primitives.len(), 2);
+        assert_eq!(primitives[0].name, "child/0");
+        let corners = corners(&primitives[0]);
+        assert_close(corners[0], [10.0, 0.0, 0.0]);
+        assert_close(corners[1], [12.0, 0.0, 0.0]);
+        assert_close(corners[2], [10.0, 2.0, 0.0]);
+        for vertex in &primitives[0].vertices {
+            assert_close(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn mirrored_nodes_keep_their_triangles_facing_out() {
+        let (document, buffers) = import("mirrored");
+        let primitives = load_primitives(Path::new("triangles.gltf"), &document, &buffers).unwrap();
+
+        assert_eq!(primitives[1].name, "mirrored/0");
+        let corners = corners(&primitives[1]);
+        assert_close(corners[0], [0.0, 1.0, 0.0]);
+        assert_close(corners[1], [-1.0, 0.0, 0.0]);
+        assert_close(corners[2], [0.0, 0.0, 0.0]);
+        // Still counter-clockwise when seen from the front.
+        let edge = |i: usize| Vector3::from(corners[i]) - Vector3::from(corners[0]);
+        assert!(edge(1).cross(edge(2)).z > 0.0);
+        for vertex in &primitives[1].vertices {
+            assert_close(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn primitives_without_a_material_use_the_default_one() {
+        let (document, buffers) = import("default_material");
+        let path = Path::new("triangles.gltf");
+        let primitives = load_primitives(path, &document, &buffers).unwrap();
+
+        assert_eq!(primitives[0].material.index(), None);
+        assert_eq!(primitives[1].material.index(), Some(0));
+
+        let default = material_desc(path, &primitives[0].material);
+        assert_eq!(default.model, MaterialModel::Pbr);
+        assert_eq!(
+            default.embedded,
+            Some((PathBuf::from("triangles.gltf"), None))
+        );
+        assert_eq!(default.factors.base_color, [1.0; 4]);
+        assert_eq!(default.factors.metallic, 1.0);
+        assert_eq!(default.factors.roughness, 1.0);
+
+        let red = material_desc(path, &primitives[1].material);
+        assert_eq!(
+            red.embedded,
+            Some((PathBuf::from("triangles.gltf"), Some(0)))
+        );
+        assert_eq!(red.factors.base_color, [1.0, 0.0, 0.0, 1.0]);
+        assert_ne!(red, default);
+    }
+}
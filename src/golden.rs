@@ -0,0 +1,285 @@
+//! Golden image tests. Each test renders a deterministic world offscreen and compares the frame
+//! to a reference PNG in `tests/golden`, allowing for small perceptual differences between
+//! adapters. Failing tests write the rendered frame and a diff image to `target/golden`.
+//!
+//! The tests need a graphics adapter, so they are ignored by default and run with
+//! `cargo test -- --ignored`; a software adapter such as lavapipe is enough. Without an adapter,
+//! or without a stored reference, they fail. CI runs them on lavapipe in
+//! `.github/workflows/golden.yml`, which is also where references are made: after an intended
+//! visual change, run that workflow by hand with `update` set, which runs the tests with
+//! `GOLDEN_UPDATE=1`, and commit the `golden-references` artifact to `tests/golden`.
+
+use anyhow::{bail, ensure, Context, Result};
+use legion::World;
+use std::{path::PathBuf, sync::Arc, thread, time::Duration};
+
+use crate::{
+    asset::{AssetRegistry, ModelAsset},
+    camera::Camera,
+    chunk::{self, CHUNK_SIZE},
+    ecs::component::{Attenuation, Light, LightKind, ModelReference, Transform},
+    pipeline::SimplePipeline,
+    renderer::Renderer,
+    scene::SceneManager,
+};
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+/// Largest perceptual colour difference, from 0 to 1, at which two pixels still count as equal.
+const PIXEL_THRESHOLD: f32 = 0.1;
+/// Fraction of pixels that may differ, which absorbs rasterization differences between adapters.
+const MAX_DIFFERING_PIXELS: f64 = 0.005;
+/// Frames to wait for asset workers to upload the models a world refers to.
+const MAX_LOAD_FRAMES: usize = 500;
+
+fn reference_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn output_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/golden")
+}
+
+fn load_registry() -> Result<Arc<AssetRegistry>> {
+    let resources = std::path::Path::new(env!("OUT_DIR")).join("resources");
+    Ok(Arc::new(AssetRegistry::load(resources.join("assets.ron"))?))
+}
+
+/// Chunks within `radius` of the origin, generated synchronously from the fixed terrain seed,
+/// lit by a fixed sun.
+fn terrain_world(radius: i32) -> World {
+    let mut world = World::default();
+    world.push((
+        Transform {
+            position: cgmath::Vector3::new(0.0, 0.0, 0.0),
+            rotation: cgmath::Euler::new(
+                cgmath::Deg(-50.0).into(),
+                cgmath::Deg(30.0).into(),
+                cgmath::Rad(0.0),
+            ),
+        },
+        Light {
+            kind: LightKind::Directional,
+            color: [1.0, 1.0, 1.0],
+            intensity: 0.9,
+            attenuation: Attenuation::default(),
+        },
+    ));
+    for x in -radius..=radius {
+        for z in -radius..=radius {
+            world.push((
+                Transform {
+                    position: cgmath::Vector3::new(
+                        (x * CHUNK_SIZE as i32) as f32,
+                        0.0,
+                        (z * CHUNK_SIZE as i32) as f32,
+                    ),
+                    rotation: cgmath::Euler::new(
+                        cgmath::Rad(0.0),
+                        cgmath::Rad(0.0),
+                        cgmath::Rad(0.0),
+                    ),
+                },
+                // The mesh id comes from the chunk location, so every chunk gets its own arena
+                // allocation whatever index is passed.
+                chunk::make_mesh(0, cgmath::Vector2::new(x, z)),
+            ));
+        }
+    }
+    world
+}
+
+fn push_props(world: &mut World, registry: &AssetRegistry) {
+    for prop in registry.props() {
+        let (x, y, z) = prop.rotation;
+        world.push((
+            Transform {
+                position: prop.position.into(),
+                rotation: cgmath::Euler::new(
+                    cgmath::Deg(x).into(),
+                    cgmath::Deg(y).into(),
+                    cgmath::Deg(z).into(),
+                ),
+            },
+            ModelReference {
+                asset_reference: ModelAsset::Manifest(registry.handle(&prop.asset).unwrap()),
+            },
+        ));
+    }
+}
+
+/// Renders the world built by `build_world` from `camera` and compares the frame with the
+/// reference called `name`.
+fn check_golden<F>(name: &str, camera: Camera, build_world: F)
+where
+    F: FnOnce(&AssetRegistry) -> World,
+{
+    let mut renderer: Renderer<SimplePipeline> =
+        Renderer::new_headless(WIDTH, HEIGHT).expect("Failed to create a headless renderer");
+    let registry = load_registry().expect("Failed to load asset manifest");
+    renderer
+        .load_environment(&registry)
+        .expect("Failed to load the environment cubemap");
+    let world = build_world(&registry);
+    let mut scene_manager = SceneManager::new(&renderer.display, &renderer.pipeline, registry)
+        .expect("Failed to create scene manager");
+
+    // Models are loaded on worker threads, so frames are only captured once nothing is pending.
+    for _ in 0..MAX_LOAD_FRAMES {
+        scene_manager.load_scene(&renderer.display, &world, &renderer.pipeline);
+        if scene_manager.stats().pending_assets == 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(
+        scene_manager.stats().pending_assets,
+        0,
+        "Assets of {} did not finish loading",
+        name
+    );
+
+    let scene = scene_manager.load_scene(&renderer.display, &world, &renderer.pipeline);
+    let frame = renderer
+        .capture(&scene, &camera)
+        .expect("Failed to capture frame");
+    if let Err(err) = compare(name, &frame) {
+        panic!("{:?}", err);
+    }
+}
+
+fn compare(name: &str, frame: &image::RgbaImage) -> Result<()> {
+    let reference_path = reference_dir().join(format!("{}.png", name));
+    if std::env::var_os("GOLDEN_UPDATE").is_some() {
+        std::fs::create_dir_all(reference_dir())?;
+        frame.save(&reference_path)?;
+        eprintln!("Wrote golden reference {:?}", reference_path);
+        return Ok(());
+    }
+    ensure!(
+        reference_path.exists(),
+        "{} has no reference at {:?}, run with GOLDEN_UPDATE=1 to write it",
+        name,
+        reference_path
+    );
+
+    let reference = image::open(&reference_path)
+        .with_context(|| format!("Failed to open reference {:?}", reference_path))?
+        .to_rgba8();
+    ensure!(
+        reference.dimensions() == frame.dimensions(),
+        "{} is {:?} but its reference is {:?}",
+        name,
+        frame.dimensions(),
+        reference.dimensions()
+    );
+
+    let (diff, differing) = diff_images(&reference, frame);
+    let ratio = differing as f64 / (frame.width() * frame.height()) as f64;
+    if ratio > MAX_DIFFERING_PIXELS {
+        let output = output_dir();
+        std::fs::create_dir_all(&output)?;
+        frame.save(output.join(format!("{}.actual.png", name)))?;
+        diff.save(output.join(format!("{}.diff.png", name)))?;
+        bail!(
+            "{} differs from its reference in {:.2}% of pixels, see {:?}",
+            name,
+            ratio * 100.0,
+            output
+        );
+    }
+    Ok(())
+}
+
+/// Colour difference in YIQ space, weighted towards luminance the way the eye is, and scaled so
+/// that the largest possible difference is 1.
+fn perceptual_distance(a: &image::Rgba<u8>, b: &image::Rgba<u8>) -> f32 {
+    let yiq = |pixel: &image::Rgba<u8>| {
+        let [r, g, b] = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+        [
+            r * 0.298_895_3 + g * 0.586_622_5 + b * 0.114_482_2,
+            r * 0.595_978 - g * 0.274_176_1 - b * 0.321_801_9,
+            r * 0.211_470_2 - g * 0.522_617_1 + b * 0.311_146_9,
+        ]
+    };
+    let (a, b) = (yiq(a), yiq(b));
+    let delta = 0.5053 * (a[0] - b[0]).powi(2)
+        + 0.299 * (a[1] - b[1]).powi(2)
+        + 0.1957 * (a[2] - b[2]).powi(2);
+    (delta / 35215.0).sqrt()
+}
+
+/// Marks differing pixels red on a faded copy of the reference and counts them.
+fn diff_images(
+    reference: &image::RgbaImage,
+    frame: &image::RgbaImage,
+) -> (image::RgbaImage, usize) {
+    let mut differing = 0;
+    let diff = image::RgbaImage::from_fn(frame.width(), frame.height(), |x, y| {
+        let (expected, actual) = (reference.get_pixel(x, y), frame.get_pixel(x, y));
+        if perceptual_distance(expected, actual) > PIXEL_THRESHOLD {
+            differing += 1;
+            image::Rgba([255, 0, 0, 255])
+        } else {
+            let luma = (expected[0] as u32 * 3 + expected[1] as u32 * 6 + expected[2] as u32) / 10;
+            let faded = (255 - (255 - luma) / 10) as u8;
+            image::Rgba([faded, faded, faded, 255])
+        }
+    });
+    (diff, differing)
+}
+
+#[test]
+fn missing_reference_fails() {
+    if std::env::var_os("GOLDEN_UPDATE").is_some() {
+        return;
+    }
+    let frame = image::RgbaImage::new(1, 1);
+    assert!(compare("missing_reference", &frame).is_err());
+    assert!(!reference_dir().join("missing_reference.png").exists());
+}
+
+#[test]
+fn terrain_world_chunks_have_distinct_ids() {
+    use crate::ecs::component::MeshReference;
+    use legion::IntoQuery;
+
+    let world = terrain_world(1);
+    let mut ids = <&MeshReference>::query()
+        .iter(&world)
+        .map(|mesh| mesh.idx)
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 9);
+}
+
+#[test]
+fn perceptual_distance_tolerates_small_changes() {
+    let grey = image::Rgba([128, 128, 128, 255]);
+    assert_eq!(perceptual_distance(&grey, &grey), 0.0);
+    assert!(perceptual_distance(&grey, &image::Rgba([131, 128, 126, 255])) < PIXEL_THRESHOLD);
+    assert!(perceptual_distance(&grey, &image::Rgba([200, 40, 40, 255])) > PIXEL_THRESHOLD);
+    let black = image::Rgba([0, 0, 0, 255]);
+    let white = image::Rgba([255, 255, 255, 255]);
+    let distance = perceptual_distance(&black, &white);
+    assert!(distance > 0.9 && distance <= 1.0);
+}
+
+#[test]
+#[ignore = "needs a graphics adapter"]
+fn terrain_matches_reference() {
+    let camera = Camera::new((0.0, 48.0, -40.0), cgmath::Deg(90.0), cgmath::Deg(-30.0));
+    check_golden("terrain", camera, |_| terrain_world(1));
+}
+
+#[test]
+#[ignore = "needs a graphics adapter"]
+fn props_match_reference() {
+    let camera = Camera::new((-2.0, 40.0, -2.0), cgmath::Deg(45.0), cgmath::Deg(-25.0));
+    check_golden("props", camera, |registry| {
+        let mut world = terrain_world(0);
+        push_props(&mut world, registry);
+        world
+    });
+}
@@ -1,11 +1,28 @@
-use crate::{display::*, timestep};
+use crate::{
+    display::*, ecs::resource::TimeOfDay, fog::FogMode, hdr::ToneMapping, ibl::EnvironmentSource,
+    msaa::SAMPLE_COUNTS, postprocess::PostProcessStack, render_list::RenderStats,
+    scene::AssetStats, settings::RenderSettings, shadow::MAX_CASCADES, ssao::MAX_SAMPLES, timestep,
+};
 use imgui_winit_support::WinitPlatform;
 use std::time::{Duration, Instant};
 
+const SHADOW_RESOLUTIONS: [u32; 4] = [512, 1024, 2048, 4096];
+
+/// Per-frame statistics and the settings the GUI panels display and edit.
+pub struct GuiState<'a> {
+    pub fps: u32,
+    pub asset_stats: &'a AssetStats,
+    pub render_stats: &'a RenderStats,
+    pub settings: &'a mut RenderSettings,
+    pub post_process: &'a mut PostProcessStack,
+    pub time_of_day: &'a mut TimeOfDay,
+}
+
 pub struct Gui {
     pub platform: imgui_winit_support::WinitPlatform,
     pub context: imgui::Context,
     pub renderer: imgui_wgpu::Renderer,
+    hidpi_factor: f64,
 }
 
 impl Gui {
@@ -19,21 +36,12 @@ impl Gui {
         );
 
         let hidpi_factor = window.scale_factor();
-        let font_size = (13.0 * hidpi_factor) as f32;
-        context.io_mut().font_global_scale = (1.0 / hidpi_factor) as f32;
-        context
-            .fonts()
-            .add_font(&[imgui::FontSource::DefaultFontData {
-                config: Some(imgui::FontConfig {
-                    oversample_h: 1,
-                    pixel_snap_h: true,
-                    size_pixels: font_size,
-                    ..Default::default()
-                }),
-            }]);
+        Self::load_fonts(&mut context, hidpi_factor);
 
         let renderer_config = imgui_wgpu::RendererConfig {
             texture_format: display.swap_chain_descriptor.format,
+            // The GUI is drawn over the frame after MSAA has been resolved.
+            sample_count: 1,
             ..Default::default()
         };
 
@@ -48,18 +56,65 @@ impl Gui {
             platform,
             context,
             renderer,
+            hidpi_factor,
+        }
+    }
+
+    pub fn handle_event<T>(
+        &mut self,
+        window: &winit::window::Window,
+        event: &winit::event::Event<T>,
+    ) {
+        self.platform
+            .handle_event(self.context.io_mut(), window, event);
+    }
+
+    /// Rebuilds the font atlas after the window moved to a display with a different scale
+    /// factor, so text stays sharp and keeps its logical size.
+    pub fn rescale(&mut self, window: &winit::window::Window, display: &Display) {
+        let hidpi_factor = window.scale_factor();
+        if hidpi_factor == self.hidpi_factor {
+            return;
         }
+        self.hidpi_factor = hidpi_factor;
+        self.context.fonts().clear();
+        Self::load_fonts(&mut self.context, hidpi_factor);
+        self.renderer
+            .reload_font_texture(&mut self.context, &display.device, &display.queue);
+    }
+
+    fn load_fonts(context: &mut imgui::Context, hidpi_factor: f64) {
+        let font_size = (13.0 * hidpi_factor) as f32;
+        context.io_mut().font_global_scale = (1.0 / hidpi_factor) as f32;
+        context
+            .fonts()
+            .add_font(&[imgui::FontSource::DefaultFontData {
+                config: Some(imgui::FontConfig {
+                    oversample_h: 1,
+                    pixel_snap_h: true,
+                    size_pixels: font_size,
+                    ..Default::default()
+                }),
+            }]);
     }
 
     pub fn render(
         &mut self,
         dt: Duration,
-        fps: u32,
+        state: GuiState,
         window: &winit::window::Window,
-        frame: &wgpu::SwapChainTexture,
+        target: &wgpu::TextureView,
         encoder: &mut wgpu::CommandEncoder,
         display: &Display,
     ) {
+        let GuiState {
+            fps,
+            asset_stats,
+            render_stats,
+            settings,
+            post_process,
+            time_of_day,
+        } = state;
         self.context.io_mut().update_delta_time(dt);
         self.platform
             .prepare_frame(self.context.io_mut(), window)
@@ -69,7 +124,7 @@ impl Gui {
         {
             let window = imgui::Window::new(imgui::im_str!("Hello Imgui from WGPU!"));
             window
-                .size([300.0, 100.0], imgui::Condition::FirstUseEver)
+                .size([300.0, 260.0], imgui::Condition::FirstUseEver)
                 .build(&ui, || {
                     ui.text(imgui::im_str!("Hello world!"));
                     ui.text(imgui::im_str!(
@@ -77,6 +132,252 @@ impl Gui {
                     ));
                     ui.separator();
                     ui.text(imgui::im_str!("FPS: ({:.1})", fps,));
+                    ui.text(imgui::im_str!(
+                        "Draw calls: {}, state changes: {}, instances: {}",
+                        render_stats.draw_calls,
+                        render_stats.state_changes(),
+                        render_stats.instances,
+                    ));
+                    ui.text(imgui::im_str!(
+                        "Culled: {} chunks, {} instances, lights: {}",
+                        render_stats.chunks_culled,
+                        render_stats.instances_culled,
+                        render_stats.lights,
+                    ));
+                    ui.text(imgui::im_str!(
+                        "Shadow draw calls: {}",
+                        render_stats.shadow_draw_calls,
+                    ));
+                    ui.separator();
+                    ui.text(imgui::im_str!(
+                        "Assets: {} resident ({} cached), {} pending, {} deferred, {} evicted",
+                        asset_stats.resident_assets,
+                        asset_stats.cached_assets,
+                        asset_stats.pending_assets,
+                        asset_stats.deferred_assets,
+                        asset_stats.evicted_assets,
+                    ));
+                    ui.text(imgui::im_str!(
+                        "Asset memory: {:.1} / {:.1} MiB",
+                        (asset_stats.resident_bytes + asset_stats.material_bytes) as f64
+                            / (1024.0 * 1024.0),
+                        asset_stats.memory_budget as f64 / (1024.0 * 1024.0),
+                    ));
+                    let mut budget = (settings.assets.memory_budget / (1024 * 1024)) as u32;
+                    if imgui::Slider::new(imgui::im_str!("Memory budget"))
+                        .range(64..=4096)
+                        .display_format(imgui::im_str!("%d MiB"))
+                        .build(&ui, &mut budget)
+                    {
+                        settings.assets.memory_budget = budget as u64 * 1024 * 1024;
+                    }
+                    ui.text(imgui::im_str!(
+                        "Materials: {} cached, {:.1} MiB",
+                        asset_stats.cached_materials,
+                        asset_stats.material_bytes as f64 / (1024.0 * 1024.0),
+                    ));
+                    ui.text(imgui::im_str!(
+                        "Terrain: {} chunks, {:.1} MiB arena",
+                        asset_stats.terrain_chunks,
+                        asset_stats.terrain_bytes as f64 / (1024.0 * 1024.0),
+                    ));
+                    if imgui::CollapsingHeader::new(imgui::im_str!("Time of day")).build(&ui) {
+                        imgui::Slider::new(imgui::im_str!("Time"))
+                            .range(0.0..=24.0)
+                            .display_format(imgui::im_str!("%.2f h"))
+                            .build(&ui, &mut time_of_day.hours);
+                        ui.checkbox(imgui::im_str!("Paused"), &mut time_of_day.paused);
+                        imgui::Slider::new(imgui::im_str!("Day length"))
+                            .range(10.0..=3600.0)
+                            .display_format(imgui::im_str!("%.0f s"))
+                            .build(&ui, &mut time_of_day.day_length);
+                        ui.checkbox(imgui::im_str!("Stars"), &mut settings.sky.stars);
+                        imgui::Slider::new(imgui::im_str!("Disc size"))
+                            .range(0.1..=5.0)
+                            .build(&ui, &mut settings.sky.disc_size);
+                    }
+                    if imgui::CollapsingHeader::new(imgui::im_str!("Fog")).build(&ui) {
+                        let fog = &mut settings.fog;
+                        let mut mode = FogMode::ALL
+                            .iter()
+                            .position(|&mode| mode == fog.mode)
+                            .unwrap_or(0);
+                        if imgui::ComboBox::new(imgui::im_str!("Mode")).build_simple(
+                            &ui,
+                            &mut mode,
+                            &FogMode::ALL,
+                            &|mode| imgui::im_str!("{}", mode.name()).into(),
+                        ) {
+                            fog.mode = FogMode::ALL[mode];
+                        }
+                        imgui::Slider::new(imgui::im_str!("Start"))
+                            .range(0.0..=1.0)
+                            .build(&ui, &mut fog.start);
+                        imgui::Slider::new(imgui::im_str!("End"))
+                            .range(0.1..=1.0)
+                            .build(&ui, &mut fog.end);
+                        imgui::Slider::new(imgui::im_str!("Base height"))
+                            .range(0.0..=128.0)
+                            .build(&ui, &mut fog.base_height);
+                        imgui::Slider::new(imgui::im_str!("Height falloff"))
+                            .range(0.001..=0.5)
+                            .build(&ui, &mut fog.height_falloff);
+                        imgui::Slider::new(imgui::im_str!("Scattering"))
+                            .range(0.0..=2.0)
+                            .build(&ui, &mut fog.scattering);
+                    }
+                    if imgui::CollapsingHeader::new(imgui::im_str!("HDR")).build(&ui) {
+                        let hdr = &mut settings.hdr;
+                        ui.checkbox(imgui::im_str!("Bloom"), &mut hdr.bloom);
+                        imgui::Slider::new(imgui::im_str!("Bloom threshold"))
+                            .range(0.0..=4.0)
+                            .build(&ui, &mut hdr.bloom_threshold);
+                        imgui::Slider::new(imgui::im_str!("Bloom knee"))
+                            .range(0.0..=1.0)
+                            .build(&ui, &mut hdr.bloom_knee);
+                        imgui::Slider::new(imgui::im_str!("Bloom intensity"))
+                            .range(0.0..=2.0)
+                            .build(&ui, &mut hdr.bloom_intensity);
+                        ui.checkbox(imgui::im_str!("Exposure"), &mut hdr.exposure_enabled);
+                        imgui::Slider::new(imgui::im_str!("Exposure value"))
+                            .range(0.1..=8.0)
+                            .build(&ui, &mut hdr.exposure);
+                        let mut tone_mapping = ToneMapping::ALL
+                            .iter()
+                            .position(|&tone_mapping| tone_mapping == hdr.tone_mapping)
+                            .unwrap_or(0);
+                        if imgui::ComboBox::new(imgui::im_str!("Tone mapping")).build_simple(
+                            &ui,
+                            &mut tone_mapping,
+                            &ToneMapping::ALL,
+                            &|tone_mapping| imgui::im_str!("{}", tone_mapping.name()).into(),
+                        ) {
+                            hdr.tone_mapping = ToneMapping::ALL[tone_mapping];
+                        }
+                    }
+                    if imgui::CollapsingHeader::new(imgui::im_str!("Anti-aliasing")).build(&ui) {
+                        let sample_counts = &SAMPLE_COUNTS;
+                        let mut samples = sample_counts
+                            .iter()
+                            .position(|&samples| samples == settings.msaa.samples)
+                            .unwrap_or(0);
+                        if imgui::ComboBox::new(imgui::im_str!("MSAA")).build_simple(
+                            &ui,
+                            &mut samples,
+                            sample_counts,
+                            &|&samples| match samples {
+                                1 => imgui::im_str!("Off").into(),
+                                samples => imgui::im_str!("{}x", samples).into(),
+                            },
+                        ) {
+                            settings.msaa.samples = sample_counts[samples];
+                        }
+                    }
+                    if imgui::CollapsingHeader::new(imgui::im_str!("Lighting")).build(&ui) {
+                        let lighting = &mut settings.lighting;
+                        imgui::Slider::new(imgui::im_str!("Max lights"))
+                            .range(1..=256)
+                            .build(&ui, &mut lighting.max_lights);
+                        imgui::Slider::new(imgui::im_str!("Ambient"))
+                            .range(0.0..=1.0)
+                            .build(&ui, &mut lighting.ambient_strength);
+                        imgui::ColorEdit::new(
+                            imgui::im_str!("Ambient colour"),
+                            &mut lighting.ambient_color,
+                        )
+                        .build(&ui);
+                        imgui::Slider::new(imgui::im_str!("Environment"))
+                            .range(0.0..=4.0)
+                            .build(&ui, &mut lighting.environment_intensity);
+                        let mut source = EnvironmentSource::ALL
+                            .iter()
+                            .position(|&source| source == lighting.environment_source)
+                            .unwrap_or(0);
+                        if imgui::ComboBox::new(imgui::im_str!("Environment source")).build_simple(
+                            &ui,
+                            &mut source,
+                            &EnvironmentSource::ALL,
+                            &|source| imgui::im_str!("{}", source.name()).into(),
+                        ) {
+                            lighting.environment_source = EnvironmentSource::ALL[source];
+                        }
+                    }
+                    if imgui::CollapsingHeader::new(imgui::im_str!("Ambient occlusion")).build(&ui)
+                    {
+                        let ssao = &mut settings.ssao;
+                        ui.checkbox(imgui::im_str!("SSAO"), &mut ssao.enabled);
+                        imgui::Slider::new(imgui::im_str!("Radius"))
+                            .range(0.05..=4.0)
+                            .build(&ui, &mut ssao.radius);
+                        imgui::Slider::new(imgui::im_str!("Samples"))
+                            .range(1..=MAX_SAMPLES)
+                            .build(&ui, &mut ssao.samples);
+                        imgui::Slider::new(imgui::im_str!("Bias"))
+                            .range(0.0..=0.2)
+                            .build(&ui, &mut ssao.bias);
+                        imgui::Slider::new(imgui::im_str!("Intensity"))
+                            .range(0.5..=4.0)
+                            .build(&ui, &mut ssao.intensity);
+                    }
+                    if imgui::CollapsingHeader::new(imgui::im_str!("Shadows")).build(&ui) {
+                        let shadows = &mut settings.shadows;
+                        ui.checkbox(imgui::im_str!("Enabled"), &mut shadows.enabled);
+                        imgui::Slider::new(imgui::im_str!("Cascades"))
+                            .range(1..=MAX_CASCADES as u32)
+                            .build(&ui, &mut shadows.cascade_count);
+                        let mut resolution = SHADOW_RESOLUTIONS
+                            .iter()
+                            .position(|&resolution| resolution == shadows.resolution)
+                            .unwrap_or(0);
+                        if imgui::ComboBox::new(imgui::im_str!("Resolution")).build_simple(
+                            &ui,
+                            &mut resolution,
+                            &SHADOW_RESOLUTIONS,
+                            &|resolution| imgui::im_str!("{}", resolution).into(),
+                        ) {
+                            shadows.resolution = SHADOW_RESOLUTIONS[resolution];
+                        }
+                        imgui::Slider::new(imgui::im_str!("Depth bias"))
+                            .range(0.0..=0.01)
+                            .display_format(imgui::im_str!("%.5f"))
+                            .build(&ui, &mut shadows.depth_bias);
+                        imgui::Slider::new(imgui::im_str!("Normal bias"))
+                            .range(0.0..=1.0)
+                            .build(&ui, &mut shadows.normal_bias);
+                        imgui::Slider::new(imgui::im_str!("Distance"))
+                            .range(20.0..=1000.0)
+                            .build(&ui, &mut shadows.max_distance);
+                        imgui::Slider::new(imgui::im_str!("Split lambda"))
+                            .range(0.0..=1.0)
+                            .build(&ui, &mut shadows.split_lambda);
+                    }
+                    if imgui::CollapsingHeader::new(imgui::im_str!("Post processing")).build(&ui) {
+                        let mut moved = None;
+                        let count = post_process.effects_mut().len();
+                        for (index, effect) in post_process.effects_mut().iter_mut().enumerate() {
+                            let id = ui.push_id(index as i32);
+                            ui.checkbox(&imgui::im_str!("{}", effect.name()), &mut effect.enabled);
+                            ui.same_line(0.0);
+                            if index > 0 && ui.small_button(imgui::im_str!("Up")) {
+                                moved = Some((index, index - 1));
+                            }
+                            ui.same_line(0.0);
+                            if index + 1 < count && ui.small_button(imgui::im_str!("Down")) {
+                                moved = Some((index, index + 1));
+                            }
+                            if effect.enabled {
+                                for param in effect.params_mut() {
+                                    imgui::Slider::new(&imgui::im_str!("{}", param.name))
+                                        .range(param.min..=param.max)
+                                        .build(&ui, &mut param.value);
+                                }
+                            }
+                            id.pop(&ui);
+                        }
+                        if let Some((from, to)) = moved {
+                            post_process.move_effect(from, to);
+                        }
+                    }
                 });
         }
 
@@ -85,7 +386,7 @@ impl Gui {
         let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: None,
             color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                attachment: &frame.view,
+                attachment: target,
                 resolve_target: None,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Load,
@@ -0,0 +1,542 @@
+use crate::display::Display;
+
+/// Format of the target the scene is rendered into before tone mapping.
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Number of bloom levels, each half the size of the one before, starting at half resolution.
+const BLOOM_LEVELS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapping {
+    /// Colours are clamped into the displayable range.
+    Off,
+    Reinhard,
+    Aces,
+}
+
+impl ToneMapping {
+    pub const ALL: [ToneMapping; 3] = [ToneMapping::Off, ToneMapping::Reinhard, ToneMapping::Aces];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneMapping::Off => "Off",
+            ToneMapping::Reinhard => "Reinhard",
+            ToneMapping::Aces => "ACES",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HdrConfig {
+    pub bloom: bool,
+    /// Brightness above which pixels start to bloom.
+    pub bloom_threshold: f32,
+    /// Range below the threshold over which the bloom fades in.
+    pub bloom_knee: f32,
+    pub bloom_intensity: f32,
+    pub exposure_enabled: bool,
+    pub exposure: f32,
+    pub tone_mapping: ToneMapping,
+}
+
+impl Default for HdrConfig {
+    fn default() -> Self {
+        Self {
+            bloom: true,
+            bloom_threshold: 1.0,
+            bloom_knee: 0.5,
+            bloom_intensity: 0.3,
+            exposure_enabled: true,
+            exposure: 1.0,
+            tone_mapping: ToneMapping::Aces,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PostUniforms {
+    params: [f32; 4],
+}
+
+struct BloomLevel {
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    /// Samples this level when it is the source of the next pass.
+    bind_group: wgpu::BindGroup,
+}
+
+/// Everything that depends on the size of the frame and is recreated on resize.
+struct HdrTextures {
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    source_bind_group: wgpu::BindGroup,
+    tone_map_bind_group: wgpu::BindGroup,
+    bloom: Vec<BloomLevel>,
+}
+
+/// The multisampled colour attachment of the main pass, which resolves into the HDR target.
+struct MultisampledTarget {
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+}
+
+/// The HDR colour target the scene is rendered into, and the passes that bloom and tone map it
+/// into the frame.
+pub struct HdrTarget {
+    textures: HdrTextures,
+    sample_count: u32,
+    multisampled: Option<MultisampledTarget>,
+    sampler: wgpu::Sampler,
+    source_layout: wgpu::BindGroupLayout,
+    tone_map_layout: wgpu::BindGroupLayout,
+    bloom_buffer: wgpu::Buffer,
+    tone_map_buffer: wgpu::Buffer,
+    prefilter_pipeline: wgpu::RenderPipeline,
+    downsample_pipeline: wgpu::RenderPipeline,
+    upsample_pipeline: wgpu::RenderPipeline,
+    tone_map_pipeline: wgpu::RenderPipeline,
+}
+
+impl HdrTarget {
+    pub fn new(display: &Display) -> Self {
+        let device = &display.device;
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("HDR Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let uniform_buffer = |label| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: std::mem::size_of::<PostUniforms>() as u64,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        let bloom_buffer = uniform_buffer("Bloom Uniform Buffer");
+        let tone_map_buffer = uniform_buffer("Tone Map Uniform Buffer");
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let common_entries = [
+            texture_entry(0),
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler {
+                    comparison: false,
+                    filtering: true,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ];
+        let source_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("hdr_source_bind_group_layout"),
+            entries: &common_entries,
+        });
+        let tone_map_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("tone_map_bind_group_layout"),
+            entries: &[
+                common_entries[0].clone(),
+                common_entries[1].clone(),
+                common_entries[2].clone(),
+                texture_entry(3),
+            ],
+        });
+
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!(
+            "../resources/shaders/fullscreen.vert.spv"
+        ));
+        let pipeline = |label, layout, fs_src: &wgpu::ShaderModuleDescriptor, format, blend| {
+            Self::create_pipeline(device, label, layout, &vs_module, fs_src, format, blend)
+        };
+        let additive = wgpu::BlendState {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let prefilter_pipeline = pipeline(
+            "Bloom Prefilter Pipeline",
+            &source_layout,
+            &wgpu::include_spirv!("../resources/shaders/bloom_prefilter.frag.spv"),
+            HDR_FORMAT,
+            wgpu::BlendState::REPLACE,
+        );
+        let downsample_pipeline = pipeline(
+            "Bloom Downsample Pipeline",
+            &source_layout,
+            &wgpu::include_spirv!("../resources/shaders/bloom_downsample.frag.spv"),
+            HDR_FORMAT,
+            wgpu::BlendState::REPLACE,
+        );
+        let upsample_pipeline = pipeline(
+            "Bloom Upsample Pipeline",
+            &source_layout,
+            &wgpu::include_spirv!("../resources/shaders/bloom_upsample.frag.spv"),
+            HDR_FORMAT,
+            additive,
+        );
+        let tone_map_pipeline = pipeline(
+            "Tone Map Pipeline",
+            &tone_map_layout,
+            &wgpu::include_spirv!("../resources/shaders/tonemap.frag.spv"),
+            display.swap_chain_descriptor.format,
+            wgpu::BlendState::REPLACE,
+        );
+
+        let textures = Self::create_textures(
+            display,
+            &sampler,
+            &source_layout,
+            &tone_map_layout,
+            &bloom_buffer,
+            &tone_map_buffer,
+        );
+
+        Self {
+            textures,
+            sample_count: 1,
+            multisampled: None,
+            sampler,
+            source_layout,
+            tone_map_layout,
+            bloom_buffer,
+            tone_map_buffer,
+            prefilter_pipeline,
+            downsample_pipeline,
+            upsample_pipeline,
+            tone_map_pipeline,
+        }
+    }
+
+    /// The colour attachment the scene is rendered into. With MSAA it is multisampled and
+    /// resolves into the HDR target at the end of the pass.
+    pub fn color_attachment(
+        &self,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) -> wgpu::RenderPassColorAttachmentDescriptor<'_> {
+        let ops = wgpu::Operations { load, store: true };
+        match &self.multisampled {
+            Some(multisampled) => wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &multisampled.view,
+                resolve_target: Some(&self.textures.view),
+                ops,
+            },
+            None => wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &self.textures.view,
+                resolve_target: None,
+                ops,
+            },
+        }
+    }
+
+    pub fn set_sample_count(&mut self, display: &Display, sample_count: u32) {
+        if sample_count != self.sample_count {
+            self.sample_count = sample_count;
+            self.multisampled = Self::create_multisampled(display, sample_count);
+        }
+    }
+
+    pub fn resize(&mut self, display: &Display) {
+        self.textures = Self::create_textures(
+            display,
+            &self.sampler,
+            &self.source_layout,
+            &self.tone_map_layout,
+            &self.bloom_buffer,
+            &self.tone_map_buffer,
+        );
+        self.multisampled = Self::create_multisampled(display, self.sample_count);
+    }
+
+    /// Records the bloom and tone mapping passes that turn the HDR target into the final
+    /// image in `target`.
+    pub fn resolve(
+        &self,
+        display: &Display,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        config: &HdrConfig,
+    ) {
+        let bloom = &self.textures.bloom;
+        if config.bloom {
+            display.queue.write_buffer(
+                &self.bloom_buffer,
+                0,
+                bytemuck::cast_slice(&[PostUniforms {
+                    params: [config.bloom_threshold, config.bloom_knee, 0.0, 0.0],
+                }]),
+            );
+            self.fullscreen_pass(
+                encoder,
+                "Bloom Prefilter Pass",
+                &bloom[0].view,
+                &self.prefilter_pipeline,
+                &self.textures.source_bind_group,
+                true,
+            );
+            for level in 1..bloom.len() {
+                self.fullscreen_pass(
+                    encoder,
+                    "Bloom Downsample Pass",
+                    &bloom[level].view,
+                    &self.downsample_pipeline,
+                    &bloom[level - 1].bind_group,
+                    true,
+                );
+            }
+            for level in (0..bloom.len() - 1).rev() {
+                self.fullscreen_pass(
+                    encoder,
+                    "Bloom Upsample Pass",
+                    &bloom[level].view,
+                    &self.upsample_pipeline,
+                    &bloom[level + 1].bind_group,
+                    false,
+                );
+            }
+        }
+
+        let exposure = if config.exposure_enabled {
+            config.exposure
+        } else {
+            1.0
+        };
+        let bloom_intensity = if config.bloom {
+            config.bloom_intensity
+        } else {
+            0.0
+        };
+        display.queue.write_buffer(
+            &self.tone_map_buffer,
+            0,
+            bytemuck::cast_slice(&[PostUniforms {
+                params: [
+                    exposure,
+                    bloom_intensity,
+                    config.tone_mapping as u32 as f32,
+                    0.0,
+                ],
+            }]),
+        );
+        self.fullscreen_pass(
+            encoder,
+            "Tone Map Pass",
+            target,
+            &self.tone_map_pipeline,
+            &self.textures.tone_map_bind_group,
+            true,
+        );
+    }
+
+    fn fullscreen_pass(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        label: &str,
+        target: &wgpu::TextureView,
+        pipeline: &wgpu::RenderPipeline,
+        bind_group: &wgpu::BindGroup,
+        clear: bool,
+    ) {
+        let load = if clear {
+            wgpu::LoadOp::Clear(wgpu::Color::BLACK)
+        } else {
+            wgpu::LoadOp::Load
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: target,
+                resolve_target: None,
+                ops: wgpu::Operations { load, store: true },
+            }],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    fn create_multisampled(display: &Display, sample_count: u32) -> Option<MultisampledTarget> {
+        if sample_count == 1 {
+            return None;
+        }
+        let (texture, view) = create_target(
+            &display.device,
+            "Multisampled HDR Target",
+            display.swap_chain_descriptor.width,
+            display.swap_chain_descriptor.height,
+            sample_count,
+        );
+        Some(MultisampledTarget {
+            _texture: texture,
+            view,
+        })
+    }
+
+    fn create_textures(
+        display: &Display,
+        sampler: &wgpu::Sampler,
+        source_layout: &wgpu::BindGroupLayout,
+        tone_map_layout: &wgpu::BindGroupLayout,
+        bloom_buffer: &wgpu::Buffer,
+        tone_map_buffer: &wgpu::Buffer,
+    ) -> HdrTextures {
+        let device = &display.device;
+        let (width, height) = (
+            display.swap_chain_descriptor.width,
+            display.swap_chain_descriptor.height,
+        );
+        let source_bind_group = |view: &wgpu::TextureView| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("hdr_source_bind_group"),
+                layout: source_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: bloom_buffer.as_entire_binding(),
+                    },
+                ],
+            })
+        };
+
+        let (texture, view) = create_target(device, "HDR Target", width, height, 1);
+        let bloom = (0..BLOOM_LEVELS)
+            .map(|level| {
+                let (texture, view) = create_target(
+                    device,
+                    "Bloom Level",
+                    (width >> (level + 1)).max(1),
+                    (height >> (level + 1)).max(1),
+                    1,
+                );
+                BloomLevel {
+                    bind_group: source_bind_group(&view),
+                    _texture: texture,
+                    view,
+                }
+            })
+            .collect::<Vec<_>>();
+        let tone_map_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("tone_map_bind_group"),
+            layout: tone_map_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: tone_map_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&bloom[0].view),
+                },
+            ],
+        });
+
+        HdrTextures {
+            source_bind_group: source_bind_group(&view),
+            tone_map_bind_group,
+            _texture: texture,
+            view,
+            bloom,
+        }
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        label: &str,
+        layout: &wgpu::BindGroupLayout,
+        vs_module: &wgpu::ShaderModule,
+        fs_src: &wgpu::ShaderModuleDescriptor,
+        format: wgpu::TextureFormat,
+        color_blend: wgpu::BlendState,
+    ) -> wgpu::RenderPipeline {
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(label),
+            bind_group_layouts: &[layout],
+            push_constant_ranges: &[],
+        });
+        let fs_module = device.create_shader_module(fs_src);
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: vs_module,
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format,
+                    color_blend,
+                    alpha_blend: wgpu::BlendState::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: Default::default(),
+            depth_stencil: None,
+            multisample: Default::default(),
+        })
+    }
+}
+
+fn create_target(
+    device: &wgpu::Device,
+    label: &str,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+) -> (wgpu::Texture, wgpu::TextureView) {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format: HDR_FORMAT,
+        usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    (texture, view)
+}
@@ -0,0 +1,557 @@
+use std::num::{NonZeroU32, NonZeroU64};
+
+use anyhow::Result;
+use cgmath::InnerSpace;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    display::Display,
+    ecs::component::Sky,
+    hdr::HDR_FORMAT,
+    sky::{SkyConfig, SkyPass},
+    texture::{SamplerConfig, Texture},
+};
+
+/// Size of the faces the sky is captured into.
+pub const ENVIRONMENT_SIZE: u32 = 128;
+pub const IRRADIANCE_SIZE: u32 = 32;
+pub const PREFILTERED_SIZE: u32 = 128;
+/// Mip levels of the prefiltered map. Roughness goes from zero at the top level to one at the
+/// last.
+pub const PREFILTERED_LEVELS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 256;
+const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+/// How far the sun moves, in degrees, before the environment is captured again.
+const RECAPTURE_ANGLE: f32 = 1.0;
+/// Uniforms of every convolution draw sit in one buffer, at offsets aligned for dynamic
+/// binding.
+const UNIFORM_STRIDE: usize = 256;
+
+/// Where the light of PBR materials comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvironmentSource {
+    /// The environment cubemap of the asset manifest, convolved once when it is loaded. Falls
+    /// back to the sky when no cubemap has been loaded.
+    Cubemap,
+    /// The procedural sky, captured and convolved again whenever the sun moves, so reflections
+    /// follow the time of day at the cost of the convolution passes.
+    Sky,
+}
+
+impl EnvironmentSource {
+    pub const ALL: [EnvironmentSource; 2] = [EnvironmentSource::Cubemap, EnvironmentSource::Sky];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EnvironmentSource::Cubemap => "Cubemap",
+            EnvironmentSource::Sky => "Sky",
+        }
+    }
+}
+
+/// The environment the current maps were convolved from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Convolved {
+    Cubemap,
+    /// The sky, with the sun direction it was captured with.
+    Sky(cgmath::Vector3<f32>),
+}
+
+/// The orientation of a cubemap face, following the layer order and texture coordinate
+/// conventions of cube textures.
+pub struct CubeFace {
+    pub forward: [f32; 3],
+    pub right: [f32; 3],
+    pub up: [f32; 3],
+}
+
+pub const CUBE_FACES: [CubeFace; 6] = [
+    CubeFace {
+        forward: [1.0, 0.0, 0.0],
+        right: [0.0, 0.0, -1.0],
+        up: [0.0, 1.0, 0.0],
+    },
+    CubeFace {
+        forward: [-1.0, 0.0, 0.0],
+        right: [0.0, 0.0, 1.0],
+        up: [0.0, 1.0, 0.0],
+    },
+    CubeFace {
+        forward: [0.0, 1.0, 0.0],
+        right: [1.0, 0.0, 0.0],
+        up: [0.0, 0.0, -1.0],
+    },
+    CubeFace {
+        forward: [0.0, -1.0, 0.0],
+        right: [1.0, 0.0, 0.0],
+        up: [0.0, 0.0, 1.0],
+    },
+    CubeFace {
+        forward: [0.0, 0.0, 1.0],
+        right: [1.0, 0.0, 0.0],
+        up: [0.0, 1.0, 0.0],
+    },
+    CubeFace {
+        forward: [0.0, 0.0, -1.0],
+        right: [-1.0, 0.0, 0.0],
+        up: [0.0, 1.0, 0.0],
+    },
+];
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ConvolutionUniforms {
+    right: [f32; 4],
+    up: [f32; 4],
+    forward: [f32; 4],
+    /// Roughness of the prefiltered level.
+    params: [f32; 4],
+}
+
+impl ConvolutionUniforms {
+    fn new(face: &CubeFace, roughness: f32) -> Self {
+        let axis = |v: [f32; 3]| [v[0], v[1], v[2], 0.0];
+        Self {
+            right: axis(face.right),
+            up: axis(face.up),
+            forward: axis(face.forward),
+            params: [roughness, 0.0, 0.0, 0.0],
+        }
+    }
+}
+
+/// A cube texture that is rendered to one face and mip level at a time.
+struct CubeTarget {
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    /// Views of every face, indexed by mip level and then face.
+    faces: Vec<Vec<wgpu::TextureView>>,
+}
+
+impl CubeTarget {
+    fn new(device: &wgpu::Device, label: &str, size: u32, mip_level_count: u32) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth: 6,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HDR_FORMAT,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::RENDER_ATTACHMENT,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some(label),
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let faces = (0..mip_level_count)
+            .map(|level| {
+                (0..CUBE_FACES.len() as u32)
+                    .map(|face| {
+                        texture.create_view(&wgpu::TextureViewDescriptor {
+                            label: Some(label),
+                            dimension: Some(wgpu::TextureViewDimension::D2),
+                            base_mip_level: level,
+                            level_count: NonZeroU32::new(1),
+                            base_array_layer: face,
+                            array_layer_count: NonZeroU32::new(1),
+                            ..Default::default()
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            _texture: texture,
+            view,
+            faces,
+        }
+    }
+}
+
+/// Image based lighting for PBR materials. An environment cubemap, loaded from the asset
+/// manifest or captured from the sky, is convolved into an irradiance map for diffuse light and
+/// a prefiltered map for specular reflections, with one mip level per roughness step. A lookup
+/// table of the split sum BRDF is generated once at load time.
+pub struct Environment {
+    sky_capture: CubeTarget,
+    irradiance: CubeTarget,
+    prefiltered: CubeTarget,
+    _brdf_lut: wgpu::Texture,
+    brdf_lut_view: wgpu::TextureView,
+    sampler: wgpu::Sampler,
+    uniform_buffer: wgpu::Buffer,
+    layout: wgpu::BindGroupLayout,
+    /// Convolves the sky capture.
+    sky_bind_group: wgpu::BindGroup,
+    /// The loaded environment cubemap, and the bind group that convolves it.
+    cubemap: Option<(Texture, wgpu::BindGroup)>,
+    irradiance_pipeline: wgpu::RenderPipeline,
+    prefilter_pipeline: wgpu::RenderPipeline,
+    convolved: Option<Convolved>,
+}
+
+impl Environment {
+    pub fn new(display: &Display) -> Self {
+        let device = &display.device;
+        let sky_capture = CubeTarget::new(device, "Sky Capture", ENVIRONMENT_SIZE, 1);
+        let irradiance = CubeTarget::new(device, "Irradiance Map", IRRADIANCE_SIZE, 1);
+        let prefiltered = CubeTarget::new(
+            device,
+            "Prefiltered Environment Map",
+            PREFILTERED_SIZE,
+            PREFILTERED_LEVELS,
+        );
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        // The irradiance faces come first, then the faces of every prefiltered level.
+        let mut contents = Vec::new();
+        let roughness_levels = std::iter::once(0.0).chain(
+            (0..PREFILTERED_LEVELS).map(|level| level as f32 / (PREFILTERED_LEVELS - 1) as f32),
+        );
+        for roughness in roughness_levels {
+            for face in CUBE_FACES.iter() {
+                let uniforms = ConvolutionUniforms::new(face, roughness);
+                contents.extend_from_slice(bytemuck::bytes_of(&uniforms));
+                contents.resize(
+                    contents.len() + UNIFORM_STRIDE - std::mem::size_of_val(&uniforms),
+                    0,
+                );
+            }
+        }
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Convolution Buffer"),
+            contents: &contents,
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("environment_convolution_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: NonZeroU64::new(
+                            std::mem::size_of::<ConvolutionUniforms>() as u64,
+                        ),
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let sky_bind_group = create_convolution_bind_group(
+            device,
+            &layout,
+            &sky_capture.view,
+            &sampler,
+            &uniform_buffer,
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Environment Convolution Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let irradiance_pipeline = create_pipeline(
+            device,
+            "Irradiance Pipeline",
+            &pipeline_layout,
+            HDR_FORMAT,
+            &wgpu::include_spirv!("../resources/shaders/ibl_irradiance.frag.spv"),
+        );
+        let prefilter_pipeline = create_pipeline(
+            device,
+            "Prefilter Pipeline",
+            &pipeline_layout,
+            HDR_FORMAT,
+            &wgpu::include_spirv!("../resources/shaders/ibl_prefilter.frag.spv"),
+        );
+
+        let brdf_lut = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("BRDF Lookup Table"),
+            size: wgpu::Extent3d {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: BRDF_LUT_FORMAT,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::RENDER_ATTACHMENT,
+        });
+        let brdf_lut_view = brdf_lut.create_view(&wgpu::TextureViewDescriptor::default());
+        Self::generate_brdf_lut(display, &brdf_lut_view);
+
+        Self {
+            sky_capture,
+            irradiance,
+            prefiltered,
+            _brdf_lut: brdf_lut,
+            brdf_lut_view,
+            sampler,
+            uniform_buffer,
+            layout,
+            sky_bind_group,
+            cubemap: None,
+            irradiance_pipeline,
+            prefilter_pipeline,
+            convolved: None,
+        }
+    }
+
+    /// Uploads an environment cubemap from its faces, in the layer order of cube textures, and
+    /// convolves it right away.
+    pub fn load_cubemap(&mut self, display: &Display, faces: &[image::DynamicImage]) -> Result<()> {
+        let texture = Texture::cube(
+            &display.device,
+            &display.queue,
+            faces,
+            Some("Environment Cubemap"),
+            &SamplerConfig::default(),
+        )?;
+        let bind_group = create_convolution_bind_group(
+            &display.device,
+            &self.layout,
+            &texture.view,
+            &self.sampler,
+            &self.uniform_buffer,
+        );
+
+        let mut encoder = display
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Environment Convolution Encoder"),
+            });
+        self.convolve_all(&mut encoder, &bind_group);
+        display.queue.submit(std::iter::once(encoder.finish()));
+        self.cubemap = Some((texture, bind_group));
+        self.convolved = Some(Convolved::Cubemap);
+        Ok(())
+    }
+
+    pub fn irradiance(&self) -> &wgpu::TextureView {
+        &self.irradiance.view
+    }
+
+    pub fn prefiltered(&self) -> &wgpu::TextureView {
+        &self.prefiltered.view
+    }
+
+    pub fn brdf_lut(&self) -> &wgpu::TextureView {
+        &self.brdf_lut_view
+    }
+
+    pub fn sampler(&self) -> &wgpu::Sampler {
+        &self.sampler
+    }
+
+    /// Convolves the environment `source` asks for, if the maps do not hold it already. The
+    /// loaded cubemap is only convolved again after switching back to it, while the sky is
+    /// captured and convolved again once the sun has moved far enough for the lighting to
+    /// change.
+    pub fn update(
+        &mut self,
+        display: &Display,
+        encoder: &mut wgpu::CommandEncoder,
+        source: EnvironmentSource,
+        sky: &Sky,
+        sky_pass: &SkyPass,
+        config: &SkyConfig,
+    ) {
+        if let (EnvironmentSource::Cubemap, Some((_, bind_group))) = (source, &self.cubemap) {
+            if self.convolved != Some(Convolved::Cubemap) {
+                self.convolve_all(encoder, bind_group);
+                self.convolved = Some(Convolved::Cubemap);
+            }
+            return;
+        }
+
+        let stale = match self.convolved {
+            Some(Convolved::Sky(sun)) => {
+                sun.dot(sky.sun_direction) < RECAPTURE_ANGLE.to_radians().cos()
+            }
+            _ => true,
+        };
+        if !stale {
+            return;
+        }
+        sky_pass.capture(display, encoder, sky, config, &self.sky_capture.faces[0]);
+        self.convolve_all(encoder, &self.sky_bind_group);
+        self.convolved = Some(Convolved::Sky(sky.sun_direction));
+    }
+
+    /// Renders the irradiance map and every level of the prefiltered map from the environment
+    /// bound by `bind_group`.
+    fn convolve_all(&self, encoder: &mut wgpu::CommandEncoder, bind_group: &wgpu::BindGroup) {
+        for (face, view) in self.irradiance.faces[0].iter().enumerate() {
+            self.convolve(encoder, &self.irradiance_pipeline, bind_group, view, face);
+        }
+        for (level, faces) in self.prefiltered.faces.iter().enumerate() {
+            for (face, view) in faces.iter().enumerate() {
+                let draw = CUBE_FACES.len() * (level + 1) + face;
+                self.convolve(encoder, &self.prefilter_pipeline, bind_group, view, draw);
+            }
+        }
+    }
+
+    fn convolve(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        bind_group: &wgpu::BindGroup,
+        target: &wgpu::TextureView,
+        draw: usize,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Environment Convolution"),
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[(draw * UNIFORM_STRIDE) as u32]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    fn generate_brdf_lut(display: &Display, target: &wgpu::TextureView) {
+        let device = &display.device;
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("BRDF Lookup Table Pipeline Layout"),
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+        let pipeline = create_pipeline(
+            device,
+            "BRDF Lookup Table Pipeline",
+            &pipeline_layout,
+            BRDF_LUT_FORMAT,
+            &wgpu::include_spirv!("../resources/shaders/brdf_lut.frag.spv"),
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("BRDF Lookup Table Encoder"),
+        });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("BRDF Lookup Table"),
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(&pipeline);
+            render_pass.draw(0..3, 0..1);
+        }
+        display.queue.submit(std::iter::once(encoder.finish()));
+    }
+}
+
+fn create_convolution_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    environment: &wgpu::TextureView,
+    sampler: &wgpu::Sampler,
+    uniform_buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("environment_convolution_bind_group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(environment),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: uniform_buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(std::mem::size_of::<ConvolutionUniforms>() as u64),
+                },
+            },
+        ],
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    layout: &wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    fs_src: &wgpu::ShaderModuleDescriptor,
+) -> wgpu::RenderPipeline {
+    let vs_module = device.create_shader_module(&wgpu::include_spirv!(
+        "../resources/shaders/fullscreen.vert.spv"
+    ));
+    let fs_module = device.create_shader_module(fs_src);
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: &vs_module,
+            entry_point: "main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &fs_module,
+            entry_point: "main",
+            targets: &[wgpu::ColorTargetState {
+                format,
+                color_blend: wgpu::BlendState::REPLACE,
+                alpha_blend: wgpu::BlendState::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        }),
+        primitive: Default::default(),
+        depth_stencil: None,
+        multisample: Default::default(),
+    })
+}
@@ -1,3 +1,5 @@
+use crate::display::Display;
+
 pub struct Instance {
     position: cgmath::Vector3<f32>,
     rotation: cgmath::Quaternion<f32>,
@@ -8,12 +10,8 @@ impl Instance {
         Self { position, rotation }
     }
 
-    pub fn to_raw(&self) -> InstanceRaw {
-        InstanceRaw {
-            model: (cgmath::Matrix4::from_translation(self.position)
-                * cgmath::Matrix4::from(self.rotation))
-            .into(),
-        }
+    pub fn to_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)
     }
 
     pub fn set_position(&mut self, position: cgmath::Vector3<f32>) {
@@ -31,6 +29,14 @@ pub struct InstanceRaw {
     model: [[f32; 4]; 4],
 }
 
+impl From<cgmath::Matrix4<f32>> for InstanceRaw {
+    fn from(matrix: cgmath::Matrix4<f32>) -> Self {
+        Self {
+            model: matrix.into(),
+        }
+    }
+}
+
 impl Default for InstanceRaw {
     fn default() -> Self {
         Self {
@@ -74,3 +80,57 @@ impl InstanceRaw {
         }
     }
 }
+
+/// Instance data for every model drawn in a frame, packed into one vertex buffer. The buffer
+/// grows to fit the largest frame and shrinks again once usage has stayed low for a while.
+pub struct InstanceBuffer {
+    pub buffer: wgpu::Buffer,
+    capacity: usize,
+    low_usage_frames: u32,
+}
+
+impl InstanceBuffer {
+    const MIN_CAPACITY: usize = 256;
+    const SHRINK_AFTER_FRAMES: u32 = 120;
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            buffer: Self::create_buffer(device, Self::MIN_CAPACITY),
+            capacity: Self::MIN_CAPACITY,
+            low_usage_frames: 0,
+        }
+    }
+
+    pub fn write(&mut self, display: &Display, instances: &[InstanceRaw]) {
+        let required = instances.len().max(Self::MIN_CAPACITY).next_power_of_two();
+        if required > self.capacity {
+            self.resize(&display.device, required);
+        } else if required * 4 <= self.capacity {
+            self.low_usage_frames += 1;
+            if self.low_usage_frames >= Self::SHRINK_AFTER_FRAMES {
+                self.resize(&display.device, required * 2);
+            }
+        } else {
+            self.low_usage_frames = 0;
+        }
+
+        display
+            .queue
+            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(instances));
+    }
+
+    fn resize(&mut self, device: &wgpu::Device, capacity: usize) {
+        self.buffer = Self::create_buffer(device, capacity);
+        self.capacity = capacity;
+        self.low_usage_frames = 0;
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Instance Buffer"),
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            size: (std::mem::size_of::<InstanceRaw>() * capacity) as u64,
+            mapped_at_creation: false,
+        })
+    }
+}
@@ -1,19 +1,269 @@
+use cgmath::{InnerSpace, MetricSpace};
+
+use crate::{
+    ecs::component::{Light, LightKind, Transform},
+    ibl::EnvironmentSource,
+};
+
+pub const DEFAULT_MAX_LIGHTS: u32 = 32;
+
+const LIGHT_POINT: f32 = 0.0;
+const LIGHT_SPOT: f32 = 1.0;
+const LIGHT_DIRECTIONAL: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightConfig {
+    /// Upper bound on the lights shading a frame. Directional lights are kept first, then the
+    /// point and spot lights closest to the camera.
+    pub max_lights: u32,
+    pub ambient_color: [f32; 3],
+    pub ambient_strength: f32,
+    /// Scales the image based lighting of PBR materials.
+    pub environment_intensity: f32,
+    pub environment_source: EnvironmentSource,
+}
+
+impl Default for LightConfig {
+    fn default() -> Self {
+        Self {
+            max_lights: DEFAULT_MAX_LIGHTS,
+            ambient_color: [1.0, 1.0, 1.0],
+            ambient_strength: 0.1,
+            environment_intensity: 1.0,
+            environment_source: EnvironmentSource::Cubemap,
+        }
+    }
+}
+
 #[repr(C)]
-#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct Light {
-    position: [f32; 3],
-    _padding: u32,
-    color: [f32; 3],
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightRaw {
+    /// `w` holds the light kind.
+    position: [f32; 4],
+    /// `w` holds the cosine of a spot light's outer angle.
+    direction: [f32; 4],
+    /// `w` holds the intensity.
+    color: [f32; 4],
+    /// Constant, linear and quadratic terms, `w` holds the cosine of a spot light's inner angle.
+    attenuation: [f32; 4],
 }
 
-impl Light {}
+impl LightRaw {
+    pub fn new(transform: &Transform, light: &Light) -> Self {
+        let rotation: cgmath::Quaternion<f32> = transform.rotation.into();
+        let direction = (rotation * -cgmath::Vector3::unit_z()).normalize();
+        let (kind, inner, outer) = match light.kind {
+            LightKind::Point => (LIGHT_POINT, 0.0, 0.0),
+            LightKind::Spot { inner, outer } => (LIGHT_SPOT, inner.0.cos(), outer.0.cos()),
+            LightKind::Directional => (LIGHT_DIRECTIONAL, 0.0, 0.0),
+        };
+        Self {
+            position: transform.position.extend(kind).into(),
+            direction: direction.extend(outer).into(),
+            color: [
+                light.color[0],
+                light.color[1],
+                light.color[2],
+                light.intensity,
+            ],
+            attenuation: [
+                light.attenuation.constant,
+                light.attenuation.linear,
+                light.attenuation.quadratic,
+                inner,
+            ],
+        }
+    }
 
-impl Default for Light {
-    fn default() -> Self {
+    /// The direction the light shines in, meaningless for point lights.
+    pub fn direction(&self) -> cgmath::Vector3<f32> {
+        cgmath::Vector3::new(self.direction[0], self.direction[1], self.direction[2])
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.color[3]
+    }
+
+    pub fn is_directional(&self) -> bool {
+        self.position[3] == LIGHT_DIRECTIONAL
+    }
+
+    fn distance2(&self, position: cgmath::Vector3<f32>) -> f32 {
+        cgmath::Vector3::new(self.position[0], self.position[1], self.position[2])
+            .distance2(position)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightHeader {
+    count: [u32; 4],
+    ambient: [f32; 4],
+}
+
+/// Storage buffer holding the lights that shade a frame, bound at set 2 of the pipeline.
+pub struct LightBuffer {
+    config: LightConfig,
+    layout: wgpu::BindGroupLayout,
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    lights: Vec<LightRaw>,
+    ambient_tint: [f32; 3],
+}
+
+impl LightBuffer {
+    pub fn new(device: &wgpu::Device, config: LightConfig) -> Self {
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("light_bind_group_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let (buffer, bind_group) = Self::create_buffer(device, &layout, config.max_lights);
         Self {
-            position: [2.0, 2.0, 2.0],
-            _padding: 0,
-            color: [1.0, 1.0, 1.0],
+            config,
+            layout,
+            buffer,
+            bind_group,
+            lights: Vec::new(),
+            ambient_tint: [1.0, 1.0, 1.0],
+        }
+    }
+
+    pub fn layout(&self) -> &wgpu::BindGroupLayout {
+        &self.layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn set_config(&mut self, device: &wgpu::Device, config: LightConfig) {
+        if config.max_lights != self.config.max_lights {
+            let (buffer, bind_group) = Self::create_buffer(device, &self.layout, config.max_lights);
+            self.buffer = buffer;
+            self.bind_group = bind_group;
         }
+        self.config = config;
+    }
+
+    /// Picks the lights for the next frame as seen from `view_position`. The configured ambient
+    /// colour is multiplied by `ambient_tint`, which follows the sky.
+    pub fn update(
+        &mut self,
+        lights: &[LightRaw],
+        view_position: cgmath::Vector3<f32>,
+        ambient_tint: [f32; 3],
+    ) {
+        self.ambient_tint = ambient_tint;
+        self.lights.clear();
+        self.lights.extend_from_slice(lights);
+        // The brightest directional light comes first, as it is the one casting shadows.
+        self.lights
+            .sort_by(|a, b| match (a.is_directional(), b.is_directional()) {
+                (true, true) => b
+                    .intensity()
+                    .partial_cmp(&a.intensity())
+                    .unwrap_or(std::cmp::Ordering::Equal),
+                (a_directional, b_directional) => {
+                    b_directional.cmp(&a_directional).then_with(|| {
+                        a.distance2(view_position)
+                            .partial_cmp(&b.distance2(view_position))
+                            .unwrap_or(std::cmp::Ordering::Equal)
+                    })
+                }
+            });
+        self.lights.truncate(self.config.max_lights as usize);
+    }
+
+    pub fn write(&self, queue: &wgpu::Queue) {
+        let (ambient, tint) = (self.config.ambient_color, self.ambient_tint);
+        let header = LightHeader {
+            count: [self.lights.len() as u32, 0, 0, 0],
+            ambient: [
+                ambient[0] * tint[0],
+                ambient[1] * tint[1],
+                ambient[2] * tint[2],
+                self.config.ambient_strength,
+            ],
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[header]));
+        if !self.lights.is_empty() {
+            queue.write_buffer(
+                &self.buffer,
+                std::mem::size_of::<LightHeader>() as u64,
+                bytemuck::cast_slice(&self.lights),
+            );
+        }
+    }
+
+    fn create_buffer(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        max_lights: u32,
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        // Storage buffers can not hold a zero length array, so there is always room for one light.
+        let size = std::mem::size_of::<LightHeader>()
+            + std::mem::size_of::<LightRaw>() * max_lights.max(1) as usize;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Light Buffer"),
+            size: size as u64,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("light_bind_group"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+        (buffer, bind_group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spot_lights_store_their_direction_and_cone() {
+        let transform = Transform {
+            position: cgmath::Vector3::new(1.0, 2.0, 3.0),
+            rotation: cgmath::Euler::new(
+                cgmath::Deg(-90.0).into(),
+                cgmath::Rad(0.0),
+                cgmath::Rad(0.0),
+            ),
+        };
+        let light = Light {
+            kind: LightKind::Spot {
+                inner: cgmath::Deg(20.0).into(),
+                outer: cgmath::Deg(30.0).into(),
+            },
+            color: [1.0, 1.0, 1.0],
+            intensity: 2.0,
+            attenuation: Default::default(),
+        };
+        let raw = LightRaw::new(&transform, &light);
+
+        assert_eq!(raw.position, [1.0, 2.0, 3.0, LIGHT_SPOT]);
+        assert!((raw.direction() - cgmath::Vector3::new(0.0, -1.0, 0.0)).magnitude() < 1e-5);
+        assert!((raw.direction[3] - 30f32.to_radians().cos()).abs() < 1e-6);
+        assert!((raw.attenuation[3] - 20f32.to_radians().cos()).abs() < 1e-6);
+        assert!(!raw.is_directional());
+        assert_eq!(raw.intensity(), 2.0);
     }
 }
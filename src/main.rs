@@ -1,8 +1,15 @@
 #[macro_use]
 extern crate bitflags;
 
-use std::{sync::Arc, time::Instant};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
+use asset::AssetRegistry;
+use gui::GuiState;
+use offscreen::OffscreenTarget;
 use pipeline::SimplePipeline;
 use renderer::Renderer;
 use scene::SceneManager;
@@ -20,16 +27,31 @@ mod chunk;
 mod display;
 mod ecs;
 mod event;
+mod fog;
 mod game;
+mod gltf_loader;
+#[cfg(test)]
+mod golden;
 mod gui;
+mod hdr;
+mod ibl;
 mod instance;
 mod light;
 mod material;
 mod math;
 mod mesh;
+mod msaa;
+mod offscreen;
 mod pipeline;
+mod postprocess;
+mod render_list;
 mod renderer;
 mod scene;
+mod settings;
+mod shadow;
+mod sky;
+mod ssao;
+mod terrain;
 mod texture;
 mod timestep;
 mod worker;
@@ -106,6 +128,14 @@ fn input_mapper(event: &WindowEvent) -> Option<event::Event> {
     }
 }
 
+fn screenshot_path() -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default();
+    PathBuf::from("screenshots").join(format!("{}.png", timestamp))
+}
+
 fn main() {
     env_logger::init();
 
@@ -119,91 +149,157 @@ fn main() {
     let mut renderer: Renderer<SimplePipeline> = Renderer::new(&window);
 
     let mut clock = timestep::TimeStep::new();
-    let mut game = game::Game::new(Arc::clone(&renderer.display.device));
-    let mut scene_manager = SceneManager::new(&renderer.display);
+    let resources = std::path::Path::new(env!("OUT_DIR")).join("resources");
+    let registry = Arc::new(
+        AssetRegistry::load(resources.join("assets.ron")).expect("Failed to load asset manifest"),
+    );
+    if let Err(err) = renderer.load_environment(&registry) {
+        log::error!(
+            "Failed to load the environment cubemap, lighting from the sky: {:?}",
+            err
+        );
+    }
+    let mut game = game::Game::new(Arc::clone(&renderer.display.device), &registry);
+    let mut scene_manager =
+        SceneManager::new(&renderer.display, &renderer.pipeline, Arc::clone(&registry))
+            .expect("Failed to create scene manager");
 
     let mut gui = gui::Gui::new(&window, &renderer.display);
+    let mut screenshot_requested = false;
+    let mut screenshot_target: Option<OffscreenTarget> = None;
 
     // let mut renderer = None;
 
     // let mut state = block_on(State::new(&window));
 
-    event_loop.run(move |event, _, control_flow| match event {
-        Event::NewEvents(_) => {}
-        Event::DeviceEvent { ref event, .. } => {
-            if let Some(game_event) = device_input_mapper(event) {
-                game.handle_input(&game_event);
+    event_loop.run(move |event, _, control_flow| {
+        gui.handle_event(&window, &event);
+        match event {
+            Event::NewEvents(_) => {}
+            Event::DeviceEvent { ref event, .. } => {
+                if let Some(game_event) = device_input_mapper(event) {
+                    game.handle_input(&game_event);
+                }
             }
-        }
-        Event::WindowEvent {
-            ref event,
-            window_id,
-        } if window_id == window.id() => {
-            if let Some(game_event) = input_mapper(event) {
-                game.handle_input(&game_event);
-            } else {
-                match event {
-                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                    WindowEvent::KeyboardInput { input, .. } => match input {
-                        KeyboardInput {
-                            state: ElementState::Pressed,
-                            virtual_keycode: Some(VirtualKeyCode::Escape),
-                            ..
-                        } => *control_flow = ControlFlow::Exit,
+            Event::WindowEvent {
+                ref event,
+                window_id,
+            } if window_id == window.id() => {
+                if let Some(game_event) = input_mapper(event) {
+                    game.handle_input(&game_event);
+                } else {
+                    match event {
+                        WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                        WindowEvent::KeyboardInput { input, .. } => match input {
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::Escape),
+                                ..
+                            } => *control_flow = ControlFlow::Exit,
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::F12),
+                                ..
+                            } => screenshot_requested = true,
+                            _ => {}
+                        },
+                        WindowEvent::Resized(physical_size) => {
+                            renderer.resize(*physical_size);
+                        }
+                        WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                            renderer.resize(**new_inner_size);
+                            gui.rescale(&window, &renderer.display);
+                        }
                         _ => {}
-                    },
-                    WindowEvent::Resized(_) => {
-                        // state.resize(*physical_size);
-                        // TODO: ADD RENDERER HANDLING
-                    }
-                    WindowEvent::ScaleFactorChanged { .. } => {
-                        // state.resize(**new_inner_size)
-                        // TODO: ADD RENDERER HANDLING
                     }
-                    _ => {}
                 }
             }
-        }
-        Event::RedrawRequested(_) => {
-            let current_time = Instant::now();
-            let dt = current_time.duration_since(clock.last_time);
-            clock.delta();
-            let fps = clock.frame_rate;
-            let frame = renderer
-                .display
-                .swap_chain
-                .get_current_frame()
-                .unwrap()
-                .output;
-            let mut encoder =
-                renderer
+            Event::RedrawRequested(_) => {
+                let current_time = Instant::now();
+                let dt = current_time.duration_since(clock.last_time);
+                clock.delta();
+                let fps = clock.frame_rate;
+                if renderer.display.is_minimized() {
+                    return;
+                }
+                let swap_chain = renderer
                     .display
-                    .device
-                    .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    .swap_chain
+                    .as_ref()
+                    .expect("Windowed display has no swap chain");
+                let frame = match swap_chain.get_current_frame() {
+                    Ok(frame) => frame.output,
+                    // The swap chain no longer matches the surface, so it is recreated and the
+                    // frame is skipped.
+                    Err(wgpu::SwapChainError::Lost) | Err(wgpu::SwapChainError::Outdated) => {
+                        let size = renderer.display.size;
+                        renderer.resize(size);
+                        return;
+                    }
+                    Err(err) => {
+                        log::error!("Failed to acquire the next frame: {:?}", err);
+                        return;
+                    }
+                };
+                let mut encoder = renderer.display.device.create_command_encoder(
+                    &wgpu::CommandEncoderDescriptor {
                         label: Some("Render Encoder"),
-                    });
-            game.update(dt);
-            let scene =
-                scene_manager.load_scene(&game.world, &renderer.pipeline, &renderer.display);
-            if let Some(scene) = scene {
-                renderer.render(&frame, &mut encoder, scene, &game.camera());
+                    },
+                );
+                game.update(dt);
+                scene_manager.configure(renderer.settings.assets);
+                let scene =
+                    scene_manager.load_scene(&renderer.display, &game.world, &renderer.pipeline);
+                // Screenshots are of the finished frame, GUI included, so that frame is drawn
+                // offscreen and blitted onto the swap chain before it is read back.
+                let screenshot = if screenshot_requested {
+                    screenshot_requested = false;
+                    Some(match screenshot_target.take() {
+                        Some(target) if target.matches(&renderer.display) => target,
+                        _ => OffscreenTarget::new(&renderer.display),
+                    })
+                } else {
+                    None
+                };
+                let target = screenshot
+                    .as_ref()
+                    .map_or(&frame.view, |screenshot| &screenshot.view);
+                renderer.render(target, &mut encoder, &scene, &game.camera());
+                gui.render(
+                    dt,
+                    GuiState {
+                        fps: fps as u32,
+                        asset_stats: &scene_manager.stats(),
+                        render_stats: &renderer.stats,
+                        settings: &mut renderer.settings,
+                        post_process: &mut renderer.post_process,
+                        time_of_day: &mut game.time_of_day,
+                    },
+                    &window,
+                    target,
+                    &mut encoder,
+                    &renderer.display,
+                );
+                if let Some(screenshot) = &screenshot {
+                    screenshot.blit(&mut encoder, &frame.view);
+                }
+                renderer
+                    .display
+                    .queue
+                    .submit(std::iter::once(encoder.finish()));
+                if let Some(screenshot) = screenshot {
+                    let path = screenshot_path();
+                    match screenshot.save(&renderer.display, &path) {
+                        Ok(()) => log::info!("Saved screenshot to {:?}", path),
+                        Err(err) => log::error!("Failed to save screenshot: {:?}", err),
+                    }
+                    screenshot_target = Some(screenshot);
+                }
             }
-            gui.render(
-                dt,
-                fps as u32,
-                &window,
-                &frame,
-                &mut encoder,
-                &renderer.display,
-            );
-            renderer
-                .display
-                .queue
-                .submit(std::iter::once(encoder.finish()));
-        }
-        Event::MainEventsCleared => {
-            window.request_redraw();
+            Event::MainEventsCleared => {
+                window.request_redraw();
+            }
+            _ => {}
         }
-        _ => {}
     });
 }
@@ -1,15 +1,323 @@
 use crate::{bind_group, pipeline::Pipeline};
-use crate::{pipeline::PipelineBindGroupInfo, texture::Texture};
+use crate::{
+    pipeline::PipelineBindGroupInfo,
+    texture::{SamplerConfig, Texture},
+};
 use anyhow::*;
-use std::path::Path;
-use std::{fmt::Debug, sync::Arc};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+use wgpu::util::DeviceExt;
+
+static NEXT_MATERIAL_ID: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MaterialId(u32);
+
+impl MaterialId {
+    fn next() -> Self {
+        Self(NEXT_MATERIAL_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// How a material is shaded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum MaterialModel {
+    /// Blinn-Phong lighting from the MTL diffuse and specular maps.
+    #[default]
+    Phong,
+    /// Metallic-roughness shading with a Cook-Torrance BRDF and image based lighting.
+    Pbr,
+}
+
+/// Constant factors of a PBR material, multiplied with its maps. Phong materials ignore them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaterialFactors {
+    pub base_color: [f32; 4],
+    pub emissive: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    /// How much the occlusion map darkens ambient light, from 0 to 1.
+    pub occlusion_strength: f32,
+}
+
+impl Default for MaterialFactors {
+    fn default() -> Self {
+        Self {
+            base_color: [1.0; 4],
+            emissive: [0.0; 3],
+            metallic: 0.0,
+            roughness: 1.0,
+            occlusion_strength: 1.0,
+        }
+    }
+}
+
+impl MaterialFactors {
+    fn bits(&self) -> [u32; 10] {
+        let [r, g, b, a] = self.base_color;
+        let [er, eg, eb] = self.emissive;
+        [
+            r,
+            g,
+            b,
+            a,
+            er,
+            eg,
+            eb,
+            self.metallic,
+            self.roughness,
+            self.occlusion_strength,
+        ]
+        .map(f32::to_bits)
+    }
+}
+
+// Compared bitwise so materials can be cached by their description.
+impl Eq for MaterialFactors {}
+
+impl std::hash::Hash for MaterialFactors {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.bits().hash(state);
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialRaw {
+    base_color: [f32; 4],
+    /// `w` holds the material model.
+    emissive: [f32; 4],
+    /// Metallic, roughness and occlusion strength.
+    params: [f32; 4],
+}
+
+impl MaterialRaw {
+    fn new(model: MaterialModel, factors: &MaterialFactors) -> Self {
+        match model {
+            // Phong materials show their maps as they are.
+            MaterialModel::Phong => Self {
+                base_color: [1.0; 4],
+                emissive: [1.0, 1.0, 1.0, 0.0],
+                params: [0.0, 1.0, 1.0, 0.0],
+            },
+            MaterialModel::Pbr => {
+                let [r, g, b] = factors.emissive;
+                Self {
+                    base_color: factors.base_color,
+                    emissive: [r, g, b, 1.0],
+                    params: [
+                        factors.metallic,
+                        factors.roughness,
+                        factors.occlusion_strength,
+                        0.0,
+                    ],
+                }
+            }
+        }
+    }
+}
 
 pub struct Material {
+    pub id: MaterialId,
     pub name: String,
+    /// The base colour of PBR materials. Always a texture array, with a layer per vertex layer
+    /// index the meshes of the material use.
     pub diffuse_texture: Texture,
+    /// Tangent space normals. Flat when the material has no normal map.
+    pub normal_texture: Texture,
+    /// Specular strength in the red channel for Phong materials. PBR materials keep roughness
+    /// in the green channel and metallic in the blue one instead. White when there is no map.
+    pub specular_texture: Texture,
+    /// Light emitted by the surface. Black for Phong materials without an emissive map, and
+    /// white for PBR ones so the emissive factor shows.
+    pub emissive_texture: Texture,
+    /// Ambient occlusion in the red channel. White when the material has no occlusion map.
+    pub occlusion_texture: Texture,
+    _uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
+/// Everything a material is made of: the paths of its textures, its model, its factors and how
+/// its textures are sampled. The default is a plain white Phong material.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct MaterialDesc {
+    pub model: MaterialModel,
+    pub factors: MaterialFactors,
+    pub diffuse: Option<PathBuf>,
+    /// The layers of a diffuse texture array, for materials made of several images such as the
+    /// block material. When there are any, they take the place of `diffuse`.
+    pub diffuse_layers: Vec<PathBuf>,
+    pub normal: Option<PathBuf>,
+    /// The specular map of Phong materials, or the metallic-roughness map of PBR ones.
+    pub specular: Option<PathBuf>,
+    pub emissive: Option<PathBuf>,
+    pub occlusion: Option<PathBuf>,
+    /// The model file and material index of a material whose textures are embedded in the
+    /// file and have no paths of their own. The default material of a file has no index.
+    pub embedded: Option<(PathBuf, Option<usize>)>,
+    pub sampler: SamplerConfig,
+}
+
+impl MaterialDesc {
+    /// Reads the maps of an MTL material. Emissive maps (`map_Ke`) are not parsed by tobj and
+    /// are looked up among the unknown parameters.
+    ///
+    /// Materials using the PBR extension of the format (`Pr`, `Pm` and their maps) get the PBR
+    /// model, and the others stay Phong. Roughness and metallic maps are expected to be one
+    /// packed texture, with roughness in green and metallic in blue.
+    pub fn from_mtl<F: AsRef<Path>>(material: &tobj::Material, containing_folder: F) -> Self {
+        let folder = containing_folder.as_ref();
+        let map = |texture: &str| {
+            if texture.is_empty() {
+                None
+            } else {
+                Some(folder.join(texture))
+            }
+        };
+        let param = |key: &str| material.unknown_param.get(key);
+        let scalar = |key: &str| param(key).and_then(|value| value.trim().parse::<f32>().ok());
+        let emissive = param("map_Ke").and_then(|texture| map(texture));
+
+        let pbr = ["Pr", "Pm", "map_Pr", "map_Pm"]
+            .iter()
+            .any(|key| param(key).is_some());
+        if !pbr {
+            return Self {
+                diffuse: map(&material.diffuse_texture),
+                normal: map(&material.normal_texture),
+                specular: map(&material.specular_texture),
+                emissive,
+                ..Default::default()
+            };
+        }
+
+        let metallic_roughness = param("map_Pr").or_else(|| param("map_Pm"));
+        if let (Some(roughness), Some(metallic)) = (param("map_Pr"), param("map_Pm")) {
+            if roughness != metallic {
+                log::warn!(
+                    "Material {:?} has separate roughness and metallic maps, only {:?} is used",
+                    material.name,
+                    roughness
+                );
+            }
+        }
+        let emissive_factor = param("Ke")
+            .map(|value| {
+                let mut channels = value
+                    .split_whitespace()
+                    .filter_map(|channel| channel.parse::<f32>().ok());
+                let r = channels.next().unwrap_or(0.0);
+                [
+                    r,
+                    channels.next().unwrap_or(r),
+                    channels.next().unwrap_or(r),
+                ]
+            })
+            .unwrap_or(if emissive.is_some() {
+                [1.0; 3]
+            } else {
+                [0.0; 3]
+            });
+        let [r, g, b] = if material.diffuse_texture.is_empty() {
+            material.diffuse
+        } else {
+            [1.0; 3]
+        };
+        let defaults = MaterialFactors::default();
+        Self {
+            model: MaterialModel::Pbr,
+            factors: MaterialFactors {
+                base_color: [r, g, b, material.dissolve],
+                emissive: emissive_factor,
+                metallic: scalar("Pm").unwrap_or(defaults.metallic),
+                roughness: scalar("Pr").unwrap_or(defaults.roughness),
+                occlusion_strength: defaults.occlusion_strength,
+            },
+            diffuse: map(&material.diffuse_texture),
+            normal: map(&material.normal_texture),
+            specular: metallic_roughness.and_then(|texture| map(texture)),
+            emissive,
+            occlusion: param("map_ao").and_then(|texture| map(texture)),
+            ..Default::default()
+        }
+    }
+}
+
+/// Decoded images of a material, for model formats that embed their textures. Missing maps
+/// fall back to the same defaults as materials loaded from files, and a missing diffuse
+/// texture to white.
+#[derive(Default)]
+pub struct MaterialImages {
+    /// The layers of the diffuse texture array, which all have to be the same size. Materials
+    /// usually have one, and the block material one per block texture.
+    pub diffuse: Vec<image::DynamicImage>,
+    pub normal: Option<image::DynamicImage>,
+    pub specular: Option<image::DynamicImage>,
+    pub emissive: Option<image::DynamicImage>,
+    pub occlusion: Option<image::DynamicImage>,
+}
+
+/// Materials keyed by their description, shared between every model that uses the same images
+/// and factors. Entries stay alive for as long as a model holds on to them.
+pub struct MaterialCache {
+    materials: Mutex<HashMap<MaterialDesc, Arc<Material>>>,
+}
+
+impl MaterialCache {
+    pub fn new() -> Self {
+        Self {
+            materials: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get_or_load<F: FnOnce() -> Result<Material>>(
+        &self,
+        desc: &MaterialDesc,
+        load: F,
+    ) -> Result<Arc<Material>> {
+        if let Some(material) = self.materials.lock().unwrap().get(desc) {
+            return Ok(Arc::clone(material));
+        }
+
+        // The lock is released while decoding so workers can load different materials in
+        // parallel. If two workers race on the same material the first insert wins.
+        let material = Arc::new(load()?);
+        let mut materials = self.materials.lock().unwrap();
+        Ok(Arc::clone(
+            materials.entry(desc.clone()).or_insert(material),
+        ))
+    }
+
+    /// Drops materials that are no longer referenced by any model.
+    pub fn purge(&self) {
+        self.materials
+            .lock()
+            .unwrap()
+            .retain(|_, material| Arc::strong_count(material) > 1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.lock().unwrap().len()
+    }
+
+    pub fn memory_usage(&self) -> u64 {
+        self.materials
+            .lock()
+            .unwrap()
+            .values()
+            .map(|material| material.memory_usage())
+            .sum()
+    }
+}
+
 impl bind_group::BindGroup for Material {
     fn desc<'a>() -> wgpu::BindGroupLayoutDescriptor<'a> {
         wgpu::BindGroupLayoutDescriptor {
@@ -19,8 +327,8 @@ impl bind_group::BindGroup for Material {
                     binding: 0,
                     visibility: wgpu::ShaderStage::FRAGMENT,
                     ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
                         multisampled: false,
                     },
                     count: None,
@@ -30,7 +338,93 @@ impl bind_group::BindGroup for Material {
                     visibility: wgpu::ShaderStage::FRAGMENT,
                     ty: wgpu::BindingType::Sampler {
                         comparison: false,
-                        filtering: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 9,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
                     },
                     count: None,
                 },
@@ -44,47 +438,122 @@ impl bind_group::BindGroup for Material {
 }
 
 impl Material {
-    pub fn load<F: AsRef<Path>>(
+    pub fn memory_usage(&self) -> u64 {
+        self.diffuse_texture.memory_usage()
+            + self.normal_texture.memory_usage()
+            + self.specular_texture.memory_usage()
+            + self.emissive_texture.memory_usage()
+            + self.occlusion_texture.memory_usage()
+    }
+
+    pub fn load(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        material: tobj::Material,
+        name: String,
         bind_group_info: Option<Arc<PipelineBindGroupInfo>>,
-        containing_folder: F,
-    ) -> Result<Self> {
-        let diffuse_path = material.diffuse_texture;
-
-        let path = containing_folder.as_ref().join(diffuse_path);
-        let diffuse_texture = Texture::load(device, queue, path)?;
+        desc: &MaterialDesc,
+        cache: &MaterialCache,
+    ) -> Result<Arc<Self>> {
+        cache.get_or_load(desc, || {
+            Self::create(device, queue, name, bind_group_info, desc)
+        })
+    }
 
-        let bind_group = bind_group_info.map(|info| {
-            device.create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: &info.layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                    },
-                ],
-                label: None,
+    fn create(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        name: String,
+        bind_group_info: Option<Arc<PipelineBindGroupInfo>>,
+        desc: &MaterialDesc,
+    ) -> Result<Self> {
+        // A texture that cannot be read leaves the material usable, with a checker pattern in
+        // place of the diffuse texture and the defaults in place of the other maps. The layers
+        // of a texture array have to be the same size, so a missing layer fails the material.
+        let open = |path: &Option<PathBuf>, label: &str| {
+            path.as_ref().and_then(|path| {
+                image::open(path)
+                    .map_err(|err| {
+                        log::warn!("Failed to load {} {:?} of {:?}: {}", label, path, name, err);
+                    })
+                    .ok()
             })
-        });
-        Ok(Self {
-            name: material.name,
-            diffuse_texture,
-            bind_group: bind_group.unwrap(),
-        })
+        };
+        let diffuse = if desc.diffuse_layers.is_empty() {
+            match open(&desc.diffuse, "diffuse texture") {
+                Some(image) => vec![image],
+                None if desc.diffuse.is_some() => vec![checker_image()],
+                None => Vec::new(),
+            }
+        } else {
+            desc.diffuse_layers
+                .iter()
+                .map(|path| {
+                    image::open(path)
+                        .with_context(|| format!("Unable to load layer {:?} of {:?}", path, name))
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        let images = MaterialImages {
+            diffuse,
+            normal: open(&desc.normal, "normal map"),
+            specular: open(&desc.specular, "specular map"),
+            emissive: open(&desc.emissive, "emissive map"),
+            occlusion: open(&desc.occlusion, "occlusion map"),
+        };
+        Self::from_images(device, queue, name, bind_group_info, desc, &images)
     }
-    pub fn load_from_texture<F: AsRef<Path> + Debug>(
+
+    /// Creates a material from decoded images, such as the ones embedded in a glTF file. The
+    /// paths in `desc` are ignored.
+    pub fn from_images(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        name: String,
         bind_group_info: Option<Arc<PipelineBindGroupInfo>>,
-        texture_path: F,
+        desc: &MaterialDesc,
+        images: &MaterialImages,
     ) -> Result<Self> {
-        let diffuse_texture = Texture::load(device, queue, texture_path)?;
+        let model = desc.model;
+        let texture = |image: &Option<image::DynamicImage>,
+                       default: [u8; 4],
+                       linear: bool,
+                       label: &str| {
+            let label = format!("{} {}", name, label);
+            match image {
+                Some(image) => {
+                    Texture::from_image(device, queue, image, Some(&label), linear, &desc.sampler)
+                }
+                None => Texture::solid(device, queue, default, linear, &label),
+            }
+        };
+        let white = [image::DynamicImage::ImageRgba8(
+            image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])),
+        )];
+        let diffuse_texture = Texture::from_layers(
+            device,
+            queue,
+            if images.diffuse.is_empty() {
+                &white
+            } else {
+                &images.diffuse
+            },
+            Some(&format!("{} diffuse texture", name)),
+            false,
+            &desc.sampler,
+        )?;
+        let normal_texture = texture(&images.normal, [128, 128, 255, 255], true, "normal map")?;
+        let specular_texture = texture(&images.specular, [255; 4], true, "specular map")?;
+        let no_emission = match model {
+            MaterialModel::Phong => [0, 0, 0, 255],
+            MaterialModel::Pbr => [255; 4],
+        };
+        let emissive_texture = texture(&images.emissive, no_emission, false, "emissive map")?;
+        let occlusion_texture = texture(&images.occlusion, [255; 4], true, "occlusion map")?;
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Material Buffer", name)),
+            contents: bytemuck::cast_slice(&[MaterialRaw::new(model, &desc.factors)]),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
 
         let bind_group = bind_group_info.map(|info| {
             device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -98,14 +567,69 @@ impl Material {
                         binding: 1,
                         resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
                     },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::TextureView(&specular_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: wgpu::BindingResource::Sampler(&specular_texture.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 6,
+                        resource: wgpu::BindingResource::TextureView(&emissive_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: wgpu::BindingResource::Sampler(&emissive_texture.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 8,
+                        resource: wgpu::BindingResource::TextureView(&occlusion_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 9,
+                        resource: wgpu::BindingResource::Sampler(&occlusion_texture.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 10,
+                        resource: uniform_buffer.as_entire_binding(),
+                    },
                 ],
                 label: None,
             })
         });
         Ok(Self {
-            name: "DynamicLoad".to_string(),
+            id: MaterialId::next(),
+            name,
             diffuse_texture,
+            normal_texture,
+            specular_texture,
+            emissive_texture,
+            occlusion_texture,
+            _uniform_buffer: uniform_buffer,
             bind_group: bind_group.unwrap(),
         })
     }
 }
+
+/// Stands in for diffuse textures that cannot be read, so they are easy to spot.
+fn checker_image() -> image::DynamicImage {
+    const SIZE: u32 = 64;
+    const SQUARE: u32 = 8;
+    image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+        if (x / SQUARE + y / SQUARE).is_multiple_of(2) {
+            image::Rgba([255, 0, 255, 255])
+        } else {
+            image::Rgba([0, 0, 0, 255])
+        }
+    }))
+}
@@ -1,4 +1,112 @@
+use cgmath::{InnerSpace, Matrix};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Boundary<T> {
     pub(crate) lower: cgmath::Vector3<T>,
     pub(crate) upper: cgmath::Vector3<T>,
 }
+
+impl Boundary<f32> {
+    pub fn new(lower: cgmath::Vector3<f32>, upper: cgmath::Vector3<f32>) -> Self {
+        Self { lower, upper }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = [f32; 3]>>(points: I) -> Self {
+        let mut points = points.into_iter();
+        let first = match points.next() {
+            Some(point) => cgmath::Vector3::from(point),
+            None => {
+                let origin = cgmath::Vector3::new(0.0, 0.0, 0.0);
+                return Self::new(origin, origin);
+            }
+        };
+        points.fold(Self::new(first, first), |bounds, point| {
+            bounds.union(&Self::new(point.into(), point.into()))
+        })
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            lower: cgmath::Vector3::new(
+                self.lower.x.min(other.lower.x),
+                self.lower.y.min(other.lower.y),
+                self.lower.z.min(other.lower.z),
+            ),
+            upper: cgmath::Vector3::new(
+                self.upper.x.max(other.upper.x),
+                self.upper.y.max(other.upper.y),
+                self.upper.z.max(other.upper.z),
+            ),
+        }
+    }
+
+    pub fn corners(&self) -> [cgmath::Vector3<f32>; 8] {
+        let (l, u) = (self.lower, self.upper);
+        [
+            cgmath::Vector3::new(l.x, l.y, l.z),
+            cgmath::Vector3::new(u.x, l.y, l.z),
+            cgmath::Vector3::new(l.x, u.y, l.z),
+            cgmath::Vector3::new(u.x, u.y, l.z),
+            cgmath::Vector3::new(l.x, l.y, u.z),
+            cgmath::Vector3::new(u.x, l.y, u.z),
+            cgmath::Vector3::new(l.x, u.y, u.z),
+            cgmath::Vector3::new(u.x, u.y, u.z),
+        ]
+    }
+
+    /// Axis aligned bounds enclosing this box after it has been transformed by `matrix`.
+    pub fn transform(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        Self::from_points(self.corners().iter().map(|corner| {
+            let point = matrix * corner.extend(1.0);
+            [point.x, point.y, point.z]
+        }))
+    }
+}
+
+/// The six clip planes of a view projection matrix, pointing inwards.
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    planes: [cgmath::Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from a wgpu style projection, where clip space depth runs from 0 to w.
+    pub fn from_matrix(view_projection: &cgmath::Matrix4<f32>) -> Self {
+        let row = |i| view_projection.row(i);
+        let mut planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            row(2),
+            row(3) - row(2),
+        ];
+        for plane in planes.iter_mut() {
+            *plane /= plane.truncate().magnitude();
+        }
+        Self { planes }
+    }
+
+    pub fn planes(&self) -> &[cgmath::Vector4<f32>; 6] {
+        &self.planes
+    }
+
+    pub fn intersects(&self, bounds: &Boundary<f32>) -> bool {
+        // Only the corner furthest along each plane normal has to be tested.
+        let furthest = |direction: f32, lower: f32, upper: f32| {
+            if direction >= 0.0 {
+                upper
+            } else {
+                lower
+            }
+        };
+        self.planes.iter().all(|plane| {
+            let corner = cgmath::Vector3::new(
+                furthest(plane.x, bounds.lower.x, bounds.upper.x),
+                furthest(plane.y, bounds.lower.y, bounds.upper.y),
+                furthest(plane.z, bounds.lower.z, bounds.upper.z),
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
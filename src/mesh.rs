@@ -1,12 +1,22 @@
 use crate::{
-    display::Display,
-    instance::InstanceRaw,
-    material::Material,
+    asset::MaterialOverride,
+    material::{Material, MaterialCache, MaterialDesc},
+    math::Boundary,
     pipeline::{Pipeline, PipelineBindGroupInfo},
 };
 use anyhow::*;
-use std::{fmt::Debug, ops::Range};
-use std::{path::Path, sync::Arc};
+use serde::Deserialize;
+use std::fmt::Debug;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
 use wgpu::util::DeviceExt;
 
 pub trait Vertex {
@@ -19,6 +29,10 @@ pub struct MeshVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
+    /// Points along increasing `u`, with the handedness of the bitangent in `w`.
+    pub tangent: [f32; 4],
+    /// Layer of the diffuse texture array to sample.
+    pub layer: u32,
 }
 
 impl Vertex for MeshVertex {
@@ -42,219 +56,535 @@ impl Vertex for MeshVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float3,
                 },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Uint,
+                },
             ],
         }
     }
 }
 
+/// How normals are generated for meshes that come without them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum GeneratedNormals {
+    /// Averaged over the triangles sharing a vertex.
+    #[default]
+    Smooth,
+    /// Facing away from each triangle, which gives every triangle vertices of its own.
+    Flat,
+}
+
+/// How an OBJ file is adjusted while it is loaded.
+#[derive(Debug, Clone, Copy)]
+pub struct ObjOptions<'a> {
+    pub material_overrides: &'a [MaterialOverride],
+    pub generated_normals: GeneratedNormals,
+}
+
+static NEXT_MESH_ID: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MeshBufferId(u32);
+
+impl MeshBufferId {
+    fn next() -> Self {
+        Self(NEXT_MESH_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 pub struct Mesh {
+    pub id: MeshBufferId,
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
 
+    pub num_vertices: u32,
     pub num_elements: u32,
     pub material: usize,
+    pub bounds: Boundary<f32>,
+}
+
+impl Mesh {
+    pub fn new(
+        device: &wgpu::Device,
+        name: String,
+        vertices: &[MeshVertex],
+        indices: &[u32],
+        material: usize,
+    ) -> Self {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Vertex Buffer", name)),
+            contents: bytemuck::cast_slice(vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Index Buffer", name)),
+            contents: bytemuck::cast_slice(indices),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+
+        Self {
+            id: MeshBufferId::next(),
+            name,
+            vertex_buffer,
+            index_buffer,
+            num_vertices: vertices.len() as u32,
+            num_elements: indices.len() as u32,
+            material,
+            bounds: Boundary::from_points(vertices.iter().map(|vertex| vertex.position)),
+        }
+    }
+
+    pub fn memory_usage(&self) -> u64 {
+        (self.num_vertices as usize * std::mem::size_of::<MeshVertex>()
+            + self.num_elements as usize * std::mem::size_of::<u32>()) as u64
+    }
 }
 
 pub struct Model {
     pub meshes: Vec<Mesh>,
-    pub materials: Vec<Material>,
+    pub materials: Vec<Arc<Material>>,
     pub name: String,
-    pub instance_buffer: wgpu::Buffer,
+    pub bounds: Boundary<f32>,
 }
 
 impl Model {
-    pub fn load_instance_buffers(&self, display: &Display, instance_data: Vec<InstanceRaw>) {
-        display.queue.write_buffer(
-            &self.instance_buffer,
-            0,
-            bytemuck::cast_slice(instance_data.as_slice()),
-        );
+    pub fn new(name: String, meshes: Vec<Mesh>, materials: Vec<Arc<Material>>) -> Self {
+        Self {
+            bounds: model_bounds(&meshes),
+            meshes,
+            materials,
+            name,
+        }
+    }
+
+    /// Materials are shared through the `MaterialCache` and are accounted for there.
+    pub fn memory_usage(&self) -> u64 {
+        self.meshes.iter().map(Mesh::memory_usage).sum()
     }
 
+    /// Loads an OBJ file. Meshes without texture coordinates get zeroed ones, and meshes
+    /// without normals get the `generated_normals` of `options`. Meshes without a material,
+    /// including every mesh when the MTL library cannot be read, use a plain white default
+    /// material.
     pub fn load<F: AsRef<Path> + Debug>(
         name: String,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         bind_group_info: Option<Arc<PipelineBindGroupInfo>>,
         file_path: F,
+        options: ObjOptions,
+        material_cache: &MaterialCache,
     ) -> Result<Self> {
-        let (obj_models, obj_materials) = tobj::load_obj(file_path.as_ref(), true)?;
-        let containing_folder = file_path
-            .as_ref()
-            .parent()
-            .context("Directory has no parent")?;
+        let ObjOptions {
+            material_overrides,
+            generated_normals,
+        } = options;
+        let path = file_path.as_ref();
+        let (obj_models, obj_materials) = load_obj(path)?;
+        let containing_folder = path.parent().context("Directory has no parent")?;
         let mut materials = Vec::new();
-        let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Instance Buffer"),
-            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
-            size: (std::mem::size_of::<InstanceRaw>() * 100) as u64,
-            mapped_at_creation: false,
-        });
-        for mat in obj_materials {
+        for mut mat in obj_materials {
+            let material_override = material_overrides
+                .iter()
+                .find(|material_override| material_override.material == mat.name);
+            if let Some(diffuse_texture) = material_override
+                .and_then(|material_override| material_override.diffuse_texture.clone())
+            {
+                mat.diffuse_texture = diffuse_texture;
+            }
+            let mut desc = MaterialDesc::from_mtl(&mat, containing_folder);
+            if let Some(material_override) = material_override {
+                if let Some(model) = material_override.model {
+                    desc.model = model;
+                }
+                if let Some(metallic) = material_override.metallic {
+                    desc.factors.metallic = metallic;
+                }
+                if let Some(roughness) = material_override.roughness {
+                    desc.factors.roughness = roughness;
+                }
+                if let Some(sampler) = material_override.sampler {
+                    desc.sampler = sampler;
+                }
+            }
             let material = Material::load(
                 device,
                 queue,
-                mat,
+                mat.name,
                 bind_group_info.clone(),
-                containing_folder,
+                &desc,
+                material_cache,
             );
 
             materials.push(material?);
         }
+        let default_material = materials.len();
+        let mut uses_default_material = false;
 
         let mut meshes = Vec::new();
         for m in obj_models {
-            let mut vertices = Vec::new();
-            for i in 0..m.mesh.positions.len() / 3 {
-                vertices.push(MeshVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ],
-                    tex_coords: [m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
-                })
-            }
+            let (mut vertices, indices) = obj_vertices(&m, path, generated_normals);
+            compute_tangents(&mut vertices, &indices);
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", file_path.as_ref())),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsage::VERTEX,
-            });
-
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_path.as_ref())),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsage::INDEX,
-            });
-
-            meshes.push(Mesh {
-                name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
-            })
+            let material = match m.mesh.material_id {
+                Some(material) if material < default_material => material,
+                _ => {
+                    log::warn!(
+                        "Mesh {:?} of {:?} has no material, using the default one",
+                        m.name,
+                        path
+                    );
+                    uses_default_material = true;
+                    default_material
+                }
+            };
+            meshes.push(Mesh::new(device, m.name, &vertices, &indices, material));
         }
 
-        Ok(Self {
-            meshes,
-            materials,
-            name,
-            instance_buffer,
+        if uses_default_material {
+            materials.push(Material::load(
+                device,
+                queue,
+                "default".to_string(),
+                bind_group_info,
+                &MaterialDesc::default(),
+                material_cache,
+            )?);
+        }
+
+        Ok(Self::new(name, meshes, materials))
+    }
+}
+
+/// Reads an OBJ file and its MTL libraries. A library that cannot be read only loses its
+/// materials, with a warning.
+fn load_obj(path: &Path) -> Result<(Vec<tobj::Model>, Vec<tobj::Material>)> {
+    let file = File::open(path).with_context(|| format!("Unable to open {:?}", path))?;
+    read_obj(&mut BufReader::new(file), |library| {
+        tobj::load_mtl(
+            path.parent()
+                .map_or(library.to_path_buf(), |folder| folder.join(library)),
+        )
+    })
+    .with_context(|| format!("Unable to parse {:?}", path))
+}
+
+fn read_obj<B: BufRead, L: Fn(&Path) -> tobj::MTLLoadResult>(
+    reader: &mut B,
+    load_library: L,
+) -> Result<(Vec<tobj::Model>, Vec<tobj::Material>)> {
+    Ok(tobj::load_obj_buf(reader, true, |library| {
+        load_library(library).or_else(|err| {
+            log::warn!("Unable to load material library {:?}: {}", library, err);
+            std::result::Result::Ok((Vec::new(), HashMap::new()))
         })
+    })?)
+}
+
+/// Builds the vertices and indices of an OBJ mesh, filling in what the file leaves out.
+fn obj_vertices(
+    model: &tobj::Model,
+    path: &Path,
+    generated_normals: GeneratedNormals,
+) -> (Vec<MeshVertex>, Vec<u32>) {
+    let mesh = &model.mesh;
+    let count = mesh.positions.len() / 3;
+    // tobj leaves an attribute out, or only fills it for some vertices, when faces lack it.
+    let has_tex_coords = mesh.texcoords.len() == count * 2;
+    let has_normals = mesh.normals.len() == count * 3;
+    if !has_tex_coords {
+        log::warn!(
+            "Mesh {:?} of {:?} has no texture coordinates, using zero",
+            model.name,
+            path
+        );
+    }
+    if !has_normals {
+        log::warn!(
+            "Mesh {:?} of {:?} has no normals, generating {:?} normals",
+            model.name,
+            path,
+            generated_normals
+        );
     }
-    pub fn load_from_vertex_data<F: AsRef<Path> + Debug>(
-        name: String,
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        bind_group_info: Option<Arc<PipelineBindGroupInfo>>,
-        vertex_data: &[MeshVertex],
-        index_data: &[u32],
-        texture_path: F,
-    ) -> Result<Self> {
-        let mut materials = Vec::new();
-        materials.push(Material::load_from_texture(
-            device,
-            queue,
-            bind_group_info,
-            texture_path,
-        )?);
-        let mut meshes = Vec::new();
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(&format!("{:?} Vertex Buffer", name)),
-            contents: bytemuck::cast_slice(vertex_data),
-            usage: wgpu::BufferUsage::VERTEX,
-        });
 
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(&format!("{:?} Index Buffer", name)),
-            contents: bytemuck::cast_slice(index_data),
-            usage: wgpu::BufferUsage::INDEX,
-        });
+    let vertices = (0..count)
+        .map(|i| MeshVertex {
+            position: [
+                mesh.positions[i * 3],
+                mesh.positions[i * 3 + 1],
+                mesh.positions[i * 3 + 2],
+            ],
+            tex_coords: if has_tex_coords {
+                [mesh.texcoords[i * 2], 1.0 - mesh.texcoords[i * 2 + 1]]
+            } else {
+                [0.0; 2]
+            },
+            normal: if has_normals {
+                [
+                    mesh.normals[i * 3],
+                    mesh.normals[i * 3 + 1],
+                    mesh.normals[i * 3 + 2],
+                ]
+            } else {
+                [0.0; 3]
+            },
+            tangent: [0.0; 4],
+            layer: 0,
+        })
+        .collect::<Vec<_>>();
 
-        let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Instance Buffer"),
-            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
-            size: (std::mem::size_of::<InstanceRaw>()) as u64,
-            mapped_at_creation: false,
-        });
+    match (has_normals, generated_normals) {
+        (true, _) => (vertices, mesh.indices.clone()),
+        (false, GeneratedNormals::Smooth) => {
+            let mut vertices = vertices;
+            smooth_normals(&mut vertices, &mesh.indices);
+            (vertices, mesh.indices.clone())
+        }
+        (false, GeneratedNormals::Flat) => flat_normals(&vertices, &mesh.indices),
+    }
+}
 
-        meshes.push(Mesh {
-            name: format!("Mesh {:?}", name),
-            vertex_buffer,
-            index_buffer,
-            num_elements: index_data.len() as u32,
-            material: 0,
-        });
+/// Sets the normal of every vertex to the average of the triangles around it, weighted by
+/// their area.
+pub fn smooth_normals(vertices: &mut [MeshVertex], indices: &[u32]) {
+    use cgmath::{InnerSpace, Vector3};
 
-        println!("Loaded mesh with {:?} indexes", index_data.len());
+    let mut normals = vec![Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [
+            triangle[0] as usize,
+            triangle[1] as usize,
+            triangle[2] as usize,
+        ];
+        let position = |i: usize| Vector3::from(vertices[i].position);
+        let normal = (position(b) - position(a)).cross(position(c) - position(a));
+        for &i in &[a, b, c] {
+            normals[i] += normal;
+        }
+    }
+    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+        vertex.normal = if normal.magnitude2() > f32::EPSILON {
+            normal.normalize().into()
+        } else {
+            [0.0, 1.0, 0.0]
+        };
+    }
+}
 
-        Ok(Self {
-            meshes,
-            materials,
-            name,
-            instance_buffer,
-        })
+/// Gives every triangle vertices of its own, with normals facing away from the triangle.
+/// Returns the new vertices and indices.
+pub fn flat_normals(vertices: &[MeshVertex], indices: &[u32]) -> (Vec<MeshVertex>, Vec<u32>) {
+    use cgmath::{InnerSpace, Vector3};
+
+    let mut flat = Vec::with_capacity(indices.len());
+    for triangle in indices.chunks_exact(3) {
+        let corners = [
+            vertices[triangle[0] as usize],
+            vertices[triangle[1] as usize],
+            vertices[triangle[2] as usize],
+        ];
+        let position = |i: usize| Vector3::from(corners[i].position);
+        let normal = (position(1) - position(0)).cross(position(2) - position(0));
+        let normal = if normal.magnitude2() > f32::EPSILON {
+            normal.normalize().into()
+        } else {
+            [0.0, 1.0, 0.0]
+        };
+        for &corner in &corners {
+            flat.push(MeshVertex { normal, ..corner });
+        }
     }
+    let indices = (0..flat.len() as u32).collect();
+    (flat, indices)
 }
 
-pub trait DrawModel<'a, 'b>
-where
-    'b: 'a,
-{
-    fn draw_model_instanced(
-        &mut self,
-        model: &'b Model,
-        instances: Range<u32>,
-        bind_material: bool,
-    );
-    fn draw_mesh_instanced(
-        &mut self,
-        mesh: &'b Mesh,
-        material: &'b Material,
-        instances: Range<u32>,
-        bind_material: bool,
-    );
+/// Fills in the tangents of indexed triangles from their positions and texture coordinates.
+/// Tangents of shared vertices are averaged, then made orthogonal to the vertex normal.
+pub fn compute_tangents(vertices: &mut [MeshVertex], indices: &[u32]) {
+    use cgmath::{InnerSpace, Vector2, Vector3};
+
+    let mut tangents = vec![Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    let mut bitangents = vec![Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [
+            triangle[0] as usize,
+            triangle[1] as usize,
+            triangle[2] as usize,
+        ];
+        let position = |i: usize| Vector3::from(vertices[i].position);
+        let uv = |i: usize| Vector2::from(vertices[i].tex_coords);
+
+        let edge1 = position(b) - position(a);
+        let edge2 = position(c) - position(a);
+        let delta1 = uv(b) - uv(a);
+        let delta2 = uv(c) - uv(a);
+        let determinant = delta1.x * delta2.y - delta2.x * delta1.y;
+        if determinant.abs() < f32::EPSILON {
+            continue;
+        }
+        let r = 1.0 / determinant;
+        let tangent = (edge1 * delta2.y - edge2 * delta1.y) * r;
+        let bitangent = (edge2 * delta1.x - edge1 * delta2.x) * r;
+        for &i in &[a, b, c] {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+
+    for (i, vertex) in vertices.iter_mut().enumerate() {
+        let normal = Vector3::from(vertex.normal);
+        let tangent = tangents[i] - normal * normal.dot(tangents[i]);
+        let tangent = if tangent.magnitude2() > f32::EPSILON {
+            tangent.normalize()
+        } else {
+            // Degenerate texture coordinates, so any direction across the normal will do.
+            let axis = if normal.x.abs() < 0.9 {
+                Vector3::unit_x()
+            } else {
+                Vector3::unit_y()
+            };
+            normal.cross(axis).normalize()
+        };
+        let handedness = if normal.cross(tangent).dot(bitangents[i]) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+        vertex.tangent = [tangent.x, tangent.y, tangent.z, handedness];
+    }
 }
 
-impl<'a, 'b> DrawModel<'a, 'b> for wgpu::RenderPass<'a>
-where
-    'b: 'a,
-{
-    fn draw_mesh_instanced(
-        &mut self,
-        mesh: &'b Mesh,
-        material: &'b Material,
-        instances: Range<u32>,
-        bind_material: bool,
-    ) {
-        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-        if bind_material {
-            self.set_bind_group(0, &material.bind_group, &[]);
+fn model_bounds(meshes: &[Mesh]) -> Boundary<f32> {
+    meshes
+        .iter()
+        .map(|mesh| mesh.bounds)
+        .fold(None, |bounds: Option<Boundary<f32>>, mesh_bounds| {
+            Some(bounds.map_or(mesh_bounds, |bounds| bounds.union(&mesh_bounds)))
+        })
+        .unwrap_or_else(|| Boundary::from_points(std::iter::empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{MaterialDesc, MaterialModel};
+
+    const BARE_QUAD: &str = "\
+o quad
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+f 1 2 3 4
+";
+
+    const TEXTURED_TRIANGLE: &str = "\
+mtllib triangle.mtl
+o triangle
+v 0 0 0
+v 1 0 0
+v 0 1 0
+vt 0 0.25
+vt 1 0.25
+vt 0 1
+vn 0 0 1
+usemtl plain
+f 1/1/1 2/2/1 3/3/1
+";
+
+    const PLAIN_MTL: &str = "\
+newmtl plain
+Kd 1 1 1
+";
+
+    fn parse(source: &str) -> (Vec<tobj::Model>, Vec<tobj::Material>) {
+        read_obj(&mut source.as_bytes(), |_| {
+            tobj::load_mtl_buf(&mut PLAIN_MTL.as_bytes())
+        })
+        .unwrap()
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(&expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
         }
+    }
+
+    #[test]
+    fn missing_attributes_get_zero_uvs_and_smooth_normals() {
+        let (models, _) = parse(BARE_QUAD);
+        let (vertices, indices) =
+            obj_vertices(&models[0], Path::new("quad.obj"), GeneratedNormals::Smooth);
 
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(indices.len(), 6);
+        for vertex in &vertices {
+            assert_eq!(vertex.tex_coords, [0.0; 2]);
+            assert_close(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn flat_normals_unweld_triangles() {
+        let (models, _) = parse(BARE_QUAD);
+        let (vertices, indices) =
+            obj_vertices(&models[0], Path::new("quad.obj"), GeneratedNormals::Flat);
+
+        assert_eq!(vertices.len(), 6);
+        assert_eq!(indices, (0..6).collect::<Vec<_>>());
+        for triangle in vertices.chunks(3) {
+            for vertex in triangle {
+                assert_eq!(vertex.normal, triangle[0].normal);
+                assert_close(vertex.normal, [0.0, 0.0, 1.0]);
+            }
+        }
     }
 
-    fn draw_model_instanced(
-        &mut self,
-        model: &'b Model,
-        instances: Range<u32>,
-        bind_material: bool,
-    ) {
-        self.set_vertex_buffer(1, model.instance_buffer.slice(..));
-
-        for mesh in &model.meshes {
-            let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(mesh, material, instances.clone(), bind_material);
+    #[test]
+    fn provided_attributes_are_kept() {
+        let (models, materials) = parse(TEXTURED_TRIANGLE);
+        let (vertices, _) = obj_vertices(
+            &models[0],
+            Path::new("triangle.obj"),
+            GeneratedNormals::Flat,
+        );
+
+        assert_eq!(materials.len(), 1);
+        assert_eq!(models[0].mesh.material_id, Some(0));
+        assert_eq!(vertices.len(), 3);
+        assert_eq!(vertices[0].tex_coords, [0.0, 0.75]);
+        assert_eq!(vertices[2].tex_coords, [0.0, 0.0]);
+        for vertex in &vertices {
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
         }
     }
+
+    #[test]
+    fn missing_material_library_is_not_an_error() {
+        let (models, materials) = read_obj(&mut TEXTURED_TRIANGLE.as_bytes(), |_| {
+            Err(tobj::LoadError::OpenFileFailed)
+        })
+        .unwrap();
+
+        assert_eq!(models.len(), 1);
+        assert!(materials.is_empty());
+    }
+
+    #[test]
+    fn material_without_textures_has_no_diffuse_map() {
+        let (_, materials) = parse(TEXTURED_TRIANGLE);
+        let desc = MaterialDesc::from_mtl(&materials[0], "");
+
+        assert_eq!(desc.diffuse, None);
+        assert_eq!(desc.model, MaterialModel::Phong);
+    }
 }
@@ -0,0 +1,156 @@
+use crate::texture::Texture;
+
+/// Sample counts that can be picked in the settings. wgpu neither reports the counts an adapter
+/// supports nor validates them beyond being a power of two, so an unsupported count would only
+/// be caught by the driver. Only the counts WebGPU guarantees for every renderable format are
+/// offered; 2x and 8x can join them once wgpu can report them.
+pub const SAMPLE_COUNTS: [u32; 2] = [1, 4];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MsaaConfig {
+    /// Samples per pixel of the main pass. One turns MSAA off.
+    pub samples: u32,
+}
+
+impl Default for MsaaConfig {
+    fn default() -> Self {
+        Self { samples: 4 }
+    }
+}
+
+impl MsaaConfig {
+    /// The highest supported count that is not above the configured one.
+    pub fn sample_count(&self) -> u32 {
+        SAMPLE_COUNTS
+            .iter()
+            .copied()
+            .filter(|&count| count <= self.samples)
+            .max()
+            .unwrap_or(1)
+    }
+}
+
+/// Copies the multisampled depth of the main pass into a single sampled depth texture, since
+/// wgpu cannot resolve depth attachments itself.
+pub struct DepthResolve {
+    layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    pipeline: wgpu::RenderPipeline,
+    bind_group: Option<wgpu::BindGroup>,
+}
+
+impl DepthResolve {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("depth_resolve_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Depth Resolve Sampler"),
+            ..Default::default()
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Depth Resolve Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!(
+            "../resources/shaders/fullscreen.vert.spv"
+        ));
+        let fs_module = device.create_shader_module(&wgpu::include_spirv!(
+            "../resources/shaders/depth_resolve.frag.spv"
+        ));
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Depth Resolve Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[],
+            }),
+            primitive: Default::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: Default::default(),
+                bias: Default::default(),
+                clamp_depth: false,
+            }),
+            multisample: Default::default(),
+        });
+
+        Self {
+            layout,
+            sampler,
+            pipeline,
+            bind_group: None,
+        }
+    }
+
+    /// Sets the multisampled depth texture to read from.
+    pub fn bind(&mut self, device: &wgpu::Device, source: &Texture) {
+        self.bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("depth_resolve_bind_group"),
+            layout: &self.layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        }));
+    }
+
+    pub fn resolve(&self, encoder: &mut wgpu::CommandEncoder, target: &Texture) {
+        let bind_group = match &self.bind_group {
+            Some(bind_group) => bind_group,
+            None => return,
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Depth Resolve"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &target.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::*;
+use futures::executor::block_on;
+use std::path::Path;
+
+use crate::display::Display;
+
+/// A colour texture that frames can be rendered into instead of the swap chain, together with
+/// a buffer that its pixels are copied into when they are read back. Frames that should also be
+/// shown on screen are blitted onto the swap chain afterwards.
+pub struct OffscreenTarget {
+    texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    buffer: wgpu::Buffer,
+    blit_pipeline: wgpu::RenderPipeline,
+    blit_bind_group: wgpu::BindGroup,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+}
+
+impl OffscreenTarget {
+    /// Headless displays render straight into RGBA so read backs need no swizzling.
+    #[cfg(test)]
+    pub const HEADLESS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    /// Creates a target with the size and format of the display's swap chain, so it can be
+    /// used with pipelines created for that display.
+    pub fn new(display: &Display) -> Self {
+        let descriptor = &display.swap_chain_descriptor;
+        let (width, height) = (descriptor.width, descriptor.height);
+        let texture = display.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen Target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: descriptor.format,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT
+                | wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::COPY_SRC,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // Rows copied out of a texture have to be aligned, the padding is stripped on read.
+        let unpadded_bytes_per_row = width * 4;
+        let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(alignment) * alignment;
+        let buffer = display.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Offscreen Read Back Buffer"),
+            size: padded_bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let (blit_pipeline, blit_bind_group) = Self::create_blit(display, &view);
+
+        Self {
+            texture,
+            view,
+            buffer,
+            blit_pipeline,
+            blit_bind_group,
+            format: descriptor.format,
+            width,
+            height,
+            padded_bytes_per_row,
+        }
+    }
+
+    pub fn matches(&self, display: &Display) -> bool {
+        let descriptor = &display.swap_chain_descriptor;
+        self.width == descriptor.width
+            && self.height == descriptor.height
+            && self.format == descriptor.format
+    }
+
+    /// Records a pass that copies the target onto `output`, which has to have the same size and
+    /// format. Swap chain frames cannot be copied into, so this draws a full screen triangle.
+    pub fn blit(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Offscreen Blit Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&self.blit_pipeline);
+        render_pass.set_bind_group(0, &self.blit_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    /// Copies the target back to the CPU, blocking until the GPU has finished every frame
+    /// submitted before this call.
+    pub fn read(&self, display: &Display) -> Result<image::RgbaImage> {
+        let mut encoder = display
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Offscreen Read Back Encoder"),
+            });
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::BufferCopyView {
+                buffer: &self.buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: self.padded_bytes_per_row,
+                    rows_per_image: self.height,
+                },
+            },
+            wgpu::Extent3d {
+                width: self.width,
+                height: self.height,
+                depth: 1,
+            },
+        );
+        display.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = self.buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        display.device.poll(wgpu::Maintain::Wait);
+        block_on(mapping).context("Failed to map the offscreen read back buffer")?;
+
+        let mut pixels = Vec::with_capacity((self.width * self.height * 4) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..(self.width * 4) as usize]);
+            }
+        }
+        self.buffer.unmap();
+
+        match self.format {
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => {
+                for pixel in pixels.chunks_mut(4) {
+                    pixel.swap(0, 2);
+                }
+            }
+            wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => {}
+            format => bail!("Cannot read back offscreen target with format {:?}", format),
+        }
+
+        image::RgbaImage::from_raw(self.width, self.height, pixels)
+            .context("Offscreen read back has the wrong size")
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, display: &Display, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        self.read(display)?
+            .save(path)
+            .with_context(|| format!("Failed to save screenshot to {:?}", path))
+    }
+
+    fn create_blit(
+        display: &Display,
+        view: &wgpu::TextureView,
+    ) -> (wgpu::RenderPipeline, wgpu::BindGroup) {
+        let device = &display.device;
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("offscreen_blit_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        // The target has the size of the output, so texels map one to one onto pixels.
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Offscreen Blit Sampler"),
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("offscreen_blit_bind_group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Offscreen Blit Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!(
+            "../resources/shaders/fullscreen.vert.spv"
+        ));
+        let fs_module = device
+            .create_shader_module(&wgpu::include_spirv!("../resources/shaders/blit.frag.spv"));
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Offscreen Blit Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[display.swap_chain_descriptor.format.into()],
+            }),
+            primitive: Default::default(),
+            depth_stencil: None,
+            multisample: Default::default(),
+        });
+        (pipeline, bind_group)
+    }
+}
@@ -5,10 +5,16 @@ use wgpu::util::DeviceExt;
 use super::display::Display;
 use crate::{
     bind_group::{BindGroup, BindGroupType},
+    fog::FogRaw,
+    hdr::HDR_FORMAT,
+    ibl::{Environment, PREFILTERED_LEVELS},
     instance::InstanceRaw,
-    light::Light,
+    light::{LightBuffer, LightRaw},
     material::Material,
     mesh::{MeshVertex, Vertex},
+    msaa::DepthResolve,
+    settings::RenderSettings,
+    shadow::ShadowMap,
     texture::Texture,
 };
 
@@ -22,28 +28,52 @@ pub trait Pipeline {
     fn new(window: &Display) -> Self;
     fn update_view_projection(&mut self, projection: cgmath::Matrix4<f32>);
     fn update_view_position(&mut self, position: cgmath::Vector4<f32>);
+    fn update_fog(&mut self, fog: FogRaw);
+    /// Selects the lights shading the next frame and returns how many were kept. The ambient
+    /// light is tinted by `ambient`.
+    fn update_lights(&mut self, lights: &[LightRaw], ambient: [f32; 3]) -> usize;
+    fn configure(&mut self, display: &Display, settings: &RenderSettings);
     fn bind_group_layout(
         &self,
         bind_group_type: BindGroupType,
     ) -> Option<Arc<PipelineBindGroupInfo>>;
     fn depth_stencil_attachment(&self) -> Option<wgpu::RenderPassDepthStencilAttachmentDescriptor>;
+    /// The depth written by the main pass, which full screen effects may sample. It is single
+    /// sampled, and only up to date after `resolve_depth` when the main pass uses MSAA.
+    fn depth_texture(&self) -> &Texture;
+    /// Number of samples the main pass has to be rendered with.
+    fn sample_count(&self) -> u32;
+    fn resolve_depth(&self, encoder: &mut wgpu::CommandEncoder);
+    /// Sets the ambient occlusion applied to ambient light, or none to leave it unoccluded.
+    fn set_occlusion(&mut self, display: &Display, occlusion: Option<&wgpu::TextureView>);
+    /// The image based lighting of PBR materials.
+    fn environment_mut(&mut self) -> &mut Environment;
     fn prepare(&self, display: &Display);
+    fn resize(&mut self, display: &Display);
     fn bind<'a, 'b: 'a>(&'b self, render_pass: &mut wgpu::RenderPass<'a>);
 }
 
 pub struct SimplePipeline {
     render_pipeline: wgpu::RenderPipeline,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    sample_count: u32,
 
     uniforms: Uniforms,
     uniform_buffer: wgpu::Buffer,
     uniform_bind_group: wgpu::BindGroup,
+    uniform_bind_group_layout: wgpu::BindGroupLayout,
+    /// Bound in place of the occlusion while SSAO is off.
+    no_occlusion: Texture,
+    occlusion_sampler: wgpu::Sampler,
+    environment: Environment,
 
-    light: Light,
-    light_buffer: wgpu::Buffer,
-    light_bind_group: wgpu::BindGroup,
+    lights: LightBuffer,
 
     bind_group_layouts: HashMap<BindGroupType, Arc<PipelineBindGroupInfo>>,
     depth_texture: Texture,
+    /// The depth attachment of the main pass when it is multisampled.
+    multisampled_depth: Option<Texture>,
+    depth_resolve: DepthResolve,
 }
 
 impl Pipeline for SimplePipeline {
@@ -52,12 +82,22 @@ impl Pipeline for SimplePipeline {
             &self.uniform_buffer,
             0,
             bytemuck::cast_slice(&[self.uniforms]),
-        )
+        );
+        self.lights.write(&display.queue);
     }
     fn bind<'a, 'b: 'a>(&'b self, render_pass: &mut wgpu::RenderPass<'a>) {
         render_pass.set_pipeline(&self.render_pipeline);
         render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
-        render_pass.set_bind_group(2, &self.light_bind_group, &[]);
+        render_pass.set_bind_group(2, self.lights.bind_group(), &[]);
+    }
+    fn resize(&mut self, display: &Display) {
+        self.depth_texture = Texture::create_depth_texture(
+            &display.device,
+            &display.swap_chain_descriptor,
+            1,
+            "depth_texture",
+        );
+        self.create_multisampled_depth(display);
     }
 
     fn new(display: &Display) -> Self {
@@ -80,74 +120,105 @@ impl Pipeline for SimplePipeline {
                 .device
                 .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                     label: Some("uniform_bind_group_layout"),
-                    entries: &[wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: Default::default(),
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
+                    entries: &[
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: Default::default(),
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::Sampler {
+                                comparison: false,
+                                filtering: true,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 3,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                                view_dimension: wgpu::TextureViewDimension::Cube,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 4,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                                view_dimension: wgpu::TextureViewDimension::Cube,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 5,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 6,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::Sampler {
+                                comparison: false,
+                                filtering: true,
+                            },
+                            count: None,
                         },
-                        count: None,
-                    }],
+                    ],
                 });
 
-        let uniform_bind_group = display
-            .device
-            .create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some("uniform_bind_group"),
-                layout: &uniform_bind_group_layout,
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer {
-                        buffer: &uniform_buffer,
-                        offset: 0,
-                        size: None,
-                    },
-                }],
-            });
-
-        let light = Light::default();
-
-        let light_buffer = display
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Light VB"),
-                contents: bytemuck::cast_slice(&[light]),
-                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-            });
+        let no_occlusion = Texture::solid(
+            &display.device,
+            &display.queue,
+            [255; 4],
+            true,
+            "no_occlusion_texture",
+        )
+        .expect("Failed to create the default occlusion texture");
+        let occlusion_sampler = display.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Occlusion Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let environment = Environment::new(display);
+        let uniform_bind_group = Self::create_uniform_bind_group(
+            &display.device,
+            &uniform_bind_group_layout,
+            &uniform_buffer,
+            &no_occlusion.view,
+            &occlusion_sampler,
+            &environment,
+        );
 
-        let light_bind_group_layout =
-            display
-                .device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    }],
-                });
+        let lights = LightBuffer::new(&display.device, Default::default());
 
-        let light_bind_group = display
-            .device
-            .create_bind_group(&wgpu::BindGroupDescriptor {
-                label: None,
-                layout: &light_bind_group_layout,
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer {
-                        buffer: &light_buffer,
-                        offset: 0,
-                        size: None,
-                    },
-                }],
-            });
+        let shadow_bind_group_layout = display.device.create_bind_group_layout(&ShadowMap::desc());
 
         let render_pipeline_layout =
             display
@@ -157,7 +228,8 @@ impl Pipeline for SimplePipeline {
                     bind_group_layouts: &[
                         &texture_bind_group_layout,
                         &uniform_bind_group_layout,
-                        &light_bind_group_layout,
+                        lights.layout(),
+                        &shadow_bind_group_layout,
                     ],
                     push_constant_ranges: &[],
                 });
@@ -165,21 +237,12 @@ impl Pipeline for SimplePipeline {
         let depth_texture = Texture::create_depth_texture(
             &display.device,
             &display.swap_chain_descriptor,
+            1,
             "depth_texture",
         );
 
-        let render_pipeline = {
-            Self::create_render_pipeline(
-                "Render Pipeline",
-                &display.device,
-                &render_pipeline_layout,
-                display.swap_chain_descriptor.format,
-                Texture::DEPTH_FORMAT,
-                &[MeshVertex::desc(), InstanceRaw::desc()],
-                &wgpu::include_spirv!("../resources/shaders/shader.vert.spv"),
-                &wgpu::include_spirv!("../resources/shaders/shader.frag.spv"),
-            )
-        };
+        let render_pipeline =
+            Self::create_main_pipeline(&display.device, &render_pipeline_layout, 1);
 
         bind_group_layouts.insert(
             Material::bind_group_type(),
@@ -188,16 +251,29 @@ impl Pipeline for SimplePipeline {
                 index: 0,
             }),
         );
+        bind_group_layouts.insert(
+            ShadowMap::bind_group_type(),
+            Arc::new(PipelineBindGroupInfo {
+                layout: Arc::new(shadow_bind_group_layout),
+                index: 3,
+            }),
+        );
 
         Self {
             render_pipeline,
+            render_pipeline_layout,
+            sample_count: 1,
             uniforms,
             uniform_buffer,
             uniform_bind_group,
-            light,
-            light_buffer,
-            light_bind_group,
+            uniform_bind_group_layout,
+            no_occlusion,
+            occlusion_sampler,
+            environment,
+            lights,
             depth_texture,
+            multisampled_depth: None,
+            depth_resolve: DepthResolve::new(&display.device),
             bind_group_layouts,
         }
     }
@@ -209,6 +285,44 @@ impl Pipeline for SimplePipeline {
         self.uniforms.view_position = position.into();
     }
 
+    fn update_fog(&mut self, fog: FogRaw) {
+        self.uniforms.fog = fog;
+    }
+
+    fn update_lights(&mut self, lights: &[LightRaw], ambient: [f32; 3]) -> usize {
+        let [x, y, z, _] = self.uniforms.view_position;
+        self.lights
+            .update(lights, cgmath::Vector3::new(x, y, z), ambient);
+        self.lights.len()
+    }
+
+    fn configure(&mut self, display: &Display, settings: &RenderSettings) {
+        self.lights.set_config(&display.device, settings.lighting);
+        self.uniforms.screen = [
+            1.0 / display.swap_chain_descriptor.width as f32,
+            1.0 / display.swap_chain_descriptor.height as f32,
+            0.0,
+            0.0,
+        ];
+        self.uniforms.environment = [
+            (PREFILTERED_LEVELS - 1) as f32,
+            settings.lighting.environment_intensity,
+            0.0,
+            0.0,
+        ];
+
+        let sample_count = settings.msaa.sample_count();
+        if sample_count != self.sample_count {
+            self.sample_count = sample_count;
+            self.render_pipeline = Self::create_main_pipeline(
+                &display.device,
+                &self.render_pipeline_layout,
+                sample_count,
+            );
+            self.create_multisampled_depth(display);
+        }
+    }
+
     fn bind_group_layout(
         &self,
         bind_group_type: BindGroupType,
@@ -218,9 +332,42 @@ impl Pipeline for SimplePipeline {
             .map(|x| x.clone())
     }
 
+    fn depth_texture(&self) -> &Texture {
+        &self.depth_texture
+    }
+
+    fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    fn resolve_depth(&self, encoder: &mut wgpu::CommandEncoder) {
+        if self.multisampled_depth.is_some() {
+            self.depth_resolve.resolve(encoder, &self.depth_texture);
+        }
+    }
+
+    fn set_occlusion(&mut self, display: &Display, occlusion: Option<&wgpu::TextureView>) {
+        self.uniform_bind_group = Self::create_uniform_bind_group(
+            &display.device,
+            &self.uniform_bind_group_layout,
+            &self.uniform_buffer,
+            occlusion.unwrap_or(&self.no_occlusion.view),
+            &self.occlusion_sampler,
+            &self.environment,
+        );
+    }
+
+    fn environment_mut(&mut self) -> &mut Environment {
+        &mut self.environment
+    }
+
     fn depth_stencil_attachment(&self) -> Option<wgpu::RenderPassDepthStencilAttachmentDescriptor> {
+        let depth_texture = self
+            .multisampled_depth
+            .as_ref()
+            .unwrap_or(&self.depth_texture);
         Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-            attachment: &self.depth_texture.view,
+            attachment: &depth_texture.view,
             depth_ops: Some(wgpu::Operations {
                 load: wgpu::LoadOp::Clear(1.0),
                 store: true,
@@ -262,7 +409,7 @@ impl SimplePipeline {
         });
         render_pass.set_pipeline(&self.render_pipeline);
         render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
-        render_pass.set_bind_group(2, &self.light_bind_group, &[]);
+        render_pass.set_bind_group(2, self.lights.bind_group(), &[]);
         render_pass
     }
 
@@ -274,20 +421,93 @@ impl SimplePipeline {
         );
     }
 
-    pub fn resize(&mut self, display: &Display) {
-        self.depth_texture = Texture::create_depth_texture(
-            &display.device,
-            &display.swap_chain_descriptor,
-            "depth_texture",
-        );
+    fn create_uniform_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buffer: &wgpu::Buffer,
+        occlusion: &wgpu::TextureView,
+        occlusion_sampler: &wgpu::Sampler,
+        environment: &Environment,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("uniform_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: uniform_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(occlusion),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(occlusion_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(environment.irradiance()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(environment.prefiltered()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(environment.brdf_lut()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Sampler(environment.sampler()),
+                },
+            ],
+        })
+    }
+
+    fn create_main_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        sample_count: u32,
+    ) -> wgpu::RenderPipeline {
+        Self::create_render_pipeline(
+            "Render Pipeline",
+            device,
+            layout,
+            sample_count,
+            &[MeshVertex::desc(), InstanceRaw::desc()],
+            &wgpu::include_spirv!("../resources/shaders/shader.vert.spv"),
+            &wgpu::include_spirv!("../resources/shaders/shader.frag.spv"),
+        )
     }
 
+    /// Recreates the multisampled depth attachment for the current size and sample count, and
+    /// points the depth resolve at it.
+    fn create_multisampled_depth(&mut self, display: &Display) {
+        self.multisampled_depth = if self.sample_count > 1 {
+            let texture = Texture::create_depth_texture(
+                &display.device,
+                &display.swap_chain_descriptor,
+                self.sample_count,
+                "multisampled_depth_texture",
+            );
+            self.depth_resolve.bind(&display.device, &texture);
+            Some(texture)
+        } else {
+            None
+        };
+    }
+
+    /// Creates a pipeline that draws into the HDR target with depth testing.
     fn create_render_pipeline(
         name: &str,
         device: &wgpu::Device,
         layout: &wgpu::PipelineLayout,
-        color_format: wgpu::TextureFormat,
-        depth_format: wgpu::TextureFormat,
+        sample_count: u32,
         vertex_descs: &[wgpu::VertexBufferLayout],
         vs_src: &wgpu::ShaderModuleDescriptor,
         fs_src: &wgpu::ShaderModuleDescriptor,
@@ -307,7 +527,7 @@ impl SimplePipeline {
                 module: &fs_module,
                 entry_point: "main",
                 targets: &[wgpu::ColorTargetState {
-                    format: color_format,
+                    format: HDR_FORMAT,
                     color_blend: wgpu::BlendState::REPLACE,
                     alpha_blend: wgpu::BlendState::REPLACE,
                     write_mask: wgpu::ColorWrite::ALL,
@@ -318,14 +538,17 @@ impl SimplePipeline {
                 ..Default::default()
             },
             depth_stencil: Some(wgpu::DepthStencilState {
-                format: depth_format,
+                format: Texture::DEPTH_FORMAT,
                 depth_write_enabled: true,
                 depth_compare: wgpu::CompareFunction::Less,
                 stencil: Default::default(),
                 bias: Default::default(),
                 clamp_depth: false,
             }),
-            multisample: Default::default(),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
         })
     }
 }
@@ -335,6 +558,12 @@ impl SimplePipeline {
 struct Uniforms {
     view_position: [f32; 4],
     view_proj: [[f32; 4]; 4],
+    fog: FogRaw,
+    /// Inverse frame size, for looking up screen space textures.
+    screen: [f32; 4],
+    /// Highest mip level of the prefiltered environment map, and the intensity of the image
+    /// based lighting.
+    environment: [f32; 4],
 }
 
 impl Uniforms {
@@ -343,6 +572,9 @@ impl Uniforms {
         Self {
             view_position: [0.0; 4],
             view_proj: cgmath::Matrix4::identity().into(),
+            fog: FogRaw::default(),
+            screen: [0.0; 4],
+            environment: [0.0; 4],
         }
     }
 }
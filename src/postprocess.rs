@@ -0,0 +1,531 @@
+use crate::{camera::Projection, display::Display, texture::Texture};
+
+/// Effects can pack up to this many parameters into their uniform block.
+pub const MAX_PARAMS: usize = 8;
+/// Width of one slice of a colour grading LUT. The slices are laid out side by side.
+pub const LUT_SIZE: u32 = 16;
+
+/// A named parameter of a post-process effect, shown as a slider in the GUI.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostParam {
+    pub name: &'static str,
+    pub value: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl PostParam {
+    pub fn new(name: &'static str, value: f32, min: f32, max: f32) -> Self {
+        Self {
+            name,
+            value,
+            min,
+            max,
+        }
+    }
+}
+
+/// Declares a full screen effect. The fragment shader samples the previous image at set 0,
+/// binding 0 and 1, and finds its parameters at binding 2. A LUT is bound at binding 3, and
+/// effects that use depth find the depth texture and a sampler for it at bindings 4 and 5.
+pub struct PostEffectDesc {
+    pub name: &'static str,
+    pub shader: wgpu::ShaderModuleDescriptor<'static>,
+    pub params: Vec<PostParam>,
+    pub uses_depth: bool,
+    pub lut: Option<image::RgbaImage>,
+    pub enabled: bool,
+}
+
+/// The effects that ship with the renderer, in their default order.
+pub fn default_effects() -> Vec<PostEffectDesc> {
+    vec![
+        PostEffectDesc {
+            name: "Underwater",
+            shader: wgpu::include_spirv!("../resources/shaders/post_underwater.frag.spv"),
+            params: vec![
+                PostParam::new("Tint red", 0.2, 0.0, 1.0),
+                PostParam::new("Tint green", 0.5, 0.0, 1.0),
+                PostParam::new("Tint blue", 0.7, 0.0, 1.0),
+                PostParam::new("Visibility", 24.0, 1.0, 200.0),
+                PostParam::new("Strength", 1.0, 0.0, 1.0),
+            ],
+            uses_depth: true,
+            lut: None,
+            enabled: false,
+        },
+        PostEffectDesc {
+            name: "Colour grading",
+            shader: wgpu::include_spirv!("../resources/shaders/post_color_grading.frag.spv"),
+            params: vec![PostParam::new("Strength", 1.0, 0.0, 1.0)],
+            uses_depth: false,
+            lut: Some(identity_lut()),
+            enabled: false,
+        },
+        PostEffectDesc {
+            name: "Chromatic aberration",
+            shader: wgpu::include_spirv!("../resources/shaders/post_chromatic_aberration.frag.spv"),
+            params: vec![PostParam::new("Strength", 0.005, 0.0, 0.05)],
+            uses_depth: false,
+            lut: None,
+            enabled: false,
+        },
+        PostEffectDesc {
+            name: "Vignette",
+            shader: wgpu::include_spirv!("../resources/shaders/post_vignette.frag.spv"),
+            params: vec![
+                PostParam::new("Intensity", 0.5, 0.0, 1.0),
+                PostParam::new("Radius", 0.9, 0.0, 1.5),
+                PostParam::new("Softness", 0.5, 0.01, 1.0),
+            ],
+            uses_depth: false,
+            lut: None,
+            enabled: false,
+        },
+        PostEffectDesc {
+            name: "FXAA",
+            shader: wgpu::include_spirv!("../resources/shaders/post_fxaa.frag.spv"),
+            params: vec![
+                PostParam::new("Span", 8.0, 1.0, 16.0),
+                PostParam::new("Reduce multiplier", 0.125, 0.0, 1.0),
+                PostParam::new("Reduce minimum", 0.0078125, 0.0, 0.1),
+            ],
+            uses_depth: false,
+            lut: None,
+            enabled: true,
+        },
+    ]
+}
+
+/// A colour grading LUT that leaves every colour as it is, to be edited in an image editor.
+pub fn identity_lut() -> image::RgbaImage {
+    let max = (LUT_SIZE - 1) as f32;
+    image::RgbaImage::from_fn(LUT_SIZE * LUT_SIZE, LUT_SIZE, |x, y| {
+        let channel = |value: u32| (value as f32 / max * 255.0).round() as u8;
+        image::Rgba([
+            channel(x % LUT_SIZE),
+            channel(y),
+            channel(x / LUT_SIZE),
+            255,
+        ])
+    })
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PostUniforms {
+    /// Texel size, near and far plane.
+    frame: [f32; 4],
+    params: [f32; MAX_PARAMS],
+}
+
+pub struct PostEffect {
+    name: &'static str,
+    pub enabled: bool,
+    params: Vec<PostParam>,
+    uses_depth: bool,
+    pipeline: wgpu::RenderPipeline,
+    uniform_buffer: wgpu::Buffer,
+    lut: wgpu::TextureView,
+    /// One bind group for each ping-pong target the effect can read from.
+    bind_groups: Vec<wgpu::BindGroup>,
+}
+
+impl PostEffect {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn params_mut(&mut self) -> &mut [PostParam] {
+        &mut self.params
+    }
+}
+
+struct PingPongTarget {
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+}
+
+/// An ordered stack of full screen effects applied to the tone mapped image. Effects render
+/// back and forth between two targets, and the last enabled effect writes into the frame.
+pub struct PostProcessStack {
+    effects: Vec<PostEffect>,
+    targets: Vec<PingPongTarget>,
+    layout: wgpu::BindGroupLayout,
+    depth_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    depth_sampler: wgpu::Sampler,
+    vs_module: wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+}
+
+impl PostProcessStack {
+    pub fn new(display: &Display) -> Self {
+        let device = &display.device;
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Post Process Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let depth_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Post Process Depth Sampler"),
+            ..Default::default()
+        });
+
+        let entries = [
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler {
+                    comparison: false,
+                    filtering: true,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                // Depth is read as a plain float texture, since it is not compared against.
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler {
+                    comparison: false,
+                    filtering: false,
+                },
+                count: None,
+            },
+        ];
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("post_process_bind_group_layout"),
+            entries: &entries[..4],
+        });
+        let depth_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("post_process_depth_bind_group_layout"),
+            entries: &entries,
+        });
+
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!(
+            "../resources/shaders/fullscreen.vert.spv"
+        ));
+        let format = display.swap_chain_descriptor.format;
+
+        Self {
+            effects: Vec::new(),
+            targets: create_targets(display, format),
+            layout,
+            depth_layout,
+            sampler,
+            depth_sampler,
+            vs_module,
+            format,
+        }
+    }
+
+    /// Adds an effect at the end of the stack.
+    pub fn push(&mut self, display: &Display, depth_texture: &Texture, desc: PostEffectDesc) {
+        assert!(
+            desc.params.len() <= MAX_PARAMS,
+            "Post-process effect {} has more than {} parameters",
+            desc.name,
+            MAX_PARAMS
+        );
+        let device = &display.device;
+        let layout = if desc.uses_depth {
+            &self.depth_layout
+        } else {
+            &self.layout
+        };
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(desc.name),
+            bind_group_layouts: &[layout],
+            push_constant_ranges: &[],
+        });
+        let fs_module = device.create_shader_module(&desc.shader);
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(desc.name),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &self.vs_module,
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: self.format,
+                    color_blend: wgpu::BlendState::REPLACE,
+                    alpha_blend: wgpu::BlendState::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: Default::default(),
+            depth_stencil: None,
+            multisample: Default::default(),
+        });
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Post Process Uniform Buffer"),
+            size: std::mem::size_of::<PostUniforms>() as u64,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let lut = match &desc.lut {
+            Some(lut) => create_lut_view(display, lut),
+            None => create_lut_view(
+                display,
+                &image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])),
+            ),
+        };
+
+        let mut effect = PostEffect {
+            name: desc.name,
+            enabled: desc.enabled,
+            params: desc.params,
+            uses_depth: desc.uses_depth,
+            pipeline,
+            uniform_buffer,
+            lut,
+            bind_groups: Vec::new(),
+        };
+        effect.bind_groups = self.create_bind_groups(display, &effect, depth_texture);
+        self.effects.push(effect);
+    }
+
+    pub fn effects_mut(&mut self) -> &mut [PostEffect] {
+        &mut self.effects
+    }
+
+    /// Moves the effect at `from` so it runs at position `to` instead.
+    pub fn move_effect(&mut self, from: usize, to: usize) {
+        if from < self.effects.len() && to < self.effects.len() {
+            let effect = self.effects.remove(from);
+            self.effects.insert(to, effect);
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.effects.iter().any(|effect| effect.enabled)
+    }
+
+    /// The target the image has to be rendered into before the stack runs.
+    pub fn input(&self) -> &wgpu::TextureView {
+        &self.targets[0].view
+    }
+
+    /// Recreates the ping-pong targets, and rebinds the depth texture, which is recreated with
+    /// the window as well.
+    pub fn resize(&mut self, display: &Display, depth_texture: &Texture) {
+        self.targets = create_targets(display, self.format);
+        for index in 0..self.effects.len() {
+            let bind_groups = self.create_bind_groups(display, &self.effects[index], depth_texture);
+            self.effects[index].bind_groups = bind_groups;
+        }
+    }
+
+    /// Runs every enabled effect over the image in `input`, writing the result into `target`.
+    pub fn run(
+        &self,
+        display: &Display,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        projection: &Projection,
+    ) {
+        let enabled = self
+            .effects
+            .iter()
+            .filter(|effect| effect.enabled)
+            .collect::<Vec<_>>();
+        let frame = [
+            1.0 / display.swap_chain_descriptor.width as f32,
+            1.0 / display.swap_chain_descriptor.height as f32,
+            projection.znear(),
+            projection.zfar(),
+        ];
+
+        for (index, effect) in enabled.iter().enumerate() {
+            let mut uniforms = PostUniforms {
+                frame,
+                params: [0.0; MAX_PARAMS],
+            };
+            for (value, param) in uniforms.params.iter_mut().zip(&effect.params) {
+                *value = param.value;
+            }
+            display.queue.write_buffer(
+                &effect.uniform_buffer,
+                0,
+                bytemuck::cast_slice(&[uniforms]),
+            );
+
+            let source = index % 2;
+            let output = if index + 1 == enabled.len() {
+                target
+            } else {
+                &self.targets[1 - source].view
+            };
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(effect.name),
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: output,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(&effect.pipeline);
+            render_pass.set_bind_group(0, &effect.bind_groups[source], &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+
+    fn create_bind_groups(
+        &self,
+        display: &Display,
+        effect: &PostEffect,
+        depth_texture: &Texture,
+    ) -> Vec<wgpu::BindGroup> {
+        self.targets
+            .iter()
+            .map(|source| {
+                let mut entries = vec![
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&source.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: effect.uniform_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::TextureView(&effect.lut),
+                    },
+                ];
+                if effect.uses_depth {
+                    entries.push(wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::TextureView(&depth_texture.view),
+                    });
+                    entries.push(wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: wgpu::BindingResource::Sampler(&self.depth_sampler),
+                    });
+                }
+                display
+                    .device
+                    .create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some("post_process_bind_group"),
+                        layout: if effect.uses_depth {
+                            &self.depth_layout
+                        } else {
+                            &self.layout
+                        },
+                        entries: &entries,
+                    })
+            })
+            .collect()
+    }
+}
+
+fn create_targets(display: &Display, format: wgpu::TextureFormat) -> Vec<PingPongTarget> {
+    (0..2)
+        .map(|_| {
+            let texture = display.device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Post Process Target"),
+                size: wgpu::Extent3d {
+                    width: display.swap_chain_descriptor.width,
+                    height: display.swap_chain_descriptor.height,
+                    depth: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+            });
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            PingPongTarget {
+                _texture: texture,
+                view,
+            }
+        })
+        .collect()
+}
+
+/// Uploads a LUT without sRGB decoding, so its texels map colours directly.
+fn create_lut_view(display: &Display, image: &image::RgbaImage) -> wgpu::TextureView {
+    let size = wgpu::Extent3d {
+        width: image.width(),
+        height: image.height(),
+        depth: 1,
+    };
+    let texture = display.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Post Process LUT"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+    });
+    display.queue.write_texture(
+        wgpu::TextureCopyView {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        image.as_raw(),
+        wgpu::TextureDataLayout {
+            offset: 0,
+            bytes_per_row: 4 * image.width(),
+            rows_per_image: image.height(),
+        },
+        size,
+    );
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
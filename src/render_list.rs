@@ -0,0 +1,191 @@
+use std::ops::Range;
+
+use crate::{
+    instance::InstanceRaw,
+    material::{Material, MaterialId},
+    mesh::{Mesh, MeshBufferId, Model},
+    pipeline::Pipeline,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PipelineKind {
+    Opaque,
+}
+
+/// Draw items are sorted by this key so that items sharing a pipeline, material or mesh end up
+/// next to each other and their state only has to be bound once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DrawKey {
+    pub pipeline: PipelineKind,
+    pub material: MaterialId,
+    pub mesh: MeshBufferId,
+}
+
+pub struct DrawItem<'a> {
+    pub key: DrawKey,
+    pub mesh: &'a Mesh,
+    pub material: &'a Material,
+    pub instances: Range<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderStats {
+    pub draw_calls: u32,
+    pub pipeline_changes: u32,
+    pub material_changes: u32,
+    pub mesh_changes: u32,
+    pub instances: u32,
+    pub instances_culled: u32,
+    pub chunks_culled: u32,
+    pub lights: u32,
+    pub shadow_draw_calls: u32,
+}
+
+impl RenderStats {
+    pub fn state_changes(&self) -> u32 {
+        self.pipeline_changes + self.material_changes + self.mesh_changes
+    }
+}
+
+pub struct RenderList<'a> {
+    items: Vec<DrawItem<'a>>,
+    instances: Vec<InstanceRaw>,
+}
+
+impl<'a> RenderList<'a> {
+    pub fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            instances: Vec::new(),
+        }
+    }
+
+    pub fn push_model(
+        &mut self,
+        pipeline: PipelineKind,
+        model: &'a Model,
+        instances: &[InstanceRaw],
+    ) {
+        if instances.is_empty() {
+            return;
+        }
+        let start = self.instances.len() as u32;
+        self.instances.extend_from_slice(instances);
+        let range = start..self.instances.len() as u32;
+
+        for mesh in &model.meshes {
+            let material = model.materials[mesh.material].as_ref();
+            self.items.push(DrawItem {
+                key: DrawKey {
+                    pipeline,
+                    material: material.id,
+                    mesh: mesh.id,
+                },
+                mesh,
+                material,
+                instances: range.clone(),
+            });
+        }
+    }
+
+    pub fn sort(&mut self) {
+        self.items.sort_by_key(|item| item.key);
+    }
+
+    pub fn instances(&self) -> &[InstanceRaw] {
+        &self.instances
+    }
+
+    pub fn items(&self) -> &[DrawItem<'a>] {
+        &self.items
+    }
+}
+
+pub trait DrawRenderList<'a, 'b>
+where
+    'b: 'a,
+{
+    fn draw_render_list<P: Pipeline>(
+        &mut self,
+        render_list: &'b RenderList<'b>,
+        pipeline: &'b P,
+        instance_buffer: &'b wgpu::Buffer,
+    ) -> RenderStats;
+
+    /// Draws the meshes of a render list without touching pipelines or materials, for depth
+    /// only passes. Returns the number of draw calls issued.
+    fn draw_render_list_geometry(
+        &mut self,
+        render_list: &'b RenderList<'b>,
+        instance_buffer: &'b wgpu::Buffer,
+    ) -> u32;
+}
+
+impl<'a, 'b> DrawRenderList<'a, 'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_render_list<P: Pipeline>(
+        &mut self,
+        render_list: &'b RenderList<'b>,
+        pipeline: &'b P,
+        instance_buffer: &'b wgpu::Buffer,
+    ) -> RenderStats {
+        let mut stats = RenderStats {
+            instances: render_list.instances().len() as u32,
+            ..Default::default()
+        };
+        let mut bound_pipeline = None;
+        let mut bound_material = None;
+        let mut bound_mesh = None;
+
+        self.set_vertex_buffer(1, instance_buffer.slice(..));
+        for item in render_list.items() {
+            if bound_pipeline != Some(item.key.pipeline) {
+                match item.key.pipeline {
+                    PipelineKind::Opaque => pipeline.bind(self),
+                }
+                bound_pipeline = Some(item.key.pipeline);
+                stats.pipeline_changes += 1;
+            }
+            if bound_material != Some(item.key.material) {
+                self.set_bind_group(0, &item.material.bind_group, &[]);
+                bound_material = Some(item.key.material);
+                stats.material_changes += 1;
+            }
+            if bound_mesh != Some(item.key.mesh) {
+                self.set_vertex_buffer(0, item.mesh.vertex_buffer.slice(..));
+                self.set_index_buffer(item.mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                bound_mesh = Some(item.key.mesh);
+                stats.mesh_changes += 1;
+            }
+
+            self.draw_indexed(0..item.mesh.num_elements, 0, item.instances.clone());
+            stats.draw_calls += 1;
+        }
+
+        stats
+    }
+
+    fn draw_render_list_geometry(
+        &mut self,
+        render_list: &'b RenderList<'b>,
+        instance_buffer: &'b wgpu::Buffer,
+    ) -> u32 {
+        let mut draw_calls = 0;
+        let mut bound_mesh = None;
+
+        self.set_vertex_buffer(1, instance_buffer.slice(..));
+        for item in render_list.items() {
+            if bound_mesh != Some(item.key.mesh) {
+                self.set_vertex_buffer(0, item.mesh.vertex_buffer.slice(..));
+                self.set_index_buffer(item.mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                bound_mesh = Some(item.key.mesh);
+            }
+            self.draw_indexed(0..item.mesh.num_elements, 0, item.instances.clone());
+            draw_calls += 1;
+        }
+
+        draw_calls
+    }
+}
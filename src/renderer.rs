@@ -1,24 +1,57 @@
+use anyhow::*;
 use futures::executor::block_on;
-use winit::window::Window;
+use winit::{dpi::PhysicalSize, window::Window};
 
 use crate::{
+    asset::AssetRegistry,
+    bind_group::BindGroupType,
     camera::{Camera, Projection},
+    chunk,
     display::Display,
-    instance::{Instance, InstanceRaw},
-    mesh::DrawModel,
-    pipeline::{Pipeline, SimplePipeline},
+    fog::FogRaw,
+    hdr::HdrTarget,
+    instance::{InstanceBuffer, InstanceRaw},
+    light::LightRaw,
+    math::Frustum,
+    pipeline::Pipeline,
+    postprocess::{self, PostEffectDesc, PostProcessStack},
+    render_list::{DrawRenderList, PipelineKind, RenderList, RenderStats},
     scene::Scene,
+    settings::RenderSettings,
+    shadow::ShadowMap,
+    sky::SkyPass,
+    ssao::SsaoPass,
 };
 
 pub struct Renderer<P: Pipeline> {
     camera_metadata: Projection,
+    instance_buffer: InstanceBuffer,
+    shadow_instance_buffer: InstanceBuffer,
+    shadows: ShadowMap,
+    sky: SkyPass,
+    ssao: SsaoPass,
+    hdr: HdrTarget,
+    pub post_process: PostProcessStack,
+    pub stats: RenderStats,
+    pub settings: RenderSettings,
     pub display: Display,
     pub pipeline: P,
 }
 
 impl<P: Pipeline> Renderer<P> {
     pub fn new(window: &Window) -> Self {
-        let display = block_on(Display::new(window));
+        Self::from_display(block_on(Display::new(window)))
+    }
+
+    /// Creates a renderer without a window. Frames are drawn with `capture`.
+    #[cfg(test)]
+    pub fn new_headless(width: u32, height: u32) -> Result<Self> {
+        Ok(Self::from_display(block_on(Display::headless(
+            width, height,
+        ))?))
+    }
+
+    fn from_display(display: Display) -> Self {
         let camera_metadata = Projection::new(
             display.swap_chain_descriptor.width,
             display.swap_chain_descriptor.height,
@@ -27,68 +60,274 @@ impl<P: Pipeline> Renderer<P> {
             1000.0,
         );
         let pipeline = P::new(&display);
+        let instance_buffer = InstanceBuffer::new(&display.device);
+        let shadow_instance_buffer = InstanceBuffer::new(&display.device);
+        let shadows = ShadowMap::new(
+            &display,
+            pipeline
+                .bind_group_layout(BindGroupType::Shadow)
+                .expect("Pipeline has no shadow bind group layout"),
+        );
+
+        let sky = SkyPass::new(&display);
+        let ssao = SsaoPass::new(&display);
+        let hdr = HdrTarget::new(&display);
+        let post_process = PostProcessStack::new(&display);
 
-        Self {
+        let mut renderer = Self {
             display,
             camera_metadata,
+            instance_buffer,
+            shadow_instance_buffer,
+            shadows,
+            sky,
+            ssao,
+            hdr,
+            post_process,
+            stats: RenderStats::default(),
+            settings: RenderSettings::default(),
             pipeline,
+        };
+        for effect in postprocess::default_effects() {
+            renderer.add_post_effect(effect);
         }
+        renderer
+    }
+
+    /// Resizes every size dependent resource. Nothing but the stored size changes while the
+    /// window is minimized, and frames should be skipped until it is restored.
+    pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        self.display.resize(size);
+        if self.display.is_minimized() {
+            return;
+        }
+        self.pipeline.resize(&self.display);
+        self.hdr.resize(&self.display);
+        self.ssao.resize(&self.display);
+        self.pipeline
+            .set_occlusion(&self.display, self.ssao.occlusion());
+        self.post_process
+            .resize(&self.display, self.pipeline.depth_texture());
+        self.camera_metadata.resize(size.width, size.height);
+    }
+
+    /// Loads the environment cubemap of the manifest, if it lists one, and convolves it for the
+    /// image based lighting of PBR materials.
+    pub fn load_environment(&mut self, registry: &AssetRegistry) -> Result<()> {
+        let paths = match registry.environment_faces() {
+            Some(paths) => paths,
+            None => return Ok(()),
+        };
+        let faces = paths
+            .iter()
+            .map(|path| {
+                image::open(path)
+                    .with_context(|| format!("Unable to load environment face {:?}", path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.pipeline
+            .environment_mut()
+            .load_cubemap(&self.display, &faces)
+    }
+
+    /// Appends a full screen effect to the end of the post-process stack.
+    pub fn add_post_effect(&mut self, desc: PostEffectDesc) {
+        self.post_process
+            .push(&self.display, self.pipeline.depth_texture(), desc);
+    }
+
+    /// Renders the scene into a new offscreen target and reads the frame back to the CPU.
+    #[cfg(test)]
+    pub fn capture(&mut self, scene: &Scene, camera: &Camera) -> Result<image::RgbaImage> {
+        let target = crate::offscreen::OffscreenTarget::new(&self.display);
+        let mut encoder =
+            self.display
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Offscreen Encoder"),
+                });
+        self.render(&target.view, &mut encoder, scene, camera);
+        self.display.queue.submit(std::iter::once(encoder.finish()));
+        target.read(&self.display)
     }
 
     pub fn render(
         &mut self,
-        frame: &wgpu::SwapChainTexture,
+        target: &wgpu::TextureView,
         encoder: &mut wgpu::CommandEncoder,
-        scene: Box<Scene>,
+        scene: &Scene,
         camera: &Camera,
     ) {
+        let view_projection = camera.projection(&self.camera_metadata);
+        self.pipeline.configure(&self.display, &self.settings);
+        let sample_count = self.pipeline.sample_count();
+        self.hdr.set_sample_count(&self.display, sample_count);
+        self.sky.set_sample_count(&self.display, sample_count);
+        if self.ssao.configure(&self.display, self.settings.ssao) {
+            self.pipeline
+                .set_occlusion(&self.display, self.ssao.occlusion());
+        }
         self.pipeline.update_view_position(camera.position());
-        self.pipeline
-            .update_view_projection(camera.projection(&self.camera_metadata));
+        self.pipeline.update_view_projection(view_projection);
+        self.pipeline.update_fog(FogRaw::new(
+            &self.settings.fog,
+            &scene.sky,
+            chunk::load_distance(),
+        ));
+        let lights = self
+            .pipeline
+            .update_lights(&scene.lights, scene.sky.ambient);
+        // The brightest directional light casts shadows, matching the order of the light buffer.
+        let sun = scene
+            .lights
+            .iter()
+            .filter(|light| light.is_directional())
+            .fold(
+                None,
+                |brightest: Option<&LightRaw>, light| match brightest {
+                    Some(brightest) if brightest.intensity() >= light.intensity() => {
+                        Some(brightest)
+                    }
+                    _ => Some(light),
+                },
+            );
+        self.shadows.configure(&self.display, self.settings.shadows);
+        self.shadows.update(camera, &self.camera_metadata, sun);
 
         self.pipeline.prepare(&self.display);
+        self.shadows.prepare(&self.display);
+        self.ssao.update(
+            &self.display,
+            camera.calc_matrix(),
+            self.camera_metadata.calc_matrix(),
+        );
+        self.sky.update(
+            &self.display,
+            &scene.sky,
+            &self.settings.sky,
+            camera.position(),
+            view_projection,
+        );
+        self.pipeline.environment_mut().update(
+            &self.display,
+            encoder,
+            self.settings.lighting.environment_source,
+            &scene.sky,
+            &self.sky,
+            &self.settings.sky,
+        );
 
+        let frustum = Frustum::from_matrix(&view_projection);
+        let mut instances_culled = 0;
+        let mut render_list = RenderList::new();
+        for batch in &scene.batches {
+            let visible = batch
+                .instances
+                .iter()
+                .map(|instance| instance.to_matrix())
+                .filter(|matrix| frustum.intersects(&batch.model.bounds.transform(matrix)))
+                .map(InstanceRaw::from)
+                .collect::<Vec<_>>();
+
+            instances_culled += (batch.instances.len() - visible.len()) as u32;
+            render_list.push_model(PipelineKind::Opaque, batch.model, &visible);
+        }
+        render_list.sort();
+        self.instance_buffer
+            .write(&self.display, render_list.instances());
+        scene.terrain.cull(&self.display, encoder, 0, &frustum);
+
+        // Casters outside the camera frustum can still throw shadows into it, so they are
+        // culled against the cascades instead.
+        let cascades = self.shadows.cascades();
+        let mut shadow_list = RenderList::new();
+        if !cascades.is_empty() {
+            for batch in &scene.batches {
+                let casters = batch
+                    .instances
+                    .iter()
+                    .map(|instance| instance.to_matrix())
+                    .filter(|matrix| {
+                        let bounds = batch.model.bounds.transform(matrix);
+                        cascades.iter().any(|cascade| cascade.intersects(&bounds))
+                    })
+                    .map(InstanceRaw::from)
+                    .collect::<Vec<_>>();
+                shadow_list.push_model(PipelineKind::Opaque, batch.model, &casters);
+            }
+        }
+        shadow_list.sort();
+        self.shadow_instance_buffer
+            .write(&self.display, shadow_list.instances());
+
+        let mut shadow_draw_calls = 0;
+        for (cascade, frustum) in cascades.iter().enumerate() {
+            let view = 1 + cascade as u32;
+            scene.terrain.cull(&self.display, encoder, view, frustum);
+            let mut shadow_pass = self.shadows.render_pass(encoder, cascade);
+            shadow_draw_calls += shadow_pass
+                .draw_render_list_geometry(&shadow_list, &self.shadow_instance_buffer.buffer);
+            shadow_draw_calls += scene.terrain.draw(&mut shadow_pass, view);
+        }
+
+        if let Some(mut prepass) = self.ssao.prepass(encoder) {
+            prepass.draw_render_list_geometry(&render_list, &self.instance_buffer.buffer);
+            scene.terrain.draw(&mut prepass, 0);
+        }
+        self.ssao.run(encoder);
+
+        let horizon = scene.sky.horizon_color;
         {
-            let mut current_scene = Some(scene);
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: None,
-                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                }],
+                color_attachments: &[self.hdr.color_attachment(wgpu::LoadOp::Clear(wgpu::Color {
+                    r: horizon[0] as f64,
+                    g: horizon[1] as f64,
+                    b: horizon[2] as f64,
+                    a: 1.0,
+                }))],
                 depth_stencil_attachment: self.pipeline.depth_stencil_attachment(),
             });
-            self.pipeline.bind(&mut render_pass);
-            let mut first = true;
-            while current_scene.is_some() {
-                let scene = current_scene.unwrap();
-                let instance_data = scene
-                    .instances
-                    .iter()
-                    .map(Instance::to_raw)
-                    .collect::<Vec<_>>();
+            render_pass.set_bind_group(3, self.shadows.bind_group(), &[]);
+            self.stats = render_pass.draw_render_list(
+                &render_list,
+                &self.pipeline,
+                &self.instance_buffer.buffer,
+            );
+            self.stats.instances_culled = instances_culled;
+            self.stats.chunks_culled = scene.terrain.count_culled(&frustum);
+            self.stats.lights = lights as u32;
+            self.stats.shadow_draw_calls = shadow_draw_calls;
 
-                scene
-                    .model
-                    .load_instance_buffers(&self.display, instance_data);
-
-                render_pass.draw_model_instanced(
-                    scene.model,
-                    0..scene.instances.len() as u32,
-                    first,
-                );
-                first = false;
-                current_scene = scene.next;
+            // The render list only binds the pipeline when it has items to draw.
+            self.pipeline.bind(&mut render_pass);
+            render_pass.set_bind_group(0, &scene.terrain.material().bind_group, &[]);
+            let terrain_draws = scene.terrain.draw(&mut render_pass, 0);
+            if terrain_draws > 0 {
+                self.stats.draw_calls += terrain_draws;
+                self.stats.pipeline_changes += 1;
+                self.stats.material_changes += 1;
+                self.stats.mesh_changes += 1;
             }
+
+            self.sky.draw(&mut render_pass);
+            self.stats.draw_calls += 1;
+            self.stats.pipeline_changes += 1;
+        }
+
+        if self.post_process.is_active() {
+            self.pipeline.resolve_depth(encoder);
+            self.hdr.resolve(
+                &self.display,
+                encoder,
+                self.post_process.input(),
+                &self.settings.hdr,
+            );
+            self.post_process
+                .run(&self.display, encoder, target, &self.camera_metadata);
+        } else {
+            self.hdr
+                .resolve(&self.display, encoder, target, &self.settings.hdr);
         }
     }
 }
@@ -1,19 +1,26 @@
 use crate::{
-    asset::ModelAsset,
+    asset::{AssetKind, AssetRegistry, MaterialOverride, ModelAsset},
+    bind_group::BindGroupType,
+    chunk,
     display::Display,
-    ecs::component::MeshReference,
     instance::Instance,
-    mesh::Model,
+    light::LightRaw,
+    material::{Material, MaterialCache, MaterialDesc},
+    mesh::{GeneratedNormals, Model, ObjOptions},
     pipeline::{Pipeline, PipelineBindGroupInfo},
+    terrain::{TerrainArena, TerrainConfig},
+    texture::{SamplerConfig, TextureAddressMode},
 };
 use crate::{
     ecs::*,
     worker::{pool::Pool, worker::Worker},
 };
+use anyhow::{Context, Result};
 use cgmath::InnerSpace;
 use legion::*;
 use std::{
     collections::{HashMap, HashSet},
+    path::PathBuf,
     sync::{
         mpsc::{self, TryRecvError},
         Arc, Mutex,
@@ -22,64 +29,274 @@ use std::{
 };
 
 pub struct Scene<'a> {
+    pub batches: Vec<ModelBatch<'a>>,
+    pub lights: Vec<LightRaw>,
+    pub sky: component::Sky,
+    pub terrain: &'a TerrainArena,
+}
+
+pub struct ModelBatch<'a> {
     pub model: &'a Model,
     pub instances: Vec<Instance>,
-    pub next: Option<Box<Scene<'a>>>,
+}
+
+pub const DEFAULT_MEMORY_BUDGET: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AssetConfig {
+    /// GPU memory that models and materials may take together. Models no entity refers to are
+    /// cached until the budget runs out, and then evicted least recently drawn first. The
+    /// terrain arena cannot be evicted, so it is reported but not counted against the budget.
+    pub memory_budget: u64,
+}
+
+impl Default for AssetConfig {
+    fn default() -> Self {
+        Self {
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AssetStats {
+    pub resident_assets: usize,
+    /// Resident assets that no entity refers to, kept in case they are needed again.
+    pub cached_assets: usize,
+    pub pending_assets: usize,
+    /// Referenced assets that are not loaded because the budget is used up by drawn assets.
+    pub deferred_assets: usize,
+    pub resident_bytes: u64,
+    pub cached_materials: usize,
+    pub material_bytes: u64,
+    pub memory_budget: u64,
+    pub evicted_assets: u64,
+    pub terrain_chunks: usize,
+    pub terrain_bytes: u64,
 }
 
 struct PendingWork {
     killer: mpsc::Sender<bool>,
-    complete: mpsc::Receiver<Model>,
+    complete: mpsc::Receiver<Result<Model>>,
 }
+
+struct ResidentAsset {
+    model: Model,
+    references: usize,
+    last_used: u64,
+    memory_usage: u64,
+}
+
 pub struct SceneManager {
-    assets: HashMap<ModelAsset, Model>,
+    assets: HashMap<ModelAsset, ResidentAsset>,
     asset_workers: Pool<AssetWork, AssetWorkerInitializer, AssetWorker>,
     pending_assets: HashMap<ModelAsset, PendingWork>,
+    failed_assets: HashSet<ModelAsset>,
+    deferred_assets: HashSet<ModelAsset>,
+    registry: Arc<AssetRegistry>,
+    material_cache: Arc<MaterialCache>,
+    frame: u64,
+    memory_budget: u64,
+    resident_bytes: u64,
+    evicted_assets: u64,
+    terrain: TerrainArena,
 }
 impl SceneManager {
-    pub fn new(display: &Display) -> Self {
+    pub fn new<P: Pipeline>(
+        display: &Display,
+        pipeline: &P,
+        registry: Arc<AssetRegistry>,
+    ) -> Result<Self> {
+        let material_cache = Arc::new(MaterialCache::new());
+        let block_paths = chunk::BlockTexture::ALL
+            .iter()
+            .map(|texture| {
+                registry
+                    .block_texture_path(texture.name())
+                    .with_context(|| format!("Manifest has no block texture {:?}", texture.name()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // The block material is cached under the paths of its layers, and counts towards the
+        // material memory like every other material.
+        let blocks_desc = MaterialDesc {
+            diffuse_layers: block_paths,
+            sampler: SamplerConfig {
+                anisotropy: 16,
+                address_mode: TextureAddressMode::Repeat,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let blocks = Material::load(
+            &display.device,
+            &display.queue,
+            "Block Textures".to_string(),
+            pipeline.bind_group_layout(BindGroupType::Material),
+            &blocks_desc,
+            &material_cache,
+        )
+        .context("Failed to load the block textures")?;
+        let terrain = TerrainArena::new(display, blocks, TerrainConfig::default());
         let initializer = AssetWorkerInitializer {
             device: Arc::clone(&display.device),
             queue: Arc::clone(&display.queue),
+            material_cache: Arc::clone(&material_cache),
         };
-        Self {
+        Ok(Self {
             assets: HashMap::new(),
             asset_workers: Pool::new(2, initializer),
             pending_assets: HashMap::new(),
+            failed_assets: HashSet::new(),
+            deferred_assets: HashSet::new(),
+            registry,
+            material_cache,
+            frame: 0,
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            resident_bytes: 0,
+            evicted_assets: 0,
+            terrain,
+        })
+    }
+
+    pub fn configure(&mut self, config: AssetConfig) {
+        self.memory_budget = config.memory_budget;
+    }
+
+    pub fn stats(&self) -> AssetStats {
+        AssetStats {
+            resident_assets: self.assets.len(),
+            cached_assets: self
+                .assets
+                .values()
+                .filter(|resident| resident.references == 0)
+                .count(),
+            pending_assets: self.pending_assets.len(),
+            deferred_assets: self.deferred_assets.len(),
+            resident_bytes: self.resident_bytes,
+            cached_materials: self.material_cache.len(),
+            material_bytes: self.material_cache.memory_usage(),
+            memory_budget: self.memory_budget,
+            evicted_assets: self.evicted_assets,
+            terrain_chunks: self.terrain.chunk_count(),
+            terrain_bytes: self.terrain.memory_usage(),
         }
     }
 
     pub fn load_scene<P: Pipeline>(
         &mut self,
+        display: &Display,
         world: &World,
         pipeline: &P,
-        display: &Display,
-    ) -> Option<Box<Scene>> {
+    ) -> Scene {
+        self.frame += 1;
+        self.receive_finished_work();
+
+        let mut instance_bundle: HashMap<ModelAsset, Vec<Instance>> = HashMap::new();
         let mut query = <(&component::Transform, &component::ModelReference)>::query();
-        let mut instance_bundle = HashMap::new();
         for (transform, model_ref) in query.iter(world) {
-            let model_asset: ModelAsset = model_ref.asset_reference;
-            if !instance_bundle.contains_key(&model_asset) {
-                instance_bundle.insert(model_asset, Vec::new());
-            }
-            let q: cgmath::Quaternion<f32> = transform.rotation.into();
-            let instance = Instance::new(transform.position.clone(), q.normalize());
             instance_bundle
-                .get_mut(&model_asset)
-                .unwrap()
-                .push(instance)
+                .entry(model_ref.asset_reference)
+                .or_default()
+                .push(to_instance(transform));
         }
 
+        let mut query = <(&component::Transform, &component::MeshReference)>::query();
+        self.terrain.sync(display, query.iter(world));
+
+        let mut query = <(&component::Transform, &component::Light)>::query();
+        let lights = query
+            .iter(world)
+            .map(|(transform, light)| LightRaw::new(transform, light))
+            .collect();
+        let sky = <&component::Sky>::query()
+            .iter(world)
+            .next()
+            .copied()
+            .unwrap_or_default();
+
+        self.update_references(&instance_bundle);
+        self.evict_over_budget();
+
+        let missing_assets = instance_bundle
+            .keys()
+            .filter(|asset| !self.assets.contains_key(asset))
+            .copied()
+            .collect::<Vec<_>>();
+        for asset in missing_assets {
+            // Instances are skipped until their model has been uploaded by an asset worker.
+            instance_bundle.remove(&asset);
+            if !self.can_dispatch(&asset) {
+                continue;
+            }
+            if self.memory_usage() >= self.memory_budget {
+                if self.deferred_assets.insert(asset) {
+                    log::warn!(
+                        "Deferred loading asset {:?}, {} of {} budget bytes are in use",
+                        asset,
+                        self.memory_usage(),
+                        self.memory_budget
+                    );
+                }
+                continue;
+            }
+            self.deferred_assets.remove(&asset);
+            match asset_source(&self.registry, &asset) {
+                Ok(source) => self.dispatch(asset, source, pipeline),
+                Err(err) => {
+                    log::error!("Failed to load asset {:?}: {:?}", asset, err);
+                    self.failed_assets.insert(asset);
+                }
+            }
+        }
+
+        let frame = self.frame;
+        for asset in instance_bundle.keys() {
+            self.assets.get_mut(asset).unwrap().last_used = frame;
+        }
+
+        let assets = &self.assets;
+        Scene {
+            batches: instance_bundle
+                .into_iter()
+                .map(|(asset, instances)| ModelBatch {
+                    model: &assets.get(&asset).unwrap().model,
+                    instances,
+                })
+                .collect(),
+            lights,
+            sky,
+            terrain: &self.terrain,
+        }
+    }
+
+    fn receive_finished_work(&mut self) {
         let mut finished_work = HashSet::new();
         for (model_asset, pending_work) in &self.pending_assets {
             match pending_work.complete.try_recv() {
-                Ok(mesh) => {
-                    self.assets.insert(model_asset.clone(), mesh);
-                    finished_work.insert(model_asset.clone());
+                Ok(Ok(model)) => {
+                    let memory_usage = model.memory_usage();
+                    self.resident_bytes += memory_usage;
+                    self.assets.insert(
+                        *model_asset,
+                        ResidentAsset {
+                            model,
+                            references: 0,
+                            last_used: self.frame,
+                            memory_usage,
+                        },
+                    );
+                    finished_work.insert(*model_asset);
+                }
+                Ok(Err(err)) => {
+                    log::error!("Failed to load asset {:?}: {:?}", model_asset, err);
+                    self.failed_assets.insert(*model_asset);
+                    finished_work.insert(*model_asset);
                 }
                 Err(TryRecvError::Empty) => {}
-                _ => {
-                    finished_work.insert(model_asset.clone());
+                Err(TryRecvError::Disconnected) => {
+                    log::error!("Asset worker stopped before loading {:?}", model_asset);
+                    self.failed_assets.insert(*model_asset);
+                    finished_work.insert(*model_asset);
                 }
             }
         }
@@ -87,65 +304,101 @@ impl SceneManager {
         for work in finished_work {
             self.pending_assets.remove(&work);
         }
+    }
 
-        let mut query = <(&component::Transform, &component::MeshReference)>::query();
-        for (transform, mesh_ref) in query.iter(world) {
-            let mesh_ref: &component::MeshReference = mesh_ref;
-
-            let q: cgmath::Quaternion<f32> = transform.rotation.into();
-            let instance = Instance::new(transform.position.clone(), q.normalize());
-            let model_asset = ModelAsset::DynamicMesh(mesh_ref.idx);
+    /// Counts the references of every resident asset. Assets no entity refers to anymore stay
+    /// resident until the budget needs their memory, but loads that are still in flight for
+    /// them are cancelled.
+    fn update_references(&mut self, instance_bundle: &HashMap<ModelAsset, Vec<Instance>>) {
+        for (asset, resident) in self.assets.iter_mut() {
+            resident.references = instance_bundle.get(asset).map_or(0, Vec::len);
+        }
 
-            if self.pending_assets.contains_key(&model_asset) {
-                continue;
-            }
-            if !self.assets.contains_key(&model_asset) {
-                let (k_sender, k_receiver) = mpsc::channel();
-                let (d_sender, d_receiver) = mpsc::channel();
-
-                let asset_work = AssetWork {
-                    mesh: mesh_ref.clone(),
-                    bind_group_info: pipeline
-                        .bind_group_layout(crate::bind_group::BindGroupType::Material),
-                    receiver: k_receiver,
-                    sender: d_sender,
-                };
-
-                self.asset_workers.dispatch(asset_work);
-
-                let pending_work = PendingWork {
-                    killer: k_sender,
-                    complete: d_receiver,
-                };
-                self.pending_assets.insert(model_asset, pending_work);
-                continue;
+        self.pending_assets.retain(|asset, pending_work| {
+            if instance_bundle.contains_key(asset) {
+                return true;
             }
+            let _ = pending_work.killer.send(true);
+            false
+        });
+        self.failed_assets
+            .retain(|asset| instance_bundle.contains_key(asset));
+        self.deferred_assets
+            .retain(|asset| instance_bundle.contains_key(asset));
+    }
 
-            if !instance_bundle.contains_key(&model_asset) {
-                instance_bundle.insert(model_asset, Vec::new());
-            }
-            instance_bundle
-                .get_mut(&model_asset)
-                .unwrap()
-                .push(instance)
+    /// Memory the budget applies to, which leaves out the terrain arena.
+    fn memory_usage(&self) -> u64 {
+        self.resident_bytes + self.material_cache.memory_usage()
+    }
+
+    /// Evicts unreferenced assets, least recently drawn first, until everything fits the
+    /// memory budget. Assets that are drawn are never evicted; new loads are deferred instead.
+    fn evict_over_budget(&mut self) {
+        if self.memory_usage() <= self.memory_budget {
+            self.material_cache.purge();
+            return;
         }
 
-        let mut current_scene: Option<Box<Scene>> = None;
-        for (asset, _) in &instance_bundle {
-            if !self.assets.contains_key(&asset) {
-                self.assets
-                    .insert(*asset, load_asset(asset, pipeline, display));
+        let mut candidates = self
+            .assets
+            .iter()
+            .filter(|(_, resident)| resident.references == 0)
+            .map(|(asset, resident)| (resident.last_used, *asset))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(last_used, _)| *last_used);
+
+        for (_, asset) in candidates {
+            // Materials are only freed once no resident model shares them anymore.
+            self.material_cache.purge();
+            if self.memory_usage() <= self.memory_budget {
+                break;
             }
+            let resident = self.assets.remove(&asset).unwrap();
+            self.resident_bytes -= resident.memory_usage;
+            self.evicted_assets += 1;
+            log::debug!(
+                "Evicted asset {:?} ({} bytes)",
+                asset,
+                resident.memory_usage
+            );
         }
-        for (asset, instances) in instance_bundle {
-            current_scene = Some(Box::new(Scene {
-                model: &self.assets.get(&asset).unwrap(),
-                instances,
-                next: current_scene,
-            }))
+        self.material_cache.purge();
+    }
+
+    fn can_dispatch(&self, model_asset: &ModelAsset) -> bool {
+        !self.assets.contains_key(model_asset)
+            && !self.pending_assets.contains_key(model_asset)
+            && !self.failed_assets.contains(model_asset)
+    }
+
+    fn dispatch<P: Pipeline>(
+        &mut self,
+        model_asset: ModelAsset,
+        source: AssetSource,
+        pipeline: &P,
+    ) {
+        let (k_sender, k_receiver) = mpsc::channel();
+        let (d_sender, d_receiver) = mpsc::channel();
+
+        let asset_work = AssetWork {
+            source,
+            bind_group_info: pipeline.bind_group_layout(BindGroupType::Material),
+            receiver: k_receiver,
+            sender: d_sender,
+        };
+
+        if let Err(err) = self.asset_workers.dispatch(asset_work) {
+            log::error!("Failed to dispatch asset {:?}: {:?}", model_asset, err);
+            self.failed_assets.insert(model_asset);
+            return;
         }
 
-        current_scene
+        let pending_work = PendingWork {
+            killer: k_sender,
+            complete: d_receiver,
+        };
+        self.pending_assets.insert(model_asset, pending_work);
     }
 }
 
@@ -157,13 +410,27 @@ pub struct AssetWorker {
 pub struct AssetWorkerInitializer {
     device: Arc<wgpu::Device>,
     queue: Arc<wgpu::Queue>,
+    material_cache: Arc<MaterialCache>,
+}
+
+pub enum AssetSource {
+    Obj {
+        name: String,
+        path: PathBuf,
+        materials: Vec<MaterialOverride>,
+        normals: GeneratedNormals,
+    },
+    Gltf {
+        name: String,
+        path: PathBuf,
+    },
 }
 
 pub struct AssetWork {
-    mesh: MeshReference,
+    source: AssetSource,
     bind_group_info: Option<Arc<PipelineBindGroupInfo>>,
     receiver: mpsc::Receiver<bool>,
-    sender: mpsc::Sender<Model>,
+    sender: mpsc::Sender<Result<Model>>,
 }
 
 impl Worker<AssetWork, AssetWorkerInitializer> for AssetWorker {
@@ -176,6 +443,7 @@ impl Worker<AssetWork, AssetWorkerInitializer> for AssetWorker {
             device: Arc::clone(&bundle.device),
 
             queue: Arc::clone(&bundle.queue),
+            material_cache: Arc::clone(&bundle.material_cache),
         };
         let thread = thread::spawn(move || loop {
             let work = receiver.lock().unwrap().recv().unwrap();
@@ -199,45 +467,75 @@ impl Worker<AssetWork, AssetWorkerInitializer> for AssetWorker {
 pub struct AssetExecutor {
     device: Arc<wgpu::Device>,
     queue: Arc<wgpu::Queue>,
+    material_cache: Arc<MaterialCache>,
 }
 
 impl AssetExecutor {
     fn execute(&self, data: AssetWork) {
-        let resources = std::path::Path::new(env!("OUT_DIR")).join("resources");
-        data.sender.send(
-            Model::load_from_vertex_data(
-                "dynamic".to_string(),
+        let model = match data.source {
+            AssetSource::Obj {
+                name,
+                path,
+                materials,
+                normals,
+            } => Model::load(
+                name,
+                &self.device,
+                &self.queue,
+                data.bind_group_info,
+                path,
+                ObjOptions {
+                    material_overrides: &materials,
+                    generated_normals: normals,
+                },
+                &self.material_cache,
+            ),
+            AssetSource::Gltf { name, path } => Model::load_gltf(
+                name,
                 &self.device,
                 &self.queue,
                 data.bind_group_info,
-                &data.mesh.vertex_data,
-                &data.mesh.index_data,
-                resources.join("blockatlas.jpg"),
-            )
-            .unwrap(),
-        );
+                path,
+                &self.material_cache,
+            ),
+        };
+        // The scene manager may have stopped waiting on this asset, in which case the result is
+        // dropped.
+        let _ = data.sender.send(model);
     }
 }
 
-fn load_asset<P: Pipeline>(model_asset: &ModelAsset, pipeline: &P, display: &Display) -> Model {
-    let resources = std::path::Path::new(env!("OUT_DIR")).join("resources");
+fn to_instance(transform: &component::Transform) -> Instance {
+    let q: cgmath::Quaternion<f32> = transform.rotation.into();
+    Instance::new(transform.position.clone(), q.normalize())
+}
+
+fn asset_source(registry: &AssetRegistry, model_asset: &ModelAsset) -> Result<AssetSource> {
     match model_asset {
-        ModelAsset::Cube => Model::load(
-            "cube".to_string(),
-            display.device.as_ref(),
-            &display.queue,
-            pipeline.bind_group_layout(crate::bind_group::BindGroupType::Material),
-            resources.join("cube.obj"),
-        )
-        .unwrap(),
-        ModelAsset::Room => Model::load(
-            "room".to_string(),
-            &display.device,
-            &display.queue,
-            pipeline.bind_group_layout(crate::bind_group::BindGroupType::Material),
-            resources.join("viking_room.obj"),
-        )
-        .unwrap(),
-        _ => unimplemented!(),
+        ModelAsset::Manifest(handle) => {
+            let descriptor = registry
+                .descriptor(*handle)
+                .with_context(|| format!("No manifest entry for {:?}", handle))?;
+            match descriptor.kind {
+                AssetKind::Obj => Ok(AssetSource::Obj {
+                    name: descriptor.id.clone(),
+                    path: registry.path(descriptor),
+                    materials: descriptor.materials.clone(),
+                    normals: descriptor.normals,
+                }),
+                AssetKind::Gltf => {
+                    if !descriptor.materials.is_empty() {
+                        log::warn!(
+                            "Material overrides of {:?} are ignored for glTF assets",
+                            descriptor.id
+                        );
+                    }
+                    Ok(AssetSource::Gltf {
+                        name: descriptor.id.clone(),
+                        path: registry.path(descriptor),
+                    })
+                }
+            }
+        }
     }
 }
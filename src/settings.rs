@@ -0,0 +1,18 @@
+use crate::{
+    fog::FogConfig, hdr::HdrConfig, light::LightConfig, msaa::MsaaConfig, scene::AssetConfig,
+    shadow::ShadowConfig, sky::SkyConfig, ssao::SsaoConfig,
+};
+
+/// Rendering options that can be changed while the game is running. The renderer hands them to
+/// its pipeline at the start of every frame, and the asset options go to the scene manager.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RenderSettings {
+    pub lighting: LightConfig,
+    pub shadows: ShadowConfig,
+    pub sky: SkyConfig,
+    pub fog: FogConfig,
+    pub hdr: HdrConfig,
+    pub msaa: MsaaConfig,
+    pub ssao: SsaoConfig,
+    pub assets: AssetConfig,
+}
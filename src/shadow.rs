@@ -0,0 +1,470 @@
+use std::sync::Arc;
+
+use cgmath::{EuclideanSpace, InnerSpace, SquareMatrix};
+
+use crate::{
+    bind_group::{BindGroup, BindGroupType},
+    camera::{Camera, Projection, OPENGL_TO_WGPU_MATRIX},
+    display::Display,
+    instance::InstanceRaw,
+    light::LightRaw,
+    math::Frustum,
+    mesh::{MeshVertex, Vertex},
+    pipeline::PipelineBindGroupInfo,
+    texture::Texture,
+};
+
+pub const MAX_CASCADES: usize = 4;
+
+/// Each cascade's matrix lives at its own dynamic offset, which has to be aligned to this.
+const CASCADE_STRIDE: u64 = 256;
+/// How far behind a cascade shadow casters are still picked up, in world units.
+const CASTER_DISTANCE: f32 = 100.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowConfig {
+    pub enabled: bool,
+    pub cascade_count: u32,
+    /// Width and height of every cascade's shadow map.
+    pub resolution: u32,
+    /// Subtracted from the receiver depth before it is compared against the shadow map.
+    pub depth_bias: f32,
+    /// Moves receivers along their normal, in world units, before the shadow map is sampled.
+    pub normal_bias: f32,
+    /// View distance covered by the last cascade.
+    pub max_distance: f32,
+    /// Blend between uniform (0) and logarithmic (1) cascade splits.
+    pub split_lambda: f32,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cascade_count: MAX_CASCADES as u32,
+            resolution: 2048,
+            depth_bias: 0.0005,
+            normal_bias: 0.1,
+            max_distance: 200.0,
+            split_lambda: 0.75,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowUniforms {
+    cascades: [[[f32; 4]; 4]; MAX_CASCADES],
+    /// Far view distance of every cascade.
+    splits: [f32; 4],
+    view_forward: [f32; 4],
+    /// Active cascade count (zero while shadows are off), depth bias, normal bias and texel size.
+    params: [f32; 4],
+}
+
+/// Cascaded shadow maps for the sun. Every cascade covers a slice of the camera frustum and is
+/// rendered into its own layer of a depth texture array, bound at set 3 of the main pipeline.
+pub struct ShadowMap {
+    config: ShadowConfig,
+    bind_group_info: Arc<PipelineBindGroupInfo>,
+    /// Only the views are used, but the texture has to outlive them.
+    _texture: wgpu::Texture,
+    layer_views: Vec<wgpu::TextureView>,
+    sampler: wgpu::Sampler,
+    uniforms: ShadowUniforms,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    cascade_buffer: wgpu::Buffer,
+    cascade_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    frustums: Vec<Frustum>,
+}
+
+impl ShadowMap {
+    pub fn new(display: &Display, bind_group_info: Arc<PipelineBindGroupInfo>) -> Self {
+        let device = &display.device;
+        let config = ShadowConfig::default();
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Uniform Buffer"),
+            size: std::mem::size_of::<ShadowUniforms>() as u64,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let cascade_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Cascade Buffer"),
+            size: CASCADE_STRIDE * MAX_CASCADES as u64,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let cascade_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("shadow_cascade_bind_group_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let cascade_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow_cascade_bind_group"),
+            layout: &cascade_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &cascade_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(std::mem::size_of::<[[f32; 4]; 4]>() as u64),
+                },
+            }],
+        });
+        let pipeline = Self::create_pipeline(device, &cascade_layout);
+
+        let (texture, layer_views, bind_group) =
+            Self::create_maps(device, &bind_group_info, &config, &sampler, &uniform_buffer);
+
+        Self {
+            config,
+            bind_group_info,
+            _texture: texture,
+            layer_views,
+            sampler,
+            uniforms: ShadowUniforms {
+                cascades: [cgmath::Matrix4::identity().into(); MAX_CASCADES],
+                splits: [0.0; 4],
+                view_forward: [0.0; 4],
+                params: [0.0; 4],
+            },
+            uniform_buffer,
+            bind_group,
+            cascade_buffer,
+            cascade_bind_group,
+            pipeline,
+            frustums: Vec::new(),
+        }
+    }
+
+    /// Applies new settings, recreating the shadow maps when their size or layer count changed.
+    pub fn configure(&mut self, display: &Display, mut config: ShadowConfig) {
+        config.cascade_count = config.cascade_count.max(1).min(MAX_CASCADES as u32);
+        if config.resolution != self.config.resolution
+            || config.cascade_count != self.config.cascade_count
+        {
+            let (texture, layer_views, bind_group) = Self::create_maps(
+                &display.device,
+                &self.bind_group_info,
+                &config,
+                &self.sampler,
+                &self.uniform_buffer,
+            );
+            self._texture = texture;
+            self.layer_views = layer_views;
+            self.bind_group = bind_group;
+        }
+        self.config = config;
+    }
+
+    /// Fits the cascades to the camera frustum as seen from the sun. Without a sun, or while
+    /// shadows are disabled, there are no cascades to render.
+    pub fn update(&mut self, camera: &Camera, projection: &Projection, sun: Option<&LightRaw>) {
+        self.frustums.clear();
+        self.uniforms.params = [
+            0.0,
+            self.config.depth_bias,
+            self.config.normal_bias,
+            1.0 / self.config.resolution as f32,
+        ];
+        let sun = match sun {
+            Some(sun) if self.config.enabled => sun,
+            _ => return,
+        };
+
+        let count = self.config.cascade_count as usize;
+        let near = projection.znear();
+        let far = self.config.max_distance.min(projection.zfar()).max(near);
+        let view = camera.calc_matrix();
+        let mut split_near = near;
+        for cascade in 0..count {
+            let split_far = cascade_split(near, far, cascade + 1, count, self.config.split_lambda);
+            let slice = projection.calc_matrix_for_range(split_near, split_far) * view;
+            let matrix = self.fit_cascade(&slice, sun.direction());
+            self.uniforms.cascades[cascade] = matrix.into();
+            self.uniforms.splits[cascade] = split_far;
+            self.frustums.push(Frustum::from_matrix(&matrix));
+            split_near = split_far;
+        }
+        self.uniforms.view_forward = camera.forward().extend(0.0).into();
+        self.uniforms.params[0] = count as f32;
+    }
+
+    /// Light space matrix for an orthographic box around a bounding sphere of `slice`. The
+    /// sphere keeps the box size constant while the camera turns, and the box is moved in whole
+    /// texels so shadow edges do not shimmer while the camera moves.
+    fn fit_cascade(
+        &self,
+        slice: &cgmath::Matrix4<f32>,
+        direction: cgmath::Vector3<f32>,
+    ) -> cgmath::Matrix4<f32> {
+        let inverse = slice.invert().unwrap_or_else(cgmath::Matrix4::identity);
+        let mut corners = [cgmath::Vector3::new(0.0, 0.0, 0.0); 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let ndc = cgmath::Vector4::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { 0.0 } else { 1.0 },
+                1.0,
+            );
+            let world = inverse * ndc;
+            *corner = world.truncate() / world.w;
+        }
+        let center = corners
+            .iter()
+            .fold(cgmath::Vector3::new(0.0, 0.0, 0.0), |sum, c| sum + c)
+            / corners.len() as f32;
+        let radius = corners
+            .iter()
+            .map(|corner| (corner - center).magnitude())
+            .fold(0.0f32, f32::max);
+        let radius = (radius * 16.0).ceil() / 16.0;
+
+        let up = if direction.y.abs() > 0.99 {
+            cgmath::Vector3::unit_z()
+        } else {
+            cgmath::Vector3::unit_y()
+        };
+        let center = cgmath::Point3::from_vec(center);
+        let eye = center - direction * (radius + CASTER_DISTANCE);
+        let light_view = cgmath::Matrix4::look_at_rh(eye, center, up);
+        let mut light_projection = cgmath::ortho(
+            -radius,
+            radius,
+            -radius,
+            radius,
+            0.0,
+            2.0 * radius + CASTER_DISTANCE,
+        );
+
+        let half_resolution = self.config.resolution as f32 / 2.0;
+        let origin = (light_projection * light_view).w * half_resolution;
+        light_projection.w.x += (origin.x.round() - origin.x) / half_resolution;
+        light_projection.w.y += (origin.y.round() - origin.y) / half_resolution;
+
+        OPENGL_TO_WGPU_MATRIX * light_projection * light_view
+    }
+
+    /// Frustums of the cascades rendered this frame, used to cull shadow casters.
+    pub fn cascades(&self) -> &[Frustum] {
+        &self.frustums
+    }
+
+    pub fn prepare(&self, display: &Display) {
+        display.queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms]),
+        );
+        for (cascade, matrix) in self.uniforms.cascades[..self.frustums.len()]
+            .iter()
+            .enumerate()
+        {
+            display.queue.write_buffer(
+                &self.cascade_buffer,
+                cascade as u64 * CASCADE_STRIDE,
+                bytemuck::cast_slice(&[*matrix]),
+            );
+        }
+    }
+
+    /// Starts the depth only pass of one cascade. Geometry drawn into it needs vertex buffers
+    /// laid out like the main pipeline's.
+    pub fn render_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        cascade: usize,
+    ) -> wgpu::RenderPass<'a> {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Shadow Pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.layer_views[cascade],
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(
+            0,
+            &self.cascade_bind_group,
+            &[(cascade as u64 * CASCADE_STRIDE) as wgpu::DynamicOffset],
+        );
+        render_pass
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    fn create_maps(
+        device: &wgpu::Device,
+        bind_group_info: &PipelineBindGroupInfo,
+        config: &ShadowConfig,
+        sampler: &wgpu::Sampler,
+        uniform_buffer: &wgpu::Buffer,
+    ) -> (wgpu::Texture, Vec<wgpu::TextureView>, wgpu::BindGroup) {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Shadow Map"),
+            size: wgpu::Extent3d {
+                width: config.resolution,
+                height: config.resolution,
+                depth: config.cascade_count,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+        let layer_views = (0..config.cascade_count)
+            .map(|layer| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("Shadow Cascade View"),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        let array_view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Shadow Map View"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow_bind_group"),
+            layout: &bind_group_info.layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&array_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        (texture, layer_views, bind_group)
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        cascade_layout: &wgpu::BindGroupLayout,
+    ) -> wgpu::RenderPipeline {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow Pipeline Layout"),
+            bind_group_layouts: &[cascade_layout],
+            push_constant_ranges: &[],
+        });
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!(
+            "../resources/shaders/shadow.vert.spv"
+        ));
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Shadow Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[MeshVertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: None,
+            // Terrain faces are one sided, so back faces have to cast shadows as well.
+            primitive: wgpu::PrimitiveState {
+                cull_mode: wgpu::CullMode::None,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: Default::default(),
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+                clamp_depth: false,
+            }),
+            multisample: Default::default(),
+        })
+    }
+}
+
+impl BindGroup for ShadowMap {
+    fn desc<'a>() -> wgpu::BindGroupLayoutDescriptor<'a> {
+        wgpu::BindGroupLayoutDescriptor {
+            label: Some("shadow_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: true,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        }
+    }
+
+    fn bind_group_type() -> BindGroupType {
+        BindGroupType::Shadow
+    }
+}
+
+/// Far distance of the `index`th of `count` cascades, blending logarithmic and uniform splits.
+fn cascade_split(near: f32, far: f32, index: usize, count: usize, lambda: f32) -> f32 {
+    let fraction = index as f32 / count as f32;
+    let logarithmic = near * (far / near).powf(fraction);
+    let uniform = near + (far - near) * fraction;
+    lambda * logarithmic + (1.0 - lambda) * uniform
+}
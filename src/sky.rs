@@ -0,0 +1,285 @@
+use crate::{
+    display::Display,
+    ecs::component::Sky,
+    hdr::HDR_FORMAT,
+    ibl::{CubeFace, CUBE_FACES},
+    texture::Texture,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkyConfig {
+    pub stars: bool,
+    /// Angular radius of the sun and moon discs, in degrees.
+    pub disc_size: f32,
+}
+
+impl Default for SkyConfig {
+    fn default() -> Self {
+        Self {
+            stars: true,
+            disc_size: 1.5,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyUniforms {
+    inverse_view_proj: [[f32; 4]; 4],
+    view_position: [f32; 4],
+    /// `w` holds the cosine of the disc radius.
+    sun_direction: [f32; 4],
+    moon_direction: [f32; 4],
+    zenith_color: [f32; 4],
+    horizon_color: [f32; 4],
+    sun_color: [f32; 4],
+    /// Star visibility.
+    params: [f32; 4],
+}
+
+impl SkyUniforms {
+    fn new(
+        sky: &Sky,
+        config: &SkyConfig,
+        star_visibility: f32,
+        view_position: cgmath::Vector4<f32>,
+        inverse_view_proj: cgmath::Matrix4<f32>,
+    ) -> Self {
+        let disc = config.disc_size.to_radians().cos();
+        let color = |c: [f32; 3]| [c[0], c[1], c[2], 1.0];
+        Self {
+            inverse_view_proj: inverse_view_proj.into(),
+            view_position: view_position.into(),
+            sun_direction: sky.sun_direction.extend(disc).into(),
+            moon_direction: sky.moon_direction.extend(disc).into(),
+            zenith_color: color(sky.zenith_color),
+            horizon_color: color(sky.horizon_color),
+            sun_color: color(sky.sun_color),
+            params: [star_visibility, 0.0, 0.0, 0.0],
+        }
+    }
+}
+
+/// Draws the procedural sky behind everything else. It runs inside the main pass after the
+/// opaque geometry and only covers pixels still at the far plane.
+pub struct SkyPass {
+    pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    sample_count: u32,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// Renders the sky into the faces of an environment cubemap.
+    capture_pipeline: wgpu::RenderPipeline,
+    capture_faces: Vec<(wgpu::Buffer, wgpu::BindGroup)>,
+}
+
+impl SkyPass {
+    pub fn new(display: &Display) -> Self {
+        let device = &display.device;
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sky Uniform Buffer"),
+            size: std::mem::size_of::<SkyUniforms>() as u64,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("sky_bind_group_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let create_bind_group = |uniform_buffer: &wgpu::Buffer| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("sky_bind_group"),
+                layout: &layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                }],
+            })
+        };
+        let bind_group = create_bind_group(&uniform_buffer);
+        let capture_faces = CUBE_FACES
+            .iter()
+            .map(|_| {
+                let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Sky Capture Uniform Buffer"),
+                    size: std::mem::size_of::<SkyUniforms>() as u64,
+                    usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                let bind_group = create_bind_group(&buffer);
+                (buffer, bind_group)
+            })
+            .collect();
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Sky Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = Self::create_pipeline(device, &pipeline_layout, 1, true);
+        let capture_pipeline = Self::create_pipeline(device, &pipeline_layout, 1, false);
+
+        Self {
+            pipeline,
+            pipeline_layout,
+            sample_count: 1,
+            uniform_buffer,
+            bind_group,
+            capture_pipeline,
+            capture_faces,
+        }
+    }
+
+    /// Rebuilds the pipeline when the main pass changes its sample count.
+    pub fn set_sample_count(&mut self, display: &Display, sample_count: u32) {
+        if sample_count != self.sample_count {
+            self.sample_count = sample_count;
+            self.pipeline =
+                Self::create_pipeline(&display.device, &self.pipeline_layout, sample_count, true);
+        }
+    }
+
+    pub fn update(
+        &self,
+        display: &Display,
+        sky: &Sky,
+        config: &SkyConfig,
+        view_position: cgmath::Vector4<f32>,
+        view_projection: cgmath::Matrix4<f32>,
+    ) {
+        use cgmath::SquareMatrix;
+        let uniforms = SkyUniforms::new(
+            sky,
+            config,
+            if config.stars {
+                sky.star_visibility
+            } else {
+                0.0
+            },
+            view_position,
+            view_projection
+                .invert()
+                .unwrap_or_else(cgmath::Matrix4::identity),
+        );
+        display
+            .queue
+            .write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniforms]));
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    /// Renders the sky as seen from the origin into the six faces of a cubemap, in the order of
+    /// `CUBE_FACES`. Stars are left out, as they would only add noise to the lighting.
+    pub fn capture(
+        &self,
+        display: &Display,
+        encoder: &mut wgpu::CommandEncoder,
+        sky: &Sky,
+        config: &SkyConfig,
+        faces: &[wgpu::TextureView],
+    ) {
+        for ((buffer, bind_group), (face, view)) in
+            self.capture_faces.iter().zip(CUBE_FACES.iter().zip(faces))
+        {
+            let uniforms = SkyUniforms::new(
+                sky,
+                config,
+                0.0,
+                cgmath::Vector4::new(0.0, 0.0, 0.0, 1.0),
+                face_matrix(face),
+            );
+            display
+                .queue
+                .write_buffer(buffer, 0, bytemuck::cast_slice(&[uniforms]));
+
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Sky Capture"),
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(&self.capture_pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        pipeline_layout: &wgpu::PipelineLayout,
+        sample_count: u32,
+        depth_test: bool,
+    ) -> wgpu::RenderPipeline {
+        let vs_module =
+            device.create_shader_module(&wgpu::include_spirv!("../resources/shaders/sky.vert.spv"));
+        let fs_module =
+            device.create_shader_module(&wgpu::include_spirv!("../resources/shaders/sky.frag.spv"));
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Sky Pipeline"),
+            layout: Some(pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: HDR_FORMAT,
+                    color_blend: wgpu::BlendState::REPLACE,
+                    alpha_blend: wgpu::BlendState::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: Default::default(),
+            depth_stencil: if depth_test {
+                Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare: wgpu::CompareFunction::LessEqual,
+                    stencil: Default::default(),
+                    bias: Default::default(),
+                    clamp_depth: false,
+                })
+            } else {
+                None
+            },
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+/// Maps the far plane of the screen onto a cube face, so that a screen position `(x, y)` looks
+/// along `forward + x * right + y * up`.
+fn face_matrix(face: &CubeFace) -> cgmath::Matrix4<f32> {
+    let axis = |v: [f32; 3]| cgmath::Vector4::new(v[0], v[1], v[2], 0.0);
+    cgmath::Matrix4::from_cols(
+        axis(face.right),
+        axis(face.up),
+        axis(face.forward),
+        cgmath::Vector4::new(0.0, 0.0, 0.0, 1.0),
+    )
+}
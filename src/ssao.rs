@@ -0,0 +1,630 @@
+use cgmath::{InnerSpace, SquareMatrix};
+
+use crate::{
+    display::Display,
+    instance::InstanceRaw,
+    mesh::{MeshVertex, Vertex},
+    texture::Texture,
+};
+
+/// Size of the kernel in the shader, the most samples a pixel can take.
+pub const MAX_SAMPLES: u32 = 64;
+/// Width and height of the tiled noise texture that rotates the kernel per pixel.
+const NOISE_SIZE: u32 = 4;
+const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const OCCLUSION_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SsaoConfig {
+    pub enabled: bool,
+    /// Radius of the sampled hemisphere, in world units.
+    pub radius: f32,
+    pub samples: u32,
+    /// Depth difference below which a sample does not occlude, against self occlusion.
+    pub bias: f32,
+    /// Exponent applied to the visibility, darkening the occlusion.
+    pub intensity: f32,
+}
+
+impl Default for SsaoConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            radius: 0.5,
+            samples: 16,
+            bias: 0.025,
+            intensity: 1.5,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PrepassUniforms {
+    view: [[f32; 4]; 4],
+    view_proj: [[f32; 4]; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SsaoUniforms {
+    projection: [[f32; 4]; 4],
+    inverse_projection: [[f32; 4]; 4],
+    /// Sample count, radius, bias and intensity.
+    params: [f32; 4],
+    /// Noise tiling, then texel size.
+    scale: [f32; 4],
+    kernel: [[f32; 4]; MAX_SAMPLES as usize],
+}
+
+struct RenderTarget {
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+}
+
+/// The size dependent targets, which only exist while SSAO is enabled.
+struct SsaoTextures {
+    normal: RenderTarget,
+    depth: Texture,
+    occlusion: RenderTarget,
+    blurred: RenderTarget,
+    ssao_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+}
+
+/// Screen space ambient occlusion. A prepass renders view space normals and depth, from which
+/// the occlusion is estimated and blurred. The main pipeline applies it to ambient light.
+pub struct SsaoPass {
+    config: SsaoConfig,
+    textures: Option<SsaoTextures>,
+    kernel: [[f32; 4]; MAX_SAMPLES as usize],
+    _noise_texture: wgpu::Texture,
+    noise: wgpu::TextureView,
+    clamp_sampler: wgpu::Sampler,
+    repeat_sampler: wgpu::Sampler,
+    prepass_buffer: wgpu::Buffer,
+    prepass_bind_group: wgpu::BindGroup,
+    uniform_buffer: wgpu::Buffer,
+    ssao_layout: wgpu::BindGroupLayout,
+    blur_layout: wgpu::BindGroupLayout,
+    prepass_pipeline: wgpu::RenderPipeline,
+    ssao_pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+}
+
+impl SsaoPass {
+    pub fn new(display: &Display) -> Self {
+        let device = &display.device;
+        let mut seed = 0x9e37_79b9;
+        let kernel = create_kernel(&mut seed);
+        let (noise_texture, noise) = create_noise(display, &mut seed);
+
+        let clamp_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("SSAO Clamp Sampler"),
+            ..Default::default()
+        });
+        let repeat_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("SSAO Repeat Sampler"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            ..Default::default()
+        });
+
+        let uniform_entry = |binding, visibility| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Sampler {
+                comparison: false,
+                filtering: false,
+            },
+            count: None,
+        };
+
+        let prepass_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ssao_prepass_bind_group_layout"),
+            entries: &[uniform_entry(0, wgpu::ShaderStage::VERTEX)],
+        });
+        let ssao_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ssao_bind_group_layout"),
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                texture_entry(2),
+                sampler_entry(3),
+                sampler_entry(4),
+                uniform_entry(5, wgpu::ShaderStage::FRAGMENT),
+            ],
+        });
+        let blur_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ssao_blur_bind_group_layout"),
+            entries: &[
+                texture_entry(0),
+                sampler_entry(1),
+                uniform_entry(2, wgpu::ShaderStage::FRAGMENT),
+            ],
+        });
+
+        let prepass_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SSAO Prepass Buffer"),
+            size: std::mem::size_of::<PrepassUniforms>() as u64,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let prepass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("ssao_prepass_bind_group"),
+            layout: &prepass_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: prepass_buffer.as_entire_binding(),
+            }],
+        });
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SSAO Uniform Buffer"),
+            size: std::mem::size_of::<SsaoUniforms>() as u64,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let prepass_pipeline = create_prepass_pipeline(device, &prepass_layout);
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!(
+            "../resources/shaders/fullscreen.vert.spv"
+        ));
+        let ssao_pipeline = create_fullscreen_pipeline(
+            device,
+            "SSAO Pipeline",
+            &ssao_layout,
+            &vs_module,
+            &wgpu::include_spirv!("../resources/shaders/ssao.frag.spv"),
+        );
+        let blur_pipeline = create_fullscreen_pipeline(
+            device,
+            "SSAO Blur Pipeline",
+            &blur_layout,
+            &vs_module,
+            &wgpu::include_spirv!("../resources/shaders/ssao_blur.frag.spv"),
+        );
+
+        Self {
+            config: SsaoConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            textures: None,
+            kernel,
+            _noise_texture: noise_texture,
+            noise,
+            clamp_sampler,
+            repeat_sampler,
+            prepass_buffer,
+            prepass_bind_group,
+            uniform_buffer,
+            ssao_layout,
+            blur_layout,
+            prepass_pipeline,
+            ssao_pipeline,
+            blur_pipeline,
+        }
+    }
+
+    /// Applies new settings, and returns true when the occlusion texture was created or
+    /// dropped, so it has to be bound again.
+    pub fn configure(&mut self, display: &Display, config: SsaoConfig) -> bool {
+        let toggled = config.enabled != self.config.enabled;
+        self.config = config;
+        if toggled {
+            self.textures = if config.enabled {
+                Some(self.create_textures(display))
+            } else {
+                None
+            };
+        }
+        toggled
+    }
+
+    pub fn resize(&mut self, display: &Display) {
+        if self.config.enabled {
+            self.textures = Some(self.create_textures(display));
+        }
+    }
+
+    /// The blurred occlusion of the last frame, while SSAO is enabled.
+    pub fn occlusion(&self) -> Option<&wgpu::TextureView> {
+        self.textures
+            .as_ref()
+            .map(|textures| &textures.blurred.view)
+    }
+
+    pub fn update(
+        &self,
+        display: &Display,
+        view: cgmath::Matrix4<f32>,
+        projection: cgmath::Matrix4<f32>,
+    ) {
+        if !self.config.enabled {
+            return;
+        }
+        let prepass = PrepassUniforms {
+            view: view.into(),
+            view_proj: (projection * view).into(),
+        };
+        display
+            .queue
+            .write_buffer(&self.prepass_buffer, 0, bytemuck::cast_slice(&[prepass]));
+
+        let (width, height) = (
+            display.swap_chain_descriptor.width as f32,
+            display.swap_chain_descriptor.height as f32,
+        );
+        let uniforms = SsaoUniforms {
+            projection: projection.into(),
+            inverse_projection: projection
+                .invert()
+                .unwrap_or_else(cgmath::Matrix4::identity)
+                .into(),
+            params: [
+                self.config.samples.min(MAX_SAMPLES) as f32,
+                self.config.radius,
+                self.config.bias,
+                self.config.intensity,
+            ],
+            scale: [
+                width / NOISE_SIZE as f32,
+                height / NOISE_SIZE as f32,
+                1.0 / width,
+                1.0 / height,
+            ],
+            kernel: self.kernel,
+        };
+        display
+            .queue
+            .write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniforms]));
+    }
+
+    /// Begins the depth and normal prepass, ready for the same geometry as the main pass. There
+    /// is no prepass while SSAO is disabled.
+    pub fn prepass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+    ) -> Option<wgpu::RenderPass<'a>> {
+        let textures = self.textures.as_ref()?;
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("SSAO Prepass"),
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &textures.normal.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                        r: 0.0,
+                        g: 0.0,
+                        b: 1.0,
+                        a: 1.0,
+                    }),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &textures.depth.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_pipeline(&self.prepass_pipeline);
+        render_pass.set_bind_group(0, &self.prepass_bind_group, &[]);
+        Some(render_pass)
+    }
+
+    /// Estimates the occlusion from the prepass and blurs it.
+    pub fn run(&self, encoder: &mut wgpu::CommandEncoder) {
+        let textures = match &self.textures {
+            Some(textures) => textures,
+            None => return,
+        };
+        fullscreen_pass(
+            encoder,
+            "SSAO Pass",
+            &textures.occlusion.view,
+            &self.ssao_pipeline,
+            &textures.ssao_bind_group,
+        );
+        fullscreen_pass(
+            encoder,
+            "SSAO Blur Pass",
+            &textures.blurred.view,
+            &self.blur_pipeline,
+            &textures.blur_bind_group,
+        );
+    }
+
+    fn create_textures(&self, display: &Display) -> SsaoTextures {
+        let device = &display.device;
+        let normal = create_target(display, "SSAO Normal Target", NORMAL_FORMAT);
+        let depth = Texture::create_depth_texture(
+            device,
+            &display.swap_chain_descriptor,
+            1,
+            "ssao_depth_texture",
+        );
+        let occlusion = create_target(display, "SSAO Target", OCCLUSION_FORMAT);
+        let blurred = create_target(display, "SSAO Blur Target", OCCLUSION_FORMAT);
+
+        let ssao_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("ssao_bind_group"),
+            layout: &self.ssao_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&depth.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&normal.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&self.noise),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&self.clamp_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&self.repeat_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        let blur_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("ssao_blur_bind_group"),
+            layout: &self.blur_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&occlusion.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.clamp_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        SsaoTextures {
+            normal,
+            depth,
+            occlusion,
+            blurred,
+            ssao_bind_group,
+            blur_bind_group,
+        }
+    }
+}
+
+/// A small xorshift generator, so the kernel and noise are the same on every run.
+fn random(seed: &mut u32) -> f32 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 17;
+    *seed ^= *seed << 5;
+    *seed as f32 / u32::MAX as f32
+}
+
+/// Sample offsets in the unit hemisphere around +Z, scaled so most of them lie close to the
+/// centre where occlusion matters most.
+fn create_kernel(seed: &mut u32) -> [[f32; 4]; MAX_SAMPLES as usize] {
+    let mut kernel = [[0.0; 4]; MAX_SAMPLES as usize];
+    for (i, sample) in kernel.iter_mut().enumerate() {
+        let direction = cgmath::Vector3::new(
+            random(seed) * 2.0 - 1.0,
+            random(seed) * 2.0 - 1.0,
+            random(seed),
+        )
+        .normalize();
+        let t = i as f32 / MAX_SAMPLES as f32;
+        let scale = 0.1 + 0.9 * t * t;
+        let offset = direction * random(seed) * scale;
+        *sample = [offset.x, offset.y, offset.z, 0.0];
+    }
+    kernel
+}
+
+/// Random rotations around the normal, tiled over the screen.
+fn create_noise(display: &Display, seed: &mut u32) -> (wgpu::Texture, wgpu::TextureView) {
+    let texels = (0..NOISE_SIZE * NOISE_SIZE)
+        .flat_map(|_| {
+            let x = random(seed) * 2.0 - 1.0;
+            let y = random(seed) * 2.0 - 1.0;
+            vec![(x * 127.0) as i8 as u8, (y * 127.0) as i8 as u8, 0, 0]
+        })
+        .collect::<Vec<_>>();
+    let size = wgpu::Extent3d {
+        width: NOISE_SIZE,
+        height: NOISE_SIZE,
+        depth: 1,
+    };
+    let texture = display.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("SSAO Noise"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Snorm,
+        usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+    });
+    display.queue.write_texture(
+        wgpu::TextureCopyView {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        &texels,
+        wgpu::TextureDataLayout {
+            offset: 0,
+            bytes_per_row: 4 * NOISE_SIZE,
+            rows_per_image: NOISE_SIZE,
+        },
+        size,
+    );
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    (texture, view)
+}
+
+fn create_target(display: &Display, label: &str, format: wgpu::TextureFormat) -> RenderTarget {
+    let texture = display.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: display.swap_chain_descriptor.width,
+            height: display.swap_chain_descriptor.height,
+            depth: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    RenderTarget {
+        _texture: texture,
+        view,
+    }
+}
+
+fn fullscreen_pass(
+    encoder: &mut wgpu::CommandEncoder,
+    label: &str,
+    target: &wgpu::TextureView,
+    pipeline: &wgpu::RenderPipeline,
+    bind_group: &wgpu::BindGroup,
+) {
+    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+            attachment: target,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                store: true,
+            },
+        }],
+        depth_stencil_attachment: None,
+    });
+    render_pass.set_pipeline(pipeline);
+    render_pass.set_bind_group(0, bind_group, &[]);
+    render_pass.draw(0..3, 0..1);
+}
+
+fn create_prepass_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+) -> wgpu::RenderPipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("SSAO Prepass Pipeline Layout"),
+        bind_group_layouts: &[layout],
+        push_constant_ranges: &[],
+    });
+    let vs_module = device.create_shader_module(&wgpu::include_spirv!(
+        "../resources/shaders/ssao_prepass.vert.spv"
+    ));
+    let fs_module = device.create_shader_module(&wgpu::include_spirv!(
+        "../resources/shaders/ssao_prepass.frag.spv"
+    ));
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("SSAO Prepass Pipeline"),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: &vs_module,
+            entry_point: "main",
+            buffers: &[MeshVertex::desc(), InstanceRaw::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &fs_module,
+            entry_point: "main",
+            targets: &[wgpu::ColorTargetState {
+                format: NORMAL_FORMAT,
+                color_blend: wgpu::BlendState::REPLACE,
+                alpha_blend: wgpu::BlendState::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        }),
+        primitive: wgpu::PrimitiveState {
+            cull_mode: wgpu::CullMode::Back,
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: Default::default(),
+            bias: Default::default(),
+            clamp_depth: false,
+        }),
+        multisample: Default::default(),
+    })
+}
+
+fn create_fullscreen_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    layout: &wgpu::BindGroupLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_src: &wgpu::ShaderModuleDescriptor,
+) -> wgpu::RenderPipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some(label),
+        bind_group_layouts: &[layout],
+        push_constant_ranges: &[],
+    });
+    let fs_module = device.create_shader_module(fs_src);
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: vs_module,
+            entry_point: "main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &fs_module,
+            entry_point: "main",
+            targets: &[wgpu::ColorTargetState {
+                format: OCCLUSION_FORMAT,
+                color_blend: wgpu::BlendState::REPLACE,
+                alpha_blend: wgpu::BlendState::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        }),
+        primitive: Default::default(),
+        depth_stencil: None,
+        multisample: Default::default(),
+    })
+}
@@ -0,0 +1,652 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::Arc,
+};
+
+use wgpu::util::DeviceExt;
+
+use crate::{
+    display::Display,
+    ecs::component::{MeshId, MeshReference, Transform},
+    instance::InstanceRaw,
+    material::Material,
+    math::{Boundary, Frustum},
+    mesh::MeshVertex,
+};
+
+const CULL_WORKGROUP_SIZE: u32 = 64;
+/// Dynamic offsets into uniform and storage buffers have to be aligned to this.
+const OFFSET_ALIGNMENT: u64 = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TerrainConfig {
+    pub max_chunks: u32,
+    /// Vertices and indices the arena starts out with room for. It grows on demand once loaded
+    /// chunks need more.
+    pub initial_vertices: u32,
+    pub initial_indices: u32,
+    /// Number of views the terrain can be culled for in one frame, each with its own indirect
+    /// draw arguments.
+    pub views: u32,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        // A slot for every chunk within the load radius, and geometry for a few dozen chunks
+        // before the arena has to grow.
+        Self {
+            max_chunks: crate::chunk::max_loaded_chunks(),
+            initial_vertices: 256 * 1024,
+            initial_indices: 384 * 1024,
+            views: 1 + crate::shadow::MAX_CASCADES as u32,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ChunkRaw {
+    bounds_min: [f32; 4],
+    bounds_max: [f32; 4],
+    index_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    active: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullUniforms {
+    planes: [[f32; 4]; 6],
+    chunk_count: u32,
+    _padding: [u32; 3],
+}
+
+/// Matches the layout of `wgpu::RenderPass::draw_indexed_indirect` arguments.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DrawIndexedIndirect {
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+struct ChunkAllocation {
+    slot: u32,
+    bounds: Boundary<f32>,
+    vertices: Range<u32>,
+    indices: Range<u32>,
+}
+
+/// First fit allocator over a range of buffer elements. Free ranges are kept sorted and
+/// coalesced so that chunks of similar size can reuse the space left by unloaded ones.
+struct RangeAllocator {
+    free: Vec<Range<u32>>,
+    capacity: u32,
+}
+
+impl RangeAllocator {
+    fn new(capacity: u32) -> Self {
+        let mut allocator = Self {
+            free: Vec::new(),
+            capacity: 0,
+        };
+        allocator.grow(capacity);
+        allocator
+    }
+
+    /// Extends the range the allocator hands out to `0..capacity`.
+    fn grow(&mut self, capacity: u32) {
+        let added = self.capacity..capacity;
+        self.capacity = capacity;
+        if !added.is_empty() {
+            self.free(added);
+        }
+    }
+
+    /// Capacity the allocator needs for `len` more elements to fit at its end.
+    fn required_capacity(&self, len: u32) -> u32 {
+        match self.free.last() {
+            Some(last) if last.end == self.capacity => {
+                self.capacity + len - (last.end - last.start)
+            }
+            _ => self.capacity + len,
+        }
+    }
+
+    fn fits(&self, len: u32) -> bool {
+        self.free.iter().any(|range| range.end - range.start >= len)
+    }
+
+    fn allocate(&mut self, len: u32) -> Option<Range<u32>> {
+        let position = self
+            .free
+            .iter()
+            .position(|range| range.end - range.start >= len)?;
+        let range = &mut self.free[position];
+        let allocation = range.start..range.start + len;
+        range.start += len;
+        if range.start == range.end {
+            self.free.remove(position);
+        }
+        Some(allocation)
+    }
+
+    fn free(&mut self, range: Range<u32>) {
+        let position = self
+            .free
+            .iter()
+            .position(|free| free.start > range.start)
+            .unwrap_or(self.free.len());
+        self.free.insert(position, range);
+
+        if position + 1 < self.free.len()
+            && self.free[position].end == self.free[position + 1].start
+        {
+            self.free[position].end = self.free.remove(position + 1).end;
+        }
+        if position > 0 && self.free[position - 1].end == self.free[position].start {
+            self.free[position - 1].end = self.free.remove(position).end;
+        }
+    }
+}
+
+/// Holds the geometry of every loaded chunk in one shared vertex and index buffer. A compute
+/// pass culls the chunk bounds against the frustum and writes the indirect draw arguments, so
+/// the whole terrain is drawn without per chunk work on the CPU. The buffers start small and
+/// are reallocated at twice the size whenever a chunk does not fit.
+pub struct TerrainArena {
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    chunk_buffer: wgpu::Buffer,
+    indirect_buffer: wgpu::Buffer,
+    cull_buffer: wgpu::Buffer,
+    instance_buffer: wgpu::Buffer,
+    cull_pipeline: wgpu::ComputePipeline,
+    cull_bind_group: wgpu::BindGroup,
+    material: Arc<Material>,
+
+    config: TerrainConfig,
+    vertex_allocator: RangeAllocator,
+    index_allocator: RangeAllocator,
+    free_slots: Vec<u32>,
+    slot_count: u32,
+    indirect_stride: u64,
+    chunks: HashMap<MeshId, ChunkAllocation>,
+    multi_draw: bool,
+}
+
+impl TerrainArena {
+    pub fn new(display: &Display, material: Arc<Material>, config: TerrainConfig) -> Self {
+        let device = &display.device;
+        let vertex_buffer = create_vertex_buffer(device, config.initial_vertices);
+        let index_buffer = create_index_buffer(device, config.initial_indices);
+        let chunk_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Terrain Chunk Buffer"),
+            size: config.max_chunks as u64 * std::mem::size_of::<ChunkRaw>() as u64,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let indirect_size =
+            config.max_chunks as u64 * std::mem::size_of::<DrawIndexedIndirect>() as u64;
+        let indirect_stride = align(indirect_size);
+        let indirect_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Terrain Indirect Buffer"),
+            size: indirect_stride * config.views as u64,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::INDIRECT,
+            mapped_at_creation: false,
+        });
+        let cull_size = std::mem::size_of::<CullUniforms>() as u64;
+        let cull_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Terrain Cull Buffer"),
+            size: align(cull_size) * config.views as u64,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // Chunk positions are baked into the vertices, so every chunk shares one identity instance.
+        let identity: cgmath::Matrix4<f32> = cgmath::SquareMatrix::identity();
+        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Terrain Instance Buffer"),
+            contents: bytemuck::cast_slice(&[InstanceRaw::from(identity)]),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+
+        let storage_entry = |binding, read_only, has_dynamic_offset| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let cull_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("terrain_cull_bind_group_layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    storage_entry(1, true, false),
+                    storage_entry(2, false, true),
+                ],
+            });
+        let cull_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("terrain_cull_bind_group"),
+            layout: &cull_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &cull_buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(cull_size),
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: chunk_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &indirect_buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(indirect_size),
+                    },
+                },
+            ],
+        });
+
+        let cull_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Terrain Cull Pipeline Layout"),
+            bind_group_layouts: &[&cull_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let cull_module = device
+            .create_shader_module(&wgpu::include_spirv!("../resources/shaders/cull.comp.spv"));
+        let cull_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Terrain Cull Pipeline"),
+            layout: Some(&cull_pipeline_layout),
+            module: &cull_module,
+            entry_point: "main",
+        });
+
+        Self {
+            vertex_buffer,
+            index_buffer,
+            chunk_buffer,
+            indirect_buffer,
+            cull_buffer,
+            instance_buffer,
+            cull_pipeline,
+            cull_bind_group,
+            material,
+            config,
+            vertex_allocator: RangeAllocator::new(config.initial_vertices),
+            index_allocator: RangeAllocator::new(config.initial_indices),
+            free_slots: Vec::new(),
+            slot_count: 0,
+            indirect_stride,
+            chunks: HashMap::new(),
+            multi_draw: device
+                .features()
+                .contains(wgpu::Features::MULTI_DRAW_INDIRECT),
+        }
+    }
+
+    /// Uploads chunks that are new to the arena and frees the space of chunks that are gone.
+    /// Chunks that do not fit are retried on a later frame, once others have been unloaded.
+    pub fn sync<'a, I>(&mut self, display: &Display, chunks: I)
+    where
+        I: IntoIterator<Item = (&'a Transform, &'a MeshReference)>,
+    {
+        let mut live = HashSet::new();
+        let mut new_chunks = Vec::new();
+        for (transform, mesh) in chunks {
+            live.insert(mesh.idx);
+            if !self.chunks.contains_key(&mesh.idx) {
+                new_chunks.push((transform, mesh));
+            }
+        }
+
+        // Unloaded chunks are released first so that new ones can reuse their space.
+        let removed = self
+            .chunks
+            .keys()
+            .filter(|id| !live.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in removed {
+            self.release(display, id);
+        }
+
+        for (transform, mesh) in new_chunks {
+            self.reserve(display, mesh);
+            if let Err(err) = self.upload(display, transform, mesh) {
+                log::warn!("Deferring upload of chunk {:?}: {}", mesh.idx, err);
+            }
+        }
+    }
+
+    /// Grows the vertex and index buffers when `mesh` would not fit into their free space.
+    fn reserve(&mut self, display: &Display, mesh: &MeshReference) {
+        let vertex_count = mesh.vertex_data.len() as u32;
+        let index_count = mesh.index_data.len() as u32;
+        let grow_vertices = !self.vertex_allocator.fits(vertex_count);
+        let grow_indices = !self.index_allocator.fits(index_count);
+        if !grow_vertices && !grow_indices {
+            return;
+        }
+
+        let mut encoder = display
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Terrain Grow Encoder"),
+            });
+        if grow_vertices {
+            let capacity = self
+                .vertex_allocator
+                .required_capacity(vertex_count)
+                .max(self.vertex_allocator.capacity * 2);
+            let buffer = create_vertex_buffer(&display.device, capacity);
+            encoder.copy_buffer_to_buffer(
+                &self.vertex_buffer,
+                0,
+                &buffer,
+                0,
+                self.vertex_allocator.capacity as u64 * std::mem::size_of::<MeshVertex>() as u64,
+            );
+            self.vertex_buffer = buffer;
+            self.vertex_allocator.grow(capacity);
+        }
+        if grow_indices {
+            let capacity = self
+                .index_allocator
+                .required_capacity(index_count)
+                .max(self.index_allocator.capacity * 2);
+            let buffer = create_index_buffer(&display.device, capacity);
+            encoder.copy_buffer_to_buffer(
+                &self.index_buffer,
+                0,
+                &buffer,
+                0,
+                self.index_allocator.capacity as u64 * std::mem::size_of::<u32>() as u64,
+            );
+            self.index_buffer = buffer;
+            self.index_allocator.grow(capacity);
+        }
+        // Writes queued for the old buffers are flushed before this copy, and the ones queued
+        // from here on land in the new buffers.
+        display.queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    fn upload(
+        &mut self,
+        display: &Display,
+        transform: &Transform,
+        mesh: &MeshReference,
+    ) -> Result<(), &'static str> {
+        let vertices = self
+            .vertex_allocator
+            .allocate(mesh.vertex_data.len() as u32)
+            .ok_or("vertex arena is full")?;
+        let indices = match self.index_allocator.allocate(mesh.index_data.len() as u32) {
+            Some(indices) => indices,
+            None => {
+                self.vertex_allocator.free(vertices);
+                return Err("index arena is full");
+            }
+        };
+        let slot = match self.allocate_slot() {
+            Some(slot) => slot,
+            None => {
+                self.vertex_allocator.free(vertices);
+                self.index_allocator.free(indices);
+                return Err("no free chunk slots");
+            }
+        };
+
+        let offset: [f32; 3] = transform.position.into();
+        let baked = mesh
+            .vertex_data
+            .iter()
+            .map(|vertex| MeshVertex {
+                position: [
+                    vertex.position[0] + offset[0],
+                    vertex.position[1] + offset[1],
+                    vertex.position[2] + offset[2],
+                ],
+                ..*vertex
+            })
+            .collect::<Vec<_>>();
+        let bounds = Boundary::from_points(baked.iter().map(|vertex| vertex.position));
+
+        display.queue.write_buffer(
+            &self.vertex_buffer,
+            vertices.start as u64 * std::mem::size_of::<MeshVertex>() as u64,
+            bytemuck::cast_slice(&baked),
+        );
+        display.queue.write_buffer(
+            &self.index_buffer,
+            indices.start as u64 * std::mem::size_of::<u32>() as u64,
+            bytemuck::cast_slice(&mesh.index_data),
+        );
+        self.write_slot(
+            display,
+            slot,
+            ChunkRaw {
+                bounds_min: bounds.lower.extend(0.0).into(),
+                bounds_max: bounds.upper.extend(0.0).into(),
+                index_count: mesh.index_data.len() as u32,
+                first_index: indices.start,
+                base_vertex: vertices.start as i32,
+                active: 1,
+            },
+        );
+
+        self.chunks.insert(
+            mesh.idx,
+            ChunkAllocation {
+                slot,
+                bounds,
+                vertices,
+                indices,
+            },
+        );
+        Ok(())
+    }
+
+    fn release(&mut self, display: &Display, id: MeshId) {
+        if let Some(allocation) = self.chunks.remove(&id) {
+            self.write_slot(display, allocation.slot, bytemuck::Zeroable::zeroed());
+            self.vertex_allocator.free(allocation.vertices);
+            self.index_allocator.free(allocation.indices);
+            self.free_slots.push(allocation.slot);
+        }
+    }
+
+    fn allocate_slot(&mut self) -> Option<u32> {
+        if let Some(slot) = self.free_slots.pop() {
+            return Some(slot);
+        }
+        if self.slot_count < self.config.max_chunks {
+            self.slot_count += 1;
+            return Some(self.slot_count - 1);
+        }
+        None
+    }
+
+    fn write_slot(&self, display: &Display, slot: u32, chunk: ChunkRaw) {
+        display.queue.write_buffer(
+            &self.chunk_buffer,
+            slot as u64 * std::mem::size_of::<ChunkRaw>() as u64,
+            bytemuck::cast_slice(&[chunk]),
+        );
+    }
+
+    /// Records the compute pass that fills the indirect draw arguments of `view` for this
+    /// frame's frustum. Every view has to be culled before the frame is submitted.
+    pub fn cull(
+        &self,
+        display: &Display,
+        encoder: &mut wgpu::CommandEncoder,
+        view: u32,
+        frustum: &Frustum,
+    ) {
+        if self.slot_count == 0 {
+            return;
+        }
+        assert!(
+            view < self.config.views,
+            "Terrain view {} is out of range",
+            view
+        );
+        let cull_offset = view as u64 * align(std::mem::size_of::<CullUniforms>() as u64);
+        let mut planes = [[0.0; 4]; 6];
+        for (plane, frustum_plane) in planes.iter_mut().zip(frustum.planes().iter()) {
+            *plane = (*frustum_plane).into();
+        }
+        display.queue.write_buffer(
+            &self.cull_buffer,
+            cull_offset,
+            bytemuck::cast_slice(&[CullUniforms {
+                planes,
+                chunk_count: self.slot_count,
+                _padding: [0; 3],
+            }]),
+        );
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Terrain Cull Pass"),
+        });
+        compute_pass.set_pipeline(&self.cull_pipeline);
+        compute_pass.set_bind_group(
+            0,
+            &self.cull_bind_group,
+            &[
+                cull_offset as wgpu::DynamicOffset,
+                (view as u64 * self.indirect_stride) as wgpu::DynamicOffset,
+            ],
+        );
+        compute_pass.dispatch(self.slot_count.div_ceil(CULL_WORKGROUP_SIZE), 1, 1);
+    }
+
+    /// Number of loaded chunks outside `frustum`. The indirect arguments stay on the GPU, so
+    /// this repeats the culling test on the CPU for the stats.
+    pub fn count_culled(&self, frustum: &Frustum) -> u32 {
+        self.chunks
+            .values()
+            .filter(|allocation| !frustum.intersects(&allocation.bounds))
+            .count() as u32
+    }
+
+    /// The block textures every chunk is textured with, one layer per block texture.
+    pub fn material(&self) -> &Material {
+        &self.material
+    }
+
+    /// Draws every chunk slot from the indirect arguments culled for `view`. The pipeline and
+    /// its bind groups have to be bound already. Returns the number of draw calls issued.
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, view: u32) -> u32 {
+        if self.chunks.is_empty() {
+            return 0;
+        }
+        let view_offset = view as u64 * self.indirect_stride;
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+
+        if self.multi_draw {
+            render_pass.multi_draw_indexed_indirect(
+                &self.indirect_buffer,
+                view_offset,
+                self.slot_count,
+            );
+            return 1;
+        }
+        for allocation in self.chunks.values() {
+            render_pass.draw_indexed_indirect(
+                &self.indirect_buffer,
+                view_offset
+                    + allocation.slot as u64 * std::mem::size_of::<DrawIndexedIndirect>() as u64,
+            );
+        }
+        self.chunks.len() as u32
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn memory_usage(&self) -> u64 {
+        self.vertex_allocator.capacity as u64 * std::mem::size_of::<MeshVertex>() as u64
+            + self.index_allocator.capacity as u64 * std::mem::size_of::<u32>() as u64
+            + self.config.max_chunks as u64 * std::mem::size_of::<ChunkRaw>() as u64
+            + self.indirect_stride * self.config.views as u64
+    }
+}
+
+fn align(size: u64) -> u64 {
+    size.div_ceil(OFFSET_ALIGNMENT) * OFFSET_ALIGNMENT
+}
+
+fn create_vertex_buffer(device: &wgpu::Device, vertices: u32) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Terrain Vertex Buffer"),
+        size: vertices as u64 * std::mem::size_of::<MeshVertex>() as u64,
+        usage: wgpu::BufferUsage::VERTEX
+            | wgpu::BufferUsage::COPY_SRC
+            | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_index_buffer(device: &wgpu::Device, indices: u32) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Terrain Index Buffer"),
+        size: indices as u64 * std::mem::size_of::<u32>() as u64,
+        usage: wgpu::BufferUsage::INDEX | wgpu::BufferUsage::COPY_SRC | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_ranges_are_coalesced() {
+        let mut allocator = RangeAllocator::new(30);
+        let a = allocator.allocate(10).unwrap();
+        let b = allocator.allocate(10).unwrap();
+        allocator.free(a);
+        allocator.free(b);
+        assert_eq!(allocator.free, vec![0..30]);
+        assert!(allocator.fits(30));
+    }
+
+    #[test]
+    fn growing_extends_the_trailing_free_range() {
+        let mut allocator = RangeAllocator::new(16);
+        allocator.allocate(10).unwrap();
+        assert!(!allocator.fits(12));
+        assert_eq!(allocator.required_capacity(12), 22);
+
+        allocator.grow(allocator.required_capacity(12));
+        assert_eq!(allocator.allocate(12), Some(10..22));
+        assert!(!allocator.fits(1));
+        assert_eq!(allocator.required_capacity(4), 26);
+    }
+}
@@ -1,20 +1,120 @@
 use anyhow::*;
 use image::GenericImageView;
-use std::fmt::Debug;
-use std::path::Path;
+use serde::Deserialize;
+use std::num::NonZeroU8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum TextureFilter {
+    Nearest,
+    Linear,
+}
+
+impl From<TextureFilter> for wgpu::FilterMode {
+    fn from(filter: TextureFilter) -> Self {
+        match filter {
+            TextureFilter::Nearest => wgpu::FilterMode::Nearest,
+            TextureFilter::Linear => wgpu::FilterMode::Linear,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum TextureAddressMode {
+    ClampToEdge,
+    Repeat,
+    MirrorRepeat,
+}
+
+impl From<TextureAddressMode> for wgpu::AddressMode {
+    fn from(address_mode: TextureAddressMode) -> Self {
+        match address_mode {
+            TextureAddressMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+            TextureAddressMode::Repeat => wgpu::AddressMode::Repeat,
+            TextureAddressMode::MirrorRepeat => wgpu::AddressMode::MirrorRepeat,
+        }
+    }
+}
+
+/// How the textures of a material are sampled. The default is trilinear filtering without
+/// anisotropy, clamped to the edges of the texture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(default)]
+pub struct SamplerConfig {
+    pub mag_filter: TextureFilter,
+    pub min_filter: TextureFilter,
+    /// Blends between mip levels when linear, or picks the nearest level.
+    pub mipmap_filter: TextureFilter,
+    /// Largest number of anisotropic samples. Rounded down to a power of two of at most 16,
+    /// and 1 or less turns anisotropic filtering off. Ignored by adapters without support.
+    pub anisotropy: u8,
+    /// How texture coordinates outside of the texture wrap, in both directions.
+    pub address_mode: TextureAddressMode,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            mag_filter: TextureFilter::Linear,
+            min_filter: TextureFilter::Linear,
+            mipmap_filter: TextureFilter::Linear,
+            anisotropy: 1,
+            address_mode: TextureAddressMode::ClampToEdge,
+        }
+    }
+}
+
+impl SamplerConfig {
+    fn anisotropy_clamp(&self) -> Option<NonZeroU8> {
+        if self.anisotropy <= 1 {
+            return None;
+        }
+        let samples = self.anisotropy.min(16);
+        NonZeroU8::new(1 << (7 - samples.leading_zeros()))
+    }
+
+    pub fn create_sampler(&self, device: &wgpu::Device, label: Option<&str>) -> wgpu::Sampler {
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            label,
+            address_mode_u: self.address_mode.into(),
+            address_mode_v: self.address_mode.into(),
+            address_mode_w: self.address_mode.into(),
+            mag_filter: self.mag_filter.into(),
+            min_filter: self.min_filter.into(),
+            mipmap_filter: self.mipmap_filter.into(),
+            anisotropy_clamp: self.anisotropy_clamp(),
+            ..Default::default()
+        })
+    }
+}
 
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
+    pub size: wgpu::Extent3d,
+    pub mip_level_count: u32,
+    pub format: wgpu::TextureFormat,
 }
 
 impl Texture {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
+    /// Bytes taken by every mip level of every layer.
+    pub fn memory_usage(&self) -> u64 {
+        let texel_size = bytes_per_texel(self.format);
+        (0..self.mip_level_count)
+            .map(|level| {
+                let width = (self.size.width >> level).max(1) as u64;
+                let height = (self.size.height >> level).max(1) as u64;
+                width * height * self.size.depth as u64 * texel_size
+            })
+            .sum()
+    }
+
     pub fn create_depth_texture(
         device: &wgpu::Device,
         sc_desc: &wgpu::SwapChainDescriptor,
+        sample_count: u32,
         label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
@@ -27,7 +127,7 @@ impl Texture {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
@@ -49,81 +149,349 @@ impl Texture {
             texture,
             view,
             sampler,
+            size,
+            mip_level_count: 1,
+            format: Self::DEPTH_FORMAT,
         }
     }
 
-    pub fn from_bytes(
+    /// A 1x1 texture of a single colour, standing in for maps a material does not have.
+    pub fn solid(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        bytes: &[u8],
+        color: [u8; 4],
+        linear: bool,
         label: &str,
     ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        let img =
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+        Self::from_image(
+            device,
+            queue,
+            &img,
+            Some(label),
+            linear,
+            &SamplerConfig::default(),
+        )
     }
 
+    /// Colour textures are decoded from sRGB when sampled. Textures holding data, such as
+    /// normal or specular maps, are `linear` and sampled as they are stored. The full mip chain
+    /// is generated on the CPU.
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
+        linear: bool,
+        sampler: &SamplerConfig,
+    ) -> Result<Self> {
+        Ok(Self::from_levels(
+            device,
+            queue,
+            &[mip_chain(img, linear)],
+            label,
+            linear,
+            sampler,
+            wgpu::TextureViewDimension::D2,
+        ))
+    }
+
+    /// A 2D texture array with one layer per image, viewed as an array. Every image must have
+    /// the same size, and each layer gets a mip chain of its own, so layers never bleed into
+    /// each other.
+    pub fn from_layers(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        images: &[image::DynamicImage],
+        label: Option<&str>,
+        linear: bool,
+        sampler: &SamplerConfig,
     ) -> Result<Self> {
-        let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        let first = images.first().context("Texture array has no layers")?;
+        if let Some((layer, img)) = images
+            .iter()
+            .enumerate()
+            .find(|(_, img)| img.dimensions() != first.dimensions())
+        {
+            bail!(
+                "Layer {} is {:?} but the first layer is {:?}",
+                layer,
+                img.dimensions(),
+                first.dimensions()
+            );
+        }
+        let layers = images
+            .iter()
+            .map(|img| mip_chain(img, linear))
+            .collect::<Vec<_>>();
+        Ok(Self::from_levels(
+            device,
+            queue,
+            &layers,
+            label,
+            linear,
+            sampler,
+            wgpu::TextureViewDimension::D2Array,
+        ))
+    }
 
+    /// A cube texture from six square faces in the layer order of cube textures, each with a
+    /// mip chain of its own.
+    pub fn cube(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[image::DynamicImage],
+        label: Option<&str>,
+        sampler: &SamplerConfig,
+    ) -> Result<Self> {
+        ensure!(faces.len() == 6, "Cube texture has {} faces", faces.len());
+        let size = faces[0].dimensions();
+        ensure!(size.0 == size.1, "Cube faces are {:?}, not square", size);
+        if let Some((face, img)) = faces
+            .iter()
+            .enumerate()
+            .find(|(_, img)| img.dimensions() != size)
+        {
+            bail!(
+                "Face {} is {:?} but the first face is {:?}",
+                face,
+                img.dimensions(),
+                size
+            );
+        }
+        let faces = faces
+            .iter()
+            .map(|img| mip_chain(img, false))
+            .collect::<Vec<_>>();
+        Ok(Self::from_levels(
+            device,
+            queue,
+            &faces,
+            label,
+            false,
+            sampler,
+            wgpu::TextureViewDimension::Cube,
+        ))
+    }
+
+    /// Creates a texture from the mip levels of each of its layers.
+    fn from_levels(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layers: &[Vec<image::RgbaImage>],
+        label: Option<&str>,
+        linear: bool,
+        sampler: &SamplerConfig,
+        dimension: wgpu::TextureViewDimension,
+    ) -> Self {
+        let mip_level_count = layers[0].len() as u32;
         let size = wgpu::Extent3d {
-            width: dimensions.0,
-            height: dimensions.1,
-            depth: 1,
+            width: layers[0][0].width(),
+            height: layers[0][0].height(),
+            depth: layers.len() as u32,
         };
 
+        let format = if linear {
+            wgpu::TextureFormat::Rgba8Unorm
+        } else {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        };
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
             size,
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            format,
             usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
         });
 
-        queue.write_texture(
-            wgpu::TextureCopyView {
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::TextureDataLayout {
-                offset: 0,
-                bytes_per_row: 4 * dimensions.0,
-                rows_per_image: dimensions.1,
-            },
-            size,
-        );
+        for (layer, levels) in layers.iter().enumerate() {
+            for (mip_level, level) in levels.iter().enumerate() {
+                queue.write_texture(
+                    wgpu::TextureCopyView {
+                        texture: &texture,
+                        mip_level: mip_level as u32,
+                        origin: wgpu::Origin3d {
+                            x: 0,
+                            y: 0,
+                            z: layer as u32,
+                        },
+                    },
+                    level,
+                    wgpu::TextureDataLayout {
+                        offset: 0,
+                        bytes_per_row: 4 * level.width(),
+                        rows_per_image: level.height(),
+                    },
+                    wgpu::Extent3d {
+                        width: level.width(),
+                        height: level.height(),
+                        depth: 1,
+                    },
+                );
+            }
+        }
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            label,
-            mag_filter: wgpu::FilterMode::Linear,
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(dimension),
             ..Default::default()
         });
+        let sampler = sampler.create_sampler(device, label);
 
-        Ok(Self {
+        Self {
             texture,
             view,
             sampler,
-        })
+            size,
+            mip_level_count,
+            format,
+        }
     }
+}
 
-    pub fn load<P: AsRef<Path> + Debug>(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        path: P,
-    ) -> Result<Self> {
-        let path_copy = path.as_ref().to_path_buf();
-        let label = path_copy.to_str();
-        let img = image::open(path)?;
-        Self::from_image(device, queue, &img, label)
+/// Size of a texel of the uncompressed formats the renderer creates textures with.
+fn bytes_per_texel(format: wgpu::TextureFormat) -> u64 {
+    use wgpu::TextureFormat::*;
+    match format {
+        R8Unorm | R8Snorm | R8Uint | R8Sint => 1,
+        R16Uint | R16Sint | R16Float | Rg8Unorm | Rg8Snorm | Rg8Uint | Rg8Sint => 2,
+        Rgba16Uint | Rgba16Sint | Rgba16Float | Rg32Uint | Rg32Sint | Rg32Float => 8,
+        Rgba32Uint | Rgba32Sint | Rgba32Float => 16,
+        _ => 4,
+    }
+}
+
+/// Every mip level of an image, down to a single texel.
+fn mip_chain(img: &image::DynamicImage, linear: bool) -> Vec<image::RgbaImage> {
+    let mut levels = vec![img.to_rgba8()];
+    loop {
+        let last = levels.last().unwrap();
+        if last.dimensions() == (1, 1) {
+            return levels;
+        }
+        let next = downsample(last, linear);
+        levels.push(next);
+    }
+}
+
+/// Halves an image with a box filter, averaging colour textures in linear space. Odd sizes
+/// round down, and the last row or column is folded into the one before it.
+fn downsample(img: &image::RgbaImage, linear: bool) -> image::RgbaImage {
+    let (width, height) = img.dimensions();
+    let (half_width, half_height) = ((width / 2).max(1), (height / 2).max(1));
+    image::RgbaImage::from_fn(half_width, half_height, |x, y| {
+        let columns = source_range(x, width, half_width);
+        let rows = source_range(y, height, half_height);
+        let mut sum = [0.0; 4];
+        let mut count = 0.0;
+        for sy in rows {
+            for sx in columns.clone() {
+                let texel = img.get_pixel(sx, sy);
+                for channel in 0..4 {
+                    let value = texel[channel] as f32 / 255.0;
+                    // Alpha is always stored linearly.
+                    sum[channel] += if linear || channel == 3 {
+                        value
+                    } else {
+                        srgb_to_linear(value)
+                    };
+                }
+                count += 1.0;
+            }
+        }
+        let mut texel = [0; 4];
+        for channel in 0..4 {
+            let value = sum[channel] / count;
+            let value = if linear || channel == 3 {
+                value
+            } else {
+                linear_to_srgb(value)
+            };
+            texel[channel] = (value * 255.0).round() as u8;
+        }
+        image::Rgba(texel)
+    })
+}
+
+/// The texels of the source row or column that fold into `index` of the halved one.
+fn source_range(index: u32, size: u32, half_size: u32) -> std::ops::Range<u32> {
+    let start = (index * 2).min(size - 1);
+    let end = if index + 1 == half_size {
+        size
+    } else {
+        start + 2
+    };
+    start..end
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downsample_halves_down_to_one_texel() {
+        let mut img = image::RgbaImage::from_pixel(5, 2, image::Rgba([10, 20, 30, 40]));
+        let mut sizes = Vec::new();
+        while img.dimensions() != (1, 1) {
+            img = downsample(&img, true);
+            sizes.push(img.dimensions());
+        }
+        assert_eq!(sizes, vec![(2, 1), (1, 1)]);
+        assert_eq!(*img.get_pixel(0, 0), image::Rgba([10, 20, 30, 40]));
+    }
+
+    #[test]
+    fn downsample_averages_colours_in_linear_space() {
+        let img = image::RgbaImage::from_fn(2, 1, |x, _| {
+            image::Rgba([if x == 0 { 0 } else { 255 }, 0, 0, 255])
+        });
+        assert_eq!(downsample(&img, true).get_pixel(0, 0)[0], 128);
+        // Half of full intensity is brighter than the middle of the sRGB range.
+        assert_eq!(downsample(&img, false).get_pixel(0, 0)[0], 188);
+    }
+
+    #[test]
+    fn anisotropy_rounds_down_to_a_power_of_two() {
+        let clamp = |anisotropy| {
+            SamplerConfig {
+                anisotropy,
+                ..Default::default()
+            }
+            .anisotropy_clamp()
+            .map(NonZeroU8::get)
+        };
+        assert_eq!(clamp(0), None);
+        assert_eq!(clamp(1), None);
+        assert_eq!(clamp(6), Some(4));
+        assert_eq!(clamp(16), Some(16));
+        assert_eq!(clamp(255), Some(16));
+    }
+
+    #[test]
+    fn mip_chain_ends_at_one_texel() {
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::new(8, 2));
+        let sizes = mip_chain(&img, false)
+            .iter()
+            .map(|level| level.dimensions())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![(8, 2), (4, 1), (2, 1), (1, 1)]);
     }
 }
@@ -29,7 +29,8 @@ impl<D: Sized, I: Sized, W: Worker<D, I>> Pool<D, I, W> {
     }
 
     pub fn dispatch(&mut self, data: D) -> Result<()> {
-        self.sender.send(data).unwrap();
-        Ok(())
+        self.sender
+            .send(data)
+            .map_err(|_| anyhow!("Worker pool has shut down"))
     }
 }